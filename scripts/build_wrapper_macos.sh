@@ -6,6 +6,9 @@ script_dir="$(dirname -- "${BASH_SOURCE[0]}")"
 
 cd "$script_dir/.."
 
+cargo build --release --manifest-path ./wrapper/Cargo.toml
+WRAPPER_TARGET_DIR="${CARGO_TARGET_DIR:-./wrapper/target}"
+
 platypus \
   --name MpvWrapper \
   --interface-type None \
@@ -13,5 +16,5 @@ platypus \
   --author "Cornholio" \
   --quit-after-execution \
   --uri-schemes mpv \
-  "./wrapper/mpv_wrapper.sh" \
+  "${WRAPPER_TARGET_DIR}/release/mpvserve-wrapper" \
   ./MpvWrapper
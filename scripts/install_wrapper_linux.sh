@@ -4,11 +4,14 @@ set -e -o pipefail
 
 script_dir="$(dirname -- "${BASH_SOURCE[0]}")"
 
+cargo build --release --manifest-path "${script_dir}/../wrapper/Cargo.toml"
+WRAPPER_TARGET_DIR="${CARGO_TARGET_DIR:-"${script_dir}/../wrapper/target"}"
+
 mkdir -p "$HOME/.bin"
-cp "${script_dir}/../wrapper/mpv_wrapper.sh" "$HOME/.bin/mpv_wrapper.sh"
-chmod +x "$HOME/.bin/mpv_wrapper.sh"
+cp "${WRAPPER_TARGET_DIR}/release/mpvserve-wrapper" "$HOME/.bin/mpvserve-wrapper"
+chmod +x "$HOME/.bin/mpvserve-wrapper"
 
-echo "Wrapper copied to $HOME/.bin/mpv_wrapper.sh"
+echo "Wrapper copied to $HOME/.bin/mpvserve-wrapper"
 
 XDG_APP_DIR=
 
@@ -0,0 +1,380 @@
+use crate::servings::{self, ServingKey};
+use crate::utils::now_secs;
+use crate::{db, history, preferences};
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use log::{debug, info};
+use rocket::tokio::task;
+use sea_orm::ActiveValue::Set;
+use sea_orm::*;
+use uuid::Uuid;
+
+use db::prelude::*;
+
+/*
+User accounts. Progress records are keyed by `user_key` of the user, not by the id,
+so anonymous history (keyed by a random UUID from the cookie) can be claimed just by re-keying records.
+user_key is also what mpv links carry, since mpv doesn't have the browser's cookies.
+*/
+
+pub const SESSION_COOKIE: &str = "mpvserve_session";
+pub const SESSION_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_NAME_LEN: usize = 64;
+
+// Hash of "dummy password" with the default parameters, checked against when the name is unknown
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=4096,t=3,p=1$JZYZZpMICCastEzWrkODGA$Pd1Zo0osXGYKq87n+XC0oQoL+k9AKS2BtTi2s5jBy4Q";
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| anyhow!("failed to hash password: {}", err))?;
+
+    Ok(hash.to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub async fn register(
+    name: &str,
+    password: &str,
+    conn: &DatabaseConnection,
+) -> Result<db::users::Model> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(anyhow!(
+            "name should be 1 to {} characters long",
+            MAX_NAME_LEN
+        ));
+    }
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(anyhow!(
+            "password should be at least {} characters long",
+            MIN_PASSWORD_LEN
+        ));
+    }
+
+    let existing = User::find()
+        .filter(db::users::Column::Name.eq(name))
+        .one(conn)
+        .await?;
+    if existing.is_some() {
+        return Err(anyhow!("name {:?} is already taken", name));
+    }
+
+    // Hashing is slow by design, keeping it off the async workers
+    let password = String::from(password);
+    let password_hash = task::spawn_blocking(move || hash_password(&password)).await??;
+
+    let user = db::users::ActiveModel {
+        name: Set(String::from(name)),
+        password_hash: Set(password_hash),
+        user_key: Set(Uuid::new_v4().to_string()),
+        created_at: Set(now_secs()),
+        ..Default::default()
+    };
+
+    info!("Registering user {:?}", name);
+    Ok(user.insert(conn).await?)
+}
+
+/// Returns the user if the password matches
+pub async fn authenticate(
+    name: &str,
+    password: &str,
+    conn: &DatabaseConnection,
+) -> Result<Option<db::users::Model>> {
+    let user = User::find()
+        .filter(db::users::Column::Name.eq(name.trim()))
+        .one(conn)
+        .await?;
+
+    // Unknown names take as long as wrong passwords, so response times don't tell which names exist
+    let password = String::from(password);
+    let password_hash = user
+        .as_ref()
+        .map_or(DUMMY_HASH, |user| &user.password_hash)
+        .to_owned();
+    let is_valid = task::spawn_blocking(move || verify_password(&password, &password_hash)).await?;
+
+    Ok(user.filter(|_| is_valid))
+}
+
+/// Creates a session for the user, returning its token
+pub async fn create_session(user: &db::users::Model, conn: &DatabaseConnection) -> Result<String> {
+    let token = Uuid::new_v4().simple().to_string() + &Uuid::new_v4().simple().to_string();
+
+    let session = db::sessions::ActiveModel {
+        token: Set(token.clone()),
+        user_id: Set(user.id),
+        expires_at: Set(now_secs() + SESSION_TTL_SECS),
+    };
+    session.insert(conn).await?;
+
+    // Good time to forget about sessions nobody's going to use
+    Session::delete_many()
+        .filter(db::sessions::Column::ExpiresAt.lt(now_secs()))
+        .exec(conn)
+        .await?;
+
+    Ok(token)
+}
+
+/// Returns the user of a valid session
+pub async fn session_user(
+    token: &str,
+    conn: &DatabaseConnection,
+) -> Result<Option<db::users::Model>> {
+    let session = match Session::find_by_id(String::from(token)).one(conn).await? {
+        Some(session) if session.expires_at > now_secs() => session,
+        _ => return Ok(None),
+    };
+
+    Ok(User::find_by_id(session.user_id).one(conn).await?)
+}
+
+pub async fn delete_session(token: &str, conn: &DatabaseConnection) -> Result<()> {
+    Session::delete_by_id(String::from(token))
+        .exec(conn)
+        .await?;
+
+    Ok(())
+}
+
+fn last_activity(serving: &db::movie_servings::Model) -> i64 {
+    serving
+        .last_timestamp
+        .max(serving.reported_timestamp.unwrap_or(0))
+}
+
+/// Moves progress recorded for an anonymous id to the user, returns the number of moved records.
+/// When both have progress for the same file, the more recent one wins
+pub async fn claim_history(
+    anonymous_id: &str,
+    user: &db::users::Model,
+    conn: &DatabaseConnection,
+) -> Result<u64> {
+    let anonymous_id = anonymous_id.trim();
+    if anonymous_id.is_empty() || anonymous_id == user.user_key {
+        return Ok(0);
+    }
+
+    // Keys of accounts are in their mpv links, they shouldn't be usable to take over someone's history
+    let is_account = User::find()
+        .filter(db::users::Column::UserKey.eq(anonymous_id))
+        .one(conn)
+        .await?
+        .is_some();
+    if is_account {
+        return Err(anyhow!("{:?} belongs to an account", anonymous_id));
+    }
+
+    let txn = conn.begin().await?;
+
+    let mut claimed = 0;
+    for serving in servings::find_by_user(anonymous_id, &txn).await? {
+        let new_key = ServingKey::new(&servings::item_key(&serving), &user.user_key);
+
+        let existing = servings::find(&new_key, &txn).await?;
+        if matches!(&existing, Some(existing) if last_activity(existing) >= last_activity(&serving))
+        {
+            debug!("Keeping newer progress of {:?}", new_key);
+            servings::delete(&ServingKey::of(&serving), &txn).await?;
+            continue;
+        }
+
+        servings::move_to(&serving, &new_key, &txn).await?;
+        claimed += 1;
+    }
+    history::claim(anonymous_id, &user.user_key, &txn).await?;
+    preferences::claim(anonymous_id, &user.user_key, &txn).await?;
+
+    txn.commit().await?;
+    info!(
+        "User {:?} claimed {} progress records of {:?}",
+        user.name, claimed, anonymous_id
+    );
+
+    Ok(claimed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preferences::{ListingQuery, SortBy};
+    use crate::test_fixtures::{self, connect};
+
+    async fn add_serving(key: &str, user_id: &str, timestamp: i64, conn: &DatabaseConnection) {
+        let mut serving = test_fixtures::serving(key, user_id, timestamp * 10, 1000);
+        serving.last_timestamp = Set(timestamp);
+        serving.insert(conn).await.unwrap();
+    }
+
+    async fn position(key: &str, user_id: &str, conn: &DatabaseConnection) -> Option<i64> {
+        servings::find(&ServingKey::new(key, user_id), conn)
+            .await
+            .unwrap()
+            .map(|serving| serving.last_file_position)
+    }
+
+    async fn remembered(user_id: &str, conn: &DatabaseConnection) -> SortBy {
+        preferences::resolve(user_id, &ListingQuery::default(), conn)
+            .await
+            .unwrap()
+            .sort
+    }
+
+    #[test]
+    fn dummy_hash_has_default_parameters() {
+        let fresh = hash_password("password").unwrap();
+        let fresh = PasswordHash::new(&fresh).unwrap();
+        let dummy = PasswordHash::new(DUMMY_HASH).unwrap();
+
+        assert_eq!(dummy.algorithm, fresh.algorithm);
+        assert_eq!(dummy.version, fresh.version);
+        assert_eq!(dummy.params, fresh.params);
+        assert!(verify_password("dummy password", DUMMY_HASH));
+    }
+
+    #[rocket::async_test]
+    async fn registers_and_authenticates() {
+        let conn = connect().await;
+
+        let user = register(" alice ", "long password", &conn).await.unwrap();
+        assert_eq!(user.name, "alice");
+        assert_ne!(user.password_hash, "long password");
+
+        assert!(register("alice", "other password", &conn).await.is_err());
+        assert!(register("bob", "short", &conn).await.is_err());
+        assert!(register(" ", "long password", &conn).await.is_err());
+
+        let authenticated = authenticate("alice", "long password", &conn).await.unwrap();
+        assert_eq!(authenticated.map(|user| user.id), Some(user.id));
+        assert!(authenticate("alice", "wrong password", &conn)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(authenticate("bob", "long password", &conn)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(authenticate("bob", "dummy password", &conn)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[rocket::async_test]
+    async fn sessions_expire() {
+        let conn = connect().await;
+        let user = register("alice", "long password", &conn).await.unwrap();
+
+        let token = create_session(&user, &conn).await.unwrap();
+        let session_of = session_user(&token, &conn).await.unwrap();
+        assert_eq!(session_of.map(|user| user.id), Some(user.id));
+        assert!(session_user("unknown", &conn).await.unwrap().is_none());
+
+        Session::update_many()
+            .col_expr(
+                db::sessions::Column::ExpiresAt,
+                sea_query::Expr::value(now_secs() - 1),
+            )
+            .exec(&conn)
+            .await
+            .unwrap();
+        assert!(session_user(&token, &conn).await.unwrap().is_none());
+
+        let token = create_session(&user, &conn).await.unwrap();
+        assert_eq!(Session::find().count(&conn).await.unwrap(), 1);
+        delete_session(&token, &conn).await.unwrap();
+        assert!(session_user(&token, &conn).await.unwrap().is_none());
+    }
+
+    #[rocket::async_test]
+    async fn claims_anonymous_history() {
+        let conn = connect().await;
+        let user = register("alice", "long password", &conn).await.unwrap();
+        add_serving("lib/a.mkv", "anonymous", 10, &conn).await;
+        add_serving("lib/b.mkv", "anonymous", 10, &conn).await;
+        add_serving("lib/b.mkv", &user.user_key, 20, &conn).await;
+        add_serving("lib/c.mkv", "anonymous", 30, &conn).await;
+        add_serving("lib/c.mkv", &user.user_key, 20, &conn).await;
+
+        let claimed = claim_history("anonymous", &user, &conn).await.unwrap();
+        assert_eq!(claimed, 2);
+
+        assert_eq!(
+            position("lib/a.mkv", &user.user_key, &conn).await,
+            Some(100)
+        );
+        assert_eq!(
+            position("lib/b.mkv", &user.user_key, &conn).await,
+            Some(200)
+        );
+        assert_eq!(
+            position("lib/c.mkv", &user.user_key, &conn).await,
+            Some(300)
+        );
+        assert!(servings::find_by_user("anonymous", &conn)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn claims_listing_preferences() {
+        let conn = connect().await;
+        let alice = register("alice", "long password", &conn).await.unwrap();
+        let bob = register("bob", "long password", &conn).await.unwrap();
+        let query = |sort| ListingQuery {
+            sort: Some(sort),
+            ..Default::default()
+        };
+
+        preferences::resolve("anonymous", &query(SortBy::Size), &conn)
+            .await
+            .unwrap();
+        claim_history("anonymous", &alice, &conn).await.unwrap();
+        assert_eq!(remembered(&alice.user_key, &conn).await, SortBy::Size);
+        assert_eq!(remembered("anonymous", &conn).await, SortBy::Name);
+
+        // Preferences of the account are kept
+        preferences::resolve(&bob.user_key, &query(SortBy::Mtime), &conn)
+            .await
+            .unwrap();
+        preferences::resolve("other", &query(SortBy::Size), &conn)
+            .await
+            .unwrap();
+        claim_history("other", &bob, &conn).await.unwrap();
+        assert_eq!(remembered(&bob.user_key, &conn).await, SortBy::Mtime);
+        assert_eq!(remembered("other", &conn).await, SortBy::Name);
+    }
+
+    #[rocket::async_test]
+    async fn keys_of_accounts_cant_be_claimed() {
+        let conn = connect().await;
+        let alice = register("alice", "long password", &conn).await.unwrap();
+        let bob = register("bob", "long password", &conn).await.unwrap();
+        add_serving("lib/a.mkv", &bob.user_key, 10, &conn).await;
+
+        assert!(claim_history(&bob.user_key, &alice, &conn).await.is_err());
+        assert_eq!(
+            claim_history(&alice.user_key, &alice, &conn).await.unwrap(),
+            0
+        );
+        assert_eq!(position("lib/a.mkv", &bob.user_key, &conn).await, Some(100));
+        assert_eq!(position("lib/a.mkv", &alice.user_key, &conn).await, None);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::db;
+use crate::library::{self, ItemKind};
+use crate::metadata::{self, StoredMetadata};
+use crate::paths::Libraries;
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, DynamicImage, RgbImage};
+use log::debug;
+use rocket::tokio::task;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use db::prelude::*;
+
+/*
+Artwork of listing entries: "poster.jpg" and the like inside a directory, "<movie>-thumb.jpg" next to a movie,
+or a cover attached to a Matroska file. Listings record where the art of each entry comes from in `artwork`,
+under the entry's id, and /art/<id> serves it resized, cached as JPEG in the settings directory.
+*/
+
+// Compared case-insensitively, best first
+const DIR_ART_NAMES: &[&str] = &["poster", "folder", "cover"];
+// Appended to the movie's name, best first
+const MOVIE_ART_SUFFIXES: &[&str] = &["-thumb", "-poster", ""];
+const ART_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+
+// Art is shrunk to fit, keeping the aspect ratio
+const MAX_WIDTH: u32 = 320;
+const MAX_HEIGHT: u32 = 480;
+const JPEG_QUALITY: u8 = 85;
+
+const INSERT_CHUNK_SIZE: usize = db::insert_chunk_size(3);
+
+// Names of files being written, so concurrent requests of the same art don't write into one file
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Image files among `keys`, by lowercase names without extension
+fn images_by_stem(keys: &[String]) -> HashMap<String, &str> {
+    let mut sorted: Vec<&String> = keys.iter().collect();
+    // "poster.jpg" wins over "poster.png" no matter what order the database returns them in
+    sorted.sort();
+
+    let mut result = HashMap::new();
+    for key in sorted {
+        let (stem, ext) = match library::name_of(key).rsplit_once('.') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        if ART_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
+            result.entry(stem.to_lowercase()).or_insert(key.as_str());
+        }
+    }
+
+    result
+}
+
+/// Poster of a directory, among the files in it
+fn dir_art(files: &[String]) -> Option<String> {
+    let images = images_by_stem(files);
+    DIR_ART_NAMES
+        .iter()
+        .find_map(|name| images.get(*name))
+        .map(|key| String::from(*key))
+}
+
+/// Image named after the movie, among the files next to it
+fn movie_art(movie: &str, images: &HashMap<String, &str>) -> Option<String> {
+    let name = library::name_of(movie);
+    let stem = name
+        .rsplit_once('.')
+        .map_or(name, |(stem, _)| stem)
+        .to_lowercase();
+
+    MOVIE_ART_SUFFIXES
+        .iter()
+        .find_map(|suffix| images.get(&(stem.clone() + suffix)))
+        .map(|key| String::from(*key))
+}
+
+/// Where the art of `entries`, the indexed children of a directory, comes from: key of an image,
+/// or the entry's own key when the cover is attached to it. Entries without art are left out.
+/// `subdir_items` are the children of the directories among the entries
+pub fn find_sources(
+    entries: &[db::library_items::Model],
+    subdir_items: &[db::library_items::Model],
+    metadata: &HashMap<String, StoredMetadata>,
+) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+
+    let files: Vec<String> = entries
+        .iter()
+        .filter(|el| el.kind == ItemKind::File.as_str())
+        .map(|el| el.path.clone())
+        .collect();
+    let images = images_by_stem(&files);
+    for file in files.iter() {
+        let has_cover = matches!(metadata.get(file), Some(stored) if stored.info.has_cover);
+        let source = match movie_art(file, &images) {
+            Some(source) => source,
+            None if has_cover => file.clone(),
+            None => continue,
+        };
+        result.insert(file.clone(), source);
+    }
+
+    let mut files_in_dirs: HashMap<&str, Vec<String>> = HashMap::new();
+    for file in subdir_items
+        .iter()
+        .filter(|el| el.kind == ItemKind::File.as_str())
+    {
+        files_in_dirs
+            .entry(&file.parent)
+            .or_default()
+            .push(file.path.clone());
+    }
+    for (dir, files) in files_in_dirs {
+        if let Some(source) = dir_art(&files) {
+            result.insert(String::from(dir), source);
+        }
+    }
+
+    result
+}
+
+/// Remembers where art of the listed entries comes from, for /art/<id>
+pub async fn register(art: &[db::artwork::Model], conn: &DatabaseConnection) -> Result<()> {
+    for chunk in art.chunks(INSERT_CHUNK_SIZE) {
+        let models = chunk.iter().map(|el| db::artwork::ActiveModel {
+            id: Set(el.id.clone()),
+            item: Set(el.item.clone()),
+            source: Set(el.source.clone()),
+        });
+
+        Artwork::insert_many(models)
+            .on_conflict(
+                OnConflict::column(db::artwork::Column::Id)
+                    .update_columns([db::artwork::Column::Item, db::artwork::Column::Source])
+                    .to_owned(),
+            )
+            .exec_without_returning(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+fn load_image(source: &Path, is_attached: bool) -> Result<Option<DynamicImage>> {
+    let data = if is_attached {
+        match metadata::read_cover(source)? {
+            Some(data) => data,
+            None => return Ok(None),
+        }
+    } else {
+        fs::read(source).with_context(|| format!("failed to read {:?}", source))?
+    };
+
+    let image = image::load_from_memory(&data)
+        .with_context(|| format!("failed to decode art of {:?}", source))?;
+    Ok(Some(image))
+}
+
+/// Resizes the art into `cached`, unless it's there already and newer than the source.
+/// Returns false when the source has no art (anymore)
+fn refresh(source: &Path, is_attached: bool, cached: &Path, tmp: &Path) -> Result<bool> {
+    let source_mtime = fs::metadata(source)?.modified()?;
+    let is_fresh = matches!(
+        fs::metadata(cached).and_then(|cached| cached.modified()),
+        Ok(cached_mtime) if cached_mtime >= source_mtime
+    );
+    if is_fresh {
+        return Ok(true);
+    }
+
+    let image = match load_image(source, is_attached)? {
+        Some(image) => image,
+        None => return Ok(false),
+    };
+    let image = if image.width() > MAX_WIDTH || image.height() > MAX_HEIGHT {
+        image.thumbnail(MAX_WIDTH, MAX_HEIGHT)
+    } else {
+        image
+    };
+
+    let written = write_jpeg(&image.to_rgb8(), tmp).and_then(|()| Ok(fs::rename(tmp, cached)?));
+    if written.is_err() {
+        // Otherwise broken files would pile up in the cache
+        let _ = fs::remove_file(tmp);
+    }
+    written?;
+
+    Ok(true)
+}
+
+fn write_jpeg(image: &RgbImage, path: &Path) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    JpegEncoder::new_with_quality(&mut writer, JPEG_QUALITY).encode(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ColorType::Rgb8,
+    )?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Resized art, JPEG files named by entry ids
+pub struct ArtCache {
+    dir: PathBuf,
+}
+
+impl ArtCache {
+    pub fn new(settings_dir: &Path) -> Result<Self> {
+        let dir = settings_dir.join("art");
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create art cache at {:?}", dir))?;
+
+        Ok(ArtCache { dir })
+    }
+
+    /// Resized art of the entry, made on the first request and again whenever its source changes.
+    /// `None` when the id is unknown or the art is gone
+    pub async fn get(
+        &self,
+        id: &str,
+        libraries: &Libraries,
+        conn: &DatabaseConnection,
+    ) -> Result<Option<PathBuf>> {
+        // Ids are md5 hex digests, anything else could point outside of the cache
+        if id.len() != 32 || !id.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+
+        let art = match Artwork::find_by_id(String::from(id)).one(conn).await? {
+            Some(art) => art,
+            None => return Ok(None),
+        };
+        let source = match libraries.resolve_key(&art.source) {
+            Ok(source) => source,
+            Err(err) => {
+                debug!("Art of {:?} is gone: {}", art.item, err);
+                return Ok(None);
+            }
+        };
+
+        let is_attached = art.source == art.item;
+        let cached = self.dir.join(format!("{}.jpg", id));
+        let tmp = self.dir.join(format!(
+            "{}.{}.tmp",
+            id,
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let result_path = cached.clone();
+        let found =
+            task::spawn_blocking(move || refresh(&source, is_attached, &cached, &tmp)).await??;
+        if found {
+            Ok(Some(result_path))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::strings;
+
+    #[test]
+    fn finds_movie_art() {
+        let files = strings(&[
+            "m/Movie.mkv",
+            "m/Movie.jpg",
+            "m/Movie-thumb.JPG",
+            "m/Other.mkv",
+            "m/Other.srt",
+            "m/Third.mp4",
+            "m/third.png",
+        ]);
+        let images = images_by_stem(&files);
+
+        assert_eq!(
+            movie_art("m/Movie.mkv", &images).as_deref(),
+            Some("m/Movie-thumb.JPG")
+        );
+        assert_eq!(movie_art("m/Other.mkv", &images), None);
+        assert_eq!(
+            movie_art("m/Third.mp4", &images).as_deref(),
+            Some("m/third.png")
+        );
+    }
+
+    #[test]
+    fn finds_dir_art() {
+        let files = strings(&["s/Folder.png", "s/poster.png", "s/poster.jpg", "s/E01.mkv"]);
+        assert_eq!(dir_art(&files).as_deref(), Some("s/poster.jpg"));
+
+        let files = strings(&["s/E01.mkv", "s/E01-thumb.jpg"]);
+        assert_eq!(dir_art(&files), None);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::library;
+use crate::reading_dirs::{self, ListingContext, ReadDirResult, ResultItem};
+use crate::servings;
+use anyhow::Result;
+use log::debug;
+use rocket::serde::Serialize;
+use sea_orm::*;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/*
+"Continue watching" and "Next up" of the home page. Both come from the user's `movie_servings`:
+directories with recent activity are listed again, the same way as browsing them,
+so items have the same progress and links as in the directory itself.
+*/
+
+// Directories with the most recent activity, older ones are likely done with
+const MAX_DIRS: usize = 30;
+const MAX_ITEMS: usize = 20;
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ContinueItem {
+    #[serde(flatten)]
+    pub item: ResultItem,
+    /// Where the item is, to tell apart episodes of different shows
+    pub dir: String,
+    pub dir_link: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ContinueResult {
+    /// Partially watched items, most recent first
+    pub continue_watching: Vec<ContinueItem>,
+    /// First unwatched episode after the last finished one, for each series
+    pub next_up: Vec<ContinueItem>,
+}
+
+fn to_continue_item(item: ResultItem) -> ContinueItem {
+    let dir = library::parent_key(&item.rel_path);
+    let dir_link = reading_dirs::get_dir_link(&library::urlencode_key(&dir));
+
+    ContinueItem {
+        item,
+        dir,
+        dir_link,
+    }
+}
+
+/// Directories with the user's progress, most recently active first
+async fn active_dirs(user_id: &str, conn: &DatabaseConnection) -> Result<Vec<String>> {
+    let mut dirs: HashMap<String, i64> = HashMap::new();
+    for serving in servings::find_by_user(user_id, conn).await? {
+        let key = servings::item_key(&serving);
+        let activity = serving
+            .last_timestamp
+            .max(serving.reported_timestamp.unwrap_or(0));
+
+        let latest = dirs.entry(library::parent_key(&key)).or_insert(activity);
+        *latest = (*latest).max(activity);
+    }
+
+    let mut dirs: Vec<(String, i64)> = dirs.into_iter().collect();
+    dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    Ok(dirs
+        .into_iter()
+        .map(|(dir, _)| dir)
+        .filter(|dir| !dir.is_empty())
+        .take(MAX_DIRS)
+        .collect())
+}
+
+/// Index of the episode to watch next in the listing, `Some(None)` when the last finished one is the last one
+fn next_in_dir(listing: &ReadDirResult) -> Option<Option<usize>> {
+    // Only series have an order to follow
+    if !listing
+        .movies
+        .iter()
+        .any(|el| matches!(&el.parsed, Some(parsed) if parsed.episode.is_some()))
+    {
+        return None;
+    }
+
+    let last_finished = listing
+        .movies
+        .iter()
+        .enumerate()
+        .filter(|(_, el)| reading_dirs::is_watched(&el.progress))
+        .max_by_key(|(_, el)| el.progress.as_ref().map(|progress| progress.timestamp))
+        .map(|(idx, _)| idx)?;
+
+    let next = listing
+        .movies
+        .iter()
+        .skip(last_finished + 1)
+        .position(|el| !reading_dirs::is_watched(&el.progress))
+        .map(|idx| last_finished + 1 + idx);
+
+    Some(next)
+}
+
+/// First unwatched episode of the directory after `dir` in its parent, like the next season
+async fn next_in_sibling(dir: &str, ctx: &ListingContext<'_>) -> Result<Option<ResultItem>> {
+    let parent = library::parent_key(dir);
+    if parent.is_empty() {
+        return Ok(None);
+    }
+
+    let parent_listing = reading_dirs::read_dir(Path::new(&parent), ctx).await?;
+    let next_dir = parent_listing
+        .dirs
+        .iter()
+        .skip_while(|el| el.rel_path != dir)
+        .nth(1);
+    let next_dir = match next_dir {
+        Some(next_dir) => next_dir,
+        None => return Ok(None),
+    };
+
+    let listing = reading_dirs::read_dir(Path::new(&next_dir.rel_path), ctx).await?;
+    Ok(listing
+        .movies
+        .into_iter()
+        .find(|el| !reading_dirs::is_watched(&el.progress)))
+}
+
+pub async fn list(ctx: &ListingContext<'_>) -> Result<ContinueResult> {
+    let mut continue_watching = Vec::new();
+    let mut next_up = Vec::new();
+
+    for dir in active_dirs(ctx.user_id.as_str(), ctx.conn).await? {
+        let listing = match reading_dirs::read_dir(Path::new(&dir), ctx).await {
+            Ok(listing) => listing,
+            Err(err) => {
+                // Directory may be gone since it was watched
+                debug!("Skipping {:?}: {:#}", dir, err);
+                continue;
+            }
+        };
+
+        let next_idx = next_in_dir(&listing);
+        let mut next = None;
+        for (idx, item) in listing.movies.into_iter().enumerate() {
+            // Started ones are in "Continue watching", even if they're next
+            if reading_dirs::is_in_progress(&item.progress) {
+                continue_watching.push(to_continue_item(item));
+            } else if next_idx == Some(Some(idx)) {
+                next = Some(item);
+            }
+        }
+
+        if next_idx == Some(None) {
+            next = next_in_sibling(&dir, ctx).await.unwrap_or_else(|err| {
+                debug!("Failed to find the next season after {:?}: {:#}", dir, err);
+                None
+            });
+        }
+        if let Some(next) = next.filter(|el| !reading_dirs::is_in_progress(&el.progress)) {
+            next_up.push(to_continue_item(next));
+        }
+    }
+
+    continue_watching.sort_by_key(|el| {
+        std::cmp::Reverse(el.item.progress.as_ref().map(|progress| progress.timestamp))
+    });
+    continue_watching.truncate(MAX_ITEMS);
+
+    // Next season's first episode may be listed by two seasons' directories
+    let mut seen = HashSet::new();
+    next_up.retain(|el| seen.insert(el.item.rel_path.clone()));
+    next_up.truncate(MAX_ITEMS);
+
+    Ok(ContinueResult {
+        continue_watching,
+        next_up,
+    })
+}
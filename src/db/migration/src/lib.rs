@@ -1,12 +1,42 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20221127_000002_create_library_items;
+mod m20221204_000003_add_orphaned_to_movie_servings;
+mod m20221211_000004_create_media_metadata;
+mod m20221218_000005_add_seek_index_to_media_metadata;
+mod m20221225_000006_add_reported_progress_to_movie_servings;
+mod m20230108_000007_create_users_and_sessions;
+mod m20230115_000008_prefix_keys_with_library;
+mod m20230122_000009_create_playback_sessions;
+mod m20230129_000010_add_watched_to_movie_servings;
+mod m20230205_000011_split_movie_servings_keys;
+mod m20230212_000012_create_artwork;
+mod m20230219_000013_create_nfo_metadata;
+mod m20230226_000014_create_search_index;
+mod m20230305_000015_create_listing_preferences;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20221127_000002_create_library_items::Migration),
+            Box::new(m20221204_000003_add_orphaned_to_movie_servings::Migration),
+            Box::new(m20221211_000004_create_media_metadata::Migration),
+            Box::new(m20221218_000005_add_seek_index_to_media_metadata::Migration),
+            Box::new(m20221225_000006_add_reported_progress_to_movie_servings::Migration),
+            Box::new(m20230108_000007_create_users_and_sessions::Migration),
+            Box::new(m20230115_000008_prefix_keys_with_library::Migration),
+            Box::new(m20230122_000009_create_playback_sessions::Migration),
+            Box::new(m20230129_000010_add_watched_to_movie_servings::Migration),
+            Box::new(m20230205_000011_split_movie_servings_keys::Migration),
+            Box::new(m20230212_000012_create_artwork::Migration),
+            Box::new(m20230219_000013_create_nfo_metadata::Migration),
+            Box::new(m20230226_000014_create_search_index::Migration),
+            Box::new(m20230305_000015_create_listing_preferences::Migration),
+        ]
     }
 }
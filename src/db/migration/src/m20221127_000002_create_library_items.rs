@@ -0,0 +1,54 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(LibraryItems::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(LibraryItems::Path)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(LibraryItems::Parent).string().not_null())
+                    .col(ColumnDef::new(LibraryItems::Size).big_integer().not_null())
+                    .col(ColumnDef::new(LibraryItems::Mtime).big_integer().not_null())
+                    .col(ColumnDef::new(LibraryItems::Kind).string().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_library_items_parent")
+                    .table(LibraryItems::Table)
+                    .col(LibraryItems::Parent)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(LibraryItems::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum LibraryItems {
+    Table,
+    Path,
+    Parent,
+    Size,
+    Mtime,
+    Kind,
+}
@@ -0,0 +1,46 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(MediaMetadata::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(MediaMetadata::Path)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(MediaMetadata::Size).big_integer().not_null())
+                    .col(
+                        ColumnDef::new(MediaMetadata::Mtime)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(MediaMetadata::Info).text().not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(MediaMetadata::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum MediaMetadata {
+    Table,
+    Path,
+    Size,
+    Mtime,
+    Info,
+}
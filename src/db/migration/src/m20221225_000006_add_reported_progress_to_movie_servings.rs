@@ -0,0 +1,74 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// SQLite can't add several columns in one statement
+fn reported_columns() -> Vec<ColumnDef> {
+    vec![
+        ColumnDef::new(MovieServings::ReportedPositionSecs)
+            .double()
+            .null()
+            .to_owned(),
+        ColumnDef::new(MovieServings::ReportedDurationSecs)
+            .double()
+            .null()
+            .to_owned(),
+        ColumnDef::new(MovieServings::ReportedPaused)
+            .boolean()
+            .not_null()
+            .default(false)
+            .to_owned(),
+        ColumnDef::new(MovieServings::ReportedTimestamp)
+            .big_integer()
+            .null()
+            .to_owned(),
+    ]
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for mut column in reported_columns() {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(MovieServings::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            MovieServings::ReportedPositionSecs,
+            MovieServings::ReportedDurationSecs,
+            MovieServings::ReportedPaused,
+            MovieServings::ReportedTimestamp,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(MovieServings::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+pub enum MovieServings {
+    Table,
+    ReportedPositionSecs,
+    ReportedDurationSecs,
+    ReportedPaused,
+    ReportedTimestamp,
+}
@@ -0,0 +1,55 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, Statement};
+
+/*
+Keys start with the library name since there can be several libraries.
+Everything indexed and watched before that belongs to the "default" library, which is what --dir is.
+*/
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+const UP: &[&str] = &[
+    "UPDATE library_items SET \
+        path = 'default/' || path, \
+        parent = CASE WHEN parent = '' THEN 'default' ELSE 'default/' || parent END",
+    "UPDATE media_metadata SET path = 'default/' || path",
+    "UPDATE movie_servings SET path = 'default/' || path",
+];
+
+// Other libraries can't be kept without the prefix
+const DOWN: &[&str] = &[
+    "DELETE FROM library_items WHERE path <> 'default' AND path NOT LIKE 'default/%'",
+    "DELETE FROM library_items WHERE path = 'default'",
+    "UPDATE library_items SET \
+        path = substr(path, 9), \
+        parent = CASE WHEN parent = 'default' THEN '' ELSE substr(parent, 9) END",
+    "DELETE FROM media_metadata WHERE path NOT LIKE 'default/%'",
+    "UPDATE media_metadata SET path = substr(path, 9)",
+    "DELETE FROM movie_servings WHERE path NOT LIKE 'default/%'",
+    "UPDATE movie_servings SET path = substr(path, 9)",
+];
+
+async fn execute_all(manager: &SchemaManager<'_>, statements: &[&str]) -> Result<(), DbErr> {
+    let conn = manager.get_connection();
+    for sql in statements {
+        conn.execute(Statement::from_string(
+            manager.get_database_backend(),
+            String::from(*sql),
+        ))
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        execute_all(manager, UP).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        execute_all(manager, DOWN).await
+    }
+}
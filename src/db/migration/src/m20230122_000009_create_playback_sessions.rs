@@ -0,0 +1,84 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PlaybackSessions::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PlaybackSessions::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(PlaybackSessions::UserId).string().not_null())
+                    .col(ColumnDef::new(PlaybackSessions::Item).string().not_null())
+                    .col(
+                        ColumnDef::new(PlaybackSessions::StartedAt)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PlaybackSessions::EndedAt)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PlaybackSessions::StartPos)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PlaybackSessions::EndPos)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PlaybackSessions::BytesSent)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(PlaybackSessions::ClientAddress).string())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_playback_sessions_user_id_started_at")
+                    .table(PlaybackSessions::Table)
+                    .col(PlaybackSessions::UserId)
+                    .col(PlaybackSessions::StartedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PlaybackSessions::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum PlaybackSessions {
+    Table,
+    Id,
+    UserId,
+    Item,
+    StartedAt,
+    EndedAt,
+    StartPos,
+    EndPos,
+    BytesSent,
+    ClientAddress,
+}
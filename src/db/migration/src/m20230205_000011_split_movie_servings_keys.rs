@@ -0,0 +1,377 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, QueryResult};
+
+/*
+Servings were keyed by "<urlencoded library key>?<user_id>" strings, which had to be built the same way
+everywhere they were looked up. Now the user, the library and the path in it are columns of their own.
+SQLite can't change the primary key of a table, so it's rebuilt and the old keys are parsed row by row.
+*/
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+const NEW_TABLE: &str = "movie_servings_new";
+const OLD_TABLE: &str = "movie_servings_old";
+
+// Columns that are the same in both layouts
+const DATA_COLUMNS: &[MovieServings] = &[
+    MovieServings::LastTimestamp,
+    MovieServings::LastFilePosition,
+    MovieServings::FileLength,
+    MovieServings::Orphaned,
+    MovieServings::ReportedPositionSecs,
+    MovieServings::ReportedDurationSecs,
+    MovieServings::ReportedPaused,
+    MovieServings::ReportedTimestamp,
+    MovieServings::Watched,
+];
+
+fn data_column_defs() -> Vec<ColumnDef> {
+    vec![
+        ColumnDef::new(MovieServings::LastTimestamp)
+            .big_integer()
+            .not_null()
+            .to_owned(),
+        ColumnDef::new(MovieServings::LastFilePosition)
+            .big_integer()
+            .not_null()
+            .to_owned(),
+        ColumnDef::new(MovieServings::FileLength)
+            .big_integer()
+            .not_null()
+            .to_owned(),
+        ColumnDef::new(MovieServings::Orphaned)
+            .boolean()
+            .not_null()
+            .default(false)
+            .to_owned(),
+        ColumnDef::new(MovieServings::ReportedPositionSecs)
+            .double()
+            .null()
+            .to_owned(),
+        ColumnDef::new(MovieServings::ReportedDurationSecs)
+            .double()
+            .null()
+            .to_owned(),
+        ColumnDef::new(MovieServings::ReportedPaused)
+            .boolean()
+            .not_null()
+            .default(false)
+            .to_owned(),
+        ColumnDef::new(MovieServings::ReportedTimestamp)
+            .big_integer()
+            .null()
+            .to_owned(),
+        ColumnDef::new(MovieServings::Watched)
+            .boolean()
+            .null()
+            .to_owned(),
+    ]
+}
+
+fn data_values(row: &QueryResult) -> Result<Vec<SimpleExpr>, DbErr> {
+    Ok(vec![
+        row.try_get::<i64>("", "last_timestamp")?.into(),
+        row.try_get::<i64>("", "last_file_position")?.into(),
+        row.try_get::<i64>("", "file_length")?.into(),
+        row.try_get::<bool>("", "orphaned")?.into(),
+        row.try_get::<Option<f64>>("", "reported_position_secs")?
+            .into(),
+        row.try_get::<Option<f64>>("", "reported_duration_secs")?
+            .into(),
+        row.try_get::<bool>("", "reported_paused")?.into(),
+        row.try_get::<Option<i64>>("", "reported_timestamp")?.into(),
+        row.try_get::<Option<bool>>("", "watched")?.into(),
+    ])
+}
+
+/// Splits "<urlencoded key>?<user_id>" into the user id, the library and the path in it.
+/// The key has its '?' urlencoded, so the first one is where the user id starts
+fn parse_old_key(path: &str) -> Option<(String, String, String)> {
+    let (urlencoded, user_id) = path.split_once('?')?;
+    let chunks: Vec<String> = urlencoded
+        .split('/')
+        .map(|el| urlencoding::decode(el).map(|el| el.into_owned()))
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let (library_id, item_path) = chunks.split_first()?;
+
+    Some((
+        String::from(user_id),
+        library_id.clone(),
+        item_path.join("/"),
+    ))
+}
+
+fn old_key(user_id: &str, library_id: &str, item_path: &str) -> String {
+    let mut chunks = vec![urlencoding::encode(library_id).into_owned()];
+    if !item_path.is_empty() {
+        chunks.extend(
+            item_path
+                .split('/')
+                .map(|el| urlencoding::encode(el).into_owned()),
+        );
+    }
+
+    chunks.join("/") + "?" + user_id
+}
+
+async fn select_all(
+    manager: &SchemaManager<'_>,
+    table: &str,
+    key_columns: &[MovieServings],
+    order_by: MovieServings,
+) -> Result<Vec<QueryResult>, DbErr> {
+    let select = Query::select()
+        .columns(key_columns.iter().chain(DATA_COLUMNS).copied())
+        .from(Alias::new(table))
+        // The most recent one wins when several old keys turn out to be the same item
+        .order_by(order_by, Order::Desc)
+        .to_owned();
+
+    let backend = manager.get_database_backend();
+    manager
+        .get_connection()
+        .query_all(backend.build(&select))
+        .await
+}
+
+async fn execute(manager: &SchemaManager<'_>, statement: &InsertStatement) -> Result<(), DbErr> {
+    let backend = manager.get_database_backend();
+    manager
+        .get_connection()
+        .execute(backend.build(statement))
+        .await?;
+
+    Ok(())
+}
+
+async fn rename(manager: &SchemaManager<'_>, from: &str, to: &str) -> Result<(), DbErr> {
+    manager
+        .rename_table(
+            Table::rename()
+                .table(Alias::new(from), Alias::new(to))
+                .to_owned(),
+        )
+        .await
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let mut table = Table::create()
+            .table(Alias::new(NEW_TABLE))
+            .col(
+                ColumnDef::new(MovieServings::Id)
+                    .integer()
+                    .not_null()
+                    .auto_increment()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(MovieServings::UserId).string().not_null())
+            .col(ColumnDef::new(MovieServings::LibraryId).string().not_null())
+            .col(ColumnDef::new(MovieServings::ItemPath).string().not_null())
+            .to_owned();
+        for mut column in data_column_defs() {
+            table.col(&mut column);
+        }
+        manager.create_table(table).await?;
+
+        // Indexes stay with the table when it's renamed
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_movie_servings_user_item")
+                    .table(Alias::new(NEW_TABLE))
+                    .col(MovieServings::UserId)
+                    .col(MovieServings::LibraryId)
+                    .col(MovieServings::ItemPath)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        let mut columns = vec![
+            MovieServings::UserId,
+            MovieServings::LibraryId,
+            MovieServings::ItemPath,
+        ];
+        columns.extend_from_slice(DATA_COLUMNS);
+
+        let old_columns = [MovieServings::Path];
+        for row in select_all(
+            manager,
+            "movie_servings",
+            &old_columns,
+            MovieServings::LastTimestamp,
+        )
+        .await?
+        {
+            let path: String = row.try_get("", "path")?;
+            let (user_id, library_id, item_path) = match parse_old_key(&path) {
+                Some(parsed) => parsed,
+                // Nothing could ever look it up
+                None => continue,
+            };
+
+            let mut values: Vec<SimpleExpr> =
+                vec![user_id.into(), library_id.into(), item_path.into()];
+            values.extend(data_values(&row)?);
+
+            let insert = Query::insert()
+                .into_table(Alias::new(NEW_TABLE))
+                .columns(columns.clone())
+                .values_panic(values)
+                .on_conflict(
+                    OnConflict::columns([
+                        MovieServings::UserId,
+                        MovieServings::LibraryId,
+                        MovieServings::ItemPath,
+                    ])
+                    .do_nothing()
+                    .to_owned(),
+                )
+                .to_owned();
+            execute(manager, &insert).await?;
+        }
+
+        manager
+            .drop_table(Table::drop().table(MovieServings::Table).to_owned())
+            .await?;
+        rename(manager, NEW_TABLE, "movie_servings").await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        rename(manager, "movie_servings", OLD_TABLE).await?;
+
+        let mut table = Table::create()
+            .table(MovieServings::Table)
+            .col(
+                ColumnDef::new(MovieServings::Path)
+                    .string()
+                    .not_null()
+                    .primary_key(),
+            )
+            .to_owned();
+        for mut column in data_column_defs() {
+            table.col(&mut column);
+        }
+        manager.create_table(table).await?;
+
+        let mut columns = vec![MovieServings::Path];
+        columns.extend_from_slice(DATA_COLUMNS);
+
+        let new_columns = [
+            MovieServings::UserId,
+            MovieServings::LibraryId,
+            MovieServings::ItemPath,
+        ];
+        for row in select_all(manager, OLD_TABLE, &new_columns, MovieServings::Id).await? {
+            let path = old_key(
+                &row.try_get::<String>("", "user_id")?,
+                &row.try_get::<String>("", "library_id")?,
+                &row.try_get::<String>("", "item_path")?,
+            );
+
+            let mut values: Vec<SimpleExpr> = vec![path.into()];
+            values.extend(data_values(&row)?);
+
+            let insert = Query::insert()
+                .into_table(MovieServings::Table)
+                .columns(columns.clone())
+                .values_panic(values)
+                .to_owned();
+            execute(manager, &insert).await?;
+        }
+
+        manager
+            .drop_table(Table::drop().table(Alias::new(OLD_TABLE)).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum MovieServings {
+    Table,
+    Path,
+    Id,
+    UserId,
+    LibraryId,
+    ItemPath,
+    LastTimestamp,
+    LastFilePosition,
+    FileLength,
+    Orphaned,
+    ReportedPositionSecs,
+    ReportedDurationSecs,
+    ReportedPaused,
+    ReportedTimestamp,
+    Watched,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(
+        user_id: &str,
+        library_id: &str,
+        item_path: &str,
+    ) -> Option<(String, String, String)> {
+        Some((
+            String::from(user_id),
+            String::from(library_id),
+            String::from(item_path),
+        ))
+    }
+
+    #[test]
+    fn parses_old_keys() {
+        assert_eq!(
+            parse_old_key("default/Show/S01/a%20b.mkv?user"),
+            parsed("user", "default", "Show/S01/a b.mkv")
+        );
+        assert_eq!(parse_old_key("default?user"), parsed("user", "default", ""));
+        assert_eq!(
+            parse_old_key("default/a%3Fb.mkv?user?with?marks"),
+            parsed("user?with?marks", "default", "a?b.mkv")
+        );
+        assert_eq!(parse_old_key("default/a.mkv"), None);
+        assert_eq!(parse_old_key("default/%FF.mkv?user"), None);
+    }
+
+    #[test]
+    fn old_keys_round_trip() {
+        let keys = [
+            ("user", "default", "Show/S01/a b.mkv"),
+            ("user", "default", ""),
+            ("user", "default", "50%2F50.mkv"),
+            (
+                "user",
+                "films",
+                "Amélie/Le fabuleux destin d'Amélie Poulain.mkv",
+            ),
+            ("user", "аниме", "東京/第1話 ?.mkv"),
+            (
+                "5f0c8a6e-1d2b-4c3d-9e8f-0a1b2c3d4e5f",
+                "my library",
+                "a+b & c#d.mkv",
+            ),
+            ("account?with?marks", "default", "what?.mkv"),
+        ];
+
+        for (user_id, library_id, item_path) in keys {
+            let key = old_key(user_id, library_id, item_path);
+            assert_eq!(
+                parse_old_key(&key),
+                parsed(user_id, library_id, item_path),
+                "{}",
+                key
+            );
+        }
+        assert_eq!(
+            old_key("user", "default", "50%2F50.mkv"),
+            "default/50%252F50.mkv?user"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::ConnectionTrait;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// Probed before covers were looked for
+const MATROSKA_EXTENSIONS: &[&str] = &["mkv", "mka", "mk3d", "webm"];
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Artwork::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Artwork::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Artwork::Item).string().not_null())
+                    .col(ColumnDef::new(Artwork::Source).string().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        // Rows without seek index get probed again, and this time `has_cover` is set
+        let mut condition = Condition::any();
+        for ext in MATROSKA_EXTENSIONS {
+            condition = condition.add(Expr::col(MediaMetadata::Path).like(format!("%.{}", ext)));
+        }
+        let update = Query::update()
+            .table(MediaMetadata::Table)
+            .value(MediaMetadata::SeekIndex, Option::<String>::None)
+            .cond_where(condition)
+            .to_owned();
+
+        let backend = manager.get_database_backend();
+        manager
+            .get_connection()
+            .execute(backend.build(&update))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Artwork::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum Artwork {
+    Table,
+    Id,
+    Item,
+    Source,
+}
+
+#[derive(Iden)]
+pub enum MediaMetadata {
+    Table,
+    Path,
+    SeekIndex,
+}
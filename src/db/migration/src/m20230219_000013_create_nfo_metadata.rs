@@ -0,0 +1,55 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(NfoMetadata::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(NfoMetadata::Path)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(NfoMetadata::Item).string().not_null())
+                    .col(ColumnDef::new(NfoMetadata::Size).big_integer().not_null())
+                    .col(ColumnDef::new(NfoMetadata::Mtime).big_integer().not_null())
+                    // NULL for files that couldn't be parsed
+                    .col(ColumnDef::new(NfoMetadata::Info).text().null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_nfo_metadata_item")
+                    .table(NfoMetadata::Table)
+                    .col(NfoMetadata::Item)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(NfoMetadata::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum NfoMetadata {
+    Table,
+    Path,
+    Item,
+    Size,
+    Mtime,
+    Info,
+}
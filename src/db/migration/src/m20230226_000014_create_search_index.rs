@@ -0,0 +1,62 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, DatabaseBackend, Statement};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let backend = manager.get_database_backend();
+        if backend == DatabaseBackend::Sqlite {
+            // Only the last three are searched, the rest are there to filter and find the rows
+            let create = "CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5(\
+                key UNINDEXED, kind UNINDEXED, ext UNINDEXED, name, title, path, \
+                tokenize = 'unicode61 remove_diacritics 2')";
+            manager
+                .get_connection()
+                .execute(Statement::from_string(backend, String::from(create)))
+                .await?;
+
+            return Ok(());
+        }
+
+        // Elsewhere it's a plain table, searched with LIKE
+        manager
+            .create_table(
+                Table::create()
+                    .table(SearchIndex::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(SearchIndex::Key)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(SearchIndex::Kind).string().not_null())
+                    .col(ColumnDef::new(SearchIndex::Ext).string().not_null())
+                    .col(ColumnDef::new(SearchIndex::Name).text().not_null())
+                    .col(ColumnDef::new(SearchIndex::Title).text().not_null())
+                    .col(ColumnDef::new(SearchIndex::Path).text().not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(SearchIndex::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum SearchIndex {
+    Table,
+    Key,
+    Kind,
+    Ext,
+    Name,
+    Title,
+    Path,
+}
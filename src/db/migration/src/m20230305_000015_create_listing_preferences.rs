@@ -0,0 +1,50 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ListingPreferences::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ListingPreferences::UserId)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ListingPreferences::Sort).string().not_null())
+                    .col(
+                        ColumnDef::new(ListingPreferences::SortOrder)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ListingPreferences::Watched)
+                            .string()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ListingPreferences::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+pub enum ListingPreferences {
+    Table,
+    UserId,
+    Sort,
+    SortOrder,
+    Watched,
+}
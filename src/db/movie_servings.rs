@@ -2,14 +2,25 @@
 
 use sea_orm::entity::prelude::*;
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "movie_servings")]
 pub struct Model {
-    #[sea_orm(primary_key, auto_increment = false)]
-    pub path: String,
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub user_id: String,
+    pub library_id: String,
+    pub item_path: String,
     pub last_timestamp: i64,
     pub last_file_position: i64,
     pub file_length: i64,
+    pub orphaned: bool,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub reported_position_secs: Option<f64>,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub reported_duration_secs: Option<f64>,
+    pub reported_paused: bool,
+    pub reported_timestamp: Option<i64>,
+    pub watched: Option<bool>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -1,3 +1,11 @@
 //! SeaORM Entity. Generated by sea-orm-codegen 0.10.1
 
+pub use super::artwork::Entity as Artwork;
+pub use super::library_items::Entity as LibraryItem;
+pub use super::listing_preferences::Entity as ListingPreference;
+pub use super::media_metadata::Entity as MediaMetadata;
 pub use super::movie_servings::Entity as MovieServing;
+pub use super::nfo_metadata::Entity as NfoMetadata;
+pub use super::playback_sessions::Entity as PlaybackSession;
+pub use super::sessions::Entity as Session;
+pub use super::users::Entity as User;
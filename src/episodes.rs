@@ -0,0 +1,540 @@
+use rocket::serde::Serialize;
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/*
+Recognition of TV series in file and directory names, like "Show.Name.S02E05.Title.1080p.WEB-DL.x264-GROUP".
+Names are split into words, everything up to the first marker (episode, season, year, resolution or a release tag)
+is the show, words between an episode marker and the next marker are the episode's title.
+*/
+
+// Words of release names which are never a part of a title
+const RELEASE_TAGS: &[&str] = &[
+    "web",
+    "web-dl",
+    "webdl",
+    "webrip",
+    "web-rip",
+    "bluray",
+    "blu-ray",
+    "bdrip",
+    "brrip",
+    "bdremux",
+    "remux",
+    "hdtv",
+    "hdrip",
+    "dvdrip",
+    "dvd",
+    "hdr",
+    "hdr10",
+    "dv",
+    "sdr",
+    "x264",
+    "x265",
+    "h264",
+    "h265",
+    "hevc",
+    "avc",
+    "xvid",
+    "divx",
+    "av1",
+    "10bit",
+    "8bit",
+    "aac",
+    "ac3",
+    "eac3",
+    "ddp",
+    "dd",
+    "dts",
+    "truehd",
+    "atmos",
+    "flac",
+    "opus",
+    "proper",
+    "repack",
+    "internal",
+    "extended",
+    "uncut",
+    "remastered",
+    "amzn",
+    "nf",
+    "dsnp",
+    "hulu",
+    "atvp",
+    "hmax",
+    "multi",
+    "dubbed",
+    "subbed",
+];
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct ParsedName {
+    /// Show name, or the title for movies
+    pub show: Option<String>,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+    /// Last episode of multi-episode files, like S01E01E02
+    pub last_episode: Option<u32>,
+    pub episode_title: Option<String>,
+    pub year: Option<u32>,
+    pub resolution: Option<String>,
+    pub release_group: Option<String>,
+}
+
+/// Splits leading digits off, returning the number and the rest
+fn take_number(s: &str, max_digits: usize) -> Option<(u32, &str)> {
+    let digits = s.chars().take_while(|ch| ch.is_ascii_digit()).count();
+    if digits == 0 || digits > max_digits {
+        return None;
+    }
+
+    Some((s[..digits].parse().ok()?, &s[digits..]))
+}
+
+/// Season, episode and last episode of "S02E05", "S01E01E02", "S01E01-E02" or "2x05"
+fn parse_episode_marker(word: &str) -> Option<(u32, u32, Option<u32>)> {
+    let word = word.to_ascii_lowercase();
+
+    let (season, rest) = if let Some(rest) = word.strip_prefix('s') {
+        let (season, rest) = take_number(rest, 2)?;
+        (season, rest.strip_prefix('e')?)
+    } else {
+        let (season, rest) = take_number(&word, 2)?;
+        (season, rest.strip_prefix('x')?)
+    };
+    let (episode, rest) = take_number(rest, 3)?;
+
+    let last_episode = match rest.strip_prefix('-').unwrap_or(rest) {
+        "" => None,
+        rest => {
+            let (last_episode, rest) = take_number(rest.strip_prefix('e')?, 3)?;
+            if !rest.is_empty() || last_episode <= episode {
+                return None;
+            }
+            Some(last_episode)
+        }
+    };
+
+    Some((season, episode, last_episode))
+}
+
+/// "S02", as season packs are named
+fn parse_season_marker(word: &str) -> Option<u32> {
+    let rest = word.strip_prefix(['s', 'S'])?;
+    match take_number(rest, 2)? {
+        (season, "") => Some(season),
+        _ => None,
+    }
+}
+
+fn parse_number(word: &str, max_digits: usize) -> Option<u32> {
+    match take_number(word, max_digits)? {
+        (number, "") => Some(number),
+        _ => None,
+    }
+}
+
+fn parse_year(word: &str) -> Option<u32> {
+    parse_number(word, 4).filter(|year| (1900..2100).contains(year) && word.len() == 4)
+}
+
+fn parse_resolution(word: &str) -> Option<String> {
+    let word = word.to_ascii_lowercase();
+    match word.as_str() {
+        "4k" | "uhd" => return Some(String::from("2160p")),
+        _ => {}
+    }
+
+    let (lines, rest) = take_number(&word, 4)?;
+    match rest {
+        "p" | "i" if lines >= 240 => Some(word),
+        _ => None,
+    }
+}
+
+fn is_release_tag(word: &str) -> bool {
+    let word = word.to_ascii_lowercase();
+    RELEASE_TAGS.contains(&word.as_str())
+}
+
+/// Splits the group off "...x264-GROUP", as long as it follows something that's surely not a title
+fn split_release_group(name: &str) -> (&str, Option<&str>) {
+    let (rest, group) = match name.rsplit_once('-') {
+        Some(parts) => parts,
+        None => return (name, None),
+    };
+    if group.is_empty() || !group.chars().all(|ch| ch.is_ascii_alphanumeric()) {
+        return (name, None);
+    }
+
+    let last_word = rest
+        .rsplit(['.', ' ', '_', ']', ')'])
+        .next()
+        .unwrap_or(rest);
+    // "WEB-DL" is a single tag
+    if is_release_tag(&format!("{}-{}", last_word, group)) {
+        return (name, None);
+    }
+
+    if is_release_tag(last_word) || parse_resolution(last_word).is_some() {
+        (rest, Some(group))
+    } else {
+        (name, None)
+    }
+}
+
+#[derive(PartialEq)]
+enum Part {
+    Show,
+    EpisodeTitle,
+    Tail,
+}
+
+/// Parses a file name without the extension, or a directory name
+pub fn parse(name: &str) -> ParsedName {
+    let mut res = ParsedName::default();
+
+    // Fansub style, "[Group] Show - 05 [1080p]"
+    let mut name = name.trim();
+    if let Some((group, rest)) = name.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        if !group.trim().is_empty() {
+            res.release_group = Some(String::from(group.trim()));
+        }
+        name = rest;
+    }
+
+    let (name, group) = split_release_group(name);
+    if let Some(group) = group {
+        res.release_group = Some(String::from(group));
+    }
+
+    let words: Vec<&str> = name
+        .split(['.', ' ', '_', '[', ']', '(', ')'])
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    let mut show_words = Vec::new();
+    let mut episode_title_words = Vec::new();
+    let mut part = Part::Show;
+    let mut after_dash = false;
+
+    let mut i = 0;
+    while i < words.len() {
+        let word = words[i];
+        let next_number = words.get(i + 1).and_then(|next| parse_number(next, 3));
+        i += 1;
+
+        if word == "-" {
+            after_dash = true;
+            continue;
+        }
+        let is_after_dash = std::mem::replace(&mut after_dash, false);
+
+        if let Some((season, episode, last_episode)) = parse_episode_marker(word) {
+            if res.episode.is_none() {
+                res.season = Some(season);
+                res.episode = Some(episode);
+                res.last_episode = last_episode;
+                part = Part::EpisodeTitle;
+                continue;
+            }
+        }
+
+        if let Some(season) = parse_season_marker(word) {
+            res.season = res.season.or(Some(season));
+            part = Part::Tail;
+            continue;
+        }
+
+        let lower = word.to_ascii_lowercase();
+        match (lower.as_str(), next_number) {
+            ("season", Some(season)) => {
+                res.season = res.season.or(Some(season));
+                part = Part::Tail;
+                i += 1;
+                continue;
+            }
+            ("episode" | "ep", Some(episode)) if res.episode.is_none() => {
+                res.episode = Some(episode);
+                part = Part::EpisodeTitle;
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        // Absolute numbering of fansubs, "Show - 05"
+        if is_after_dash && part == Part::Show && !show_words.is_empty() && res.episode.is_none() {
+            if let Some(episode) = parse_number(word, 4) {
+                res.episode = Some(episode);
+                part = Part::EpisodeTitle;
+                continue;
+            }
+        }
+
+        // Titles may start with a number, "1917" is a movie
+        if i > 1 {
+            if let Some(year) = parse_year(word) {
+                res.year = res.year.or(Some(year));
+                part = Part::Tail;
+                continue;
+            }
+        }
+
+        if let Some(resolution) = parse_resolution(word) {
+            res.resolution = res.resolution.or(Some(resolution));
+            part = Part::Tail;
+            continue;
+        }
+
+        if is_release_tag(word) {
+            part = Part::Tail;
+            continue;
+        }
+
+        match part {
+            Part::Show => show_words.push(word),
+            Part::EpisodeTitle => episode_title_words.push(word),
+            Part::Tail => {}
+        }
+    }
+
+    if !show_words.is_empty() {
+        res.show = Some(show_words.join(" "));
+    }
+    if !episode_title_words.is_empty() {
+        res.episode_title = Some(episode_title_words.join(" "));
+    }
+
+    res
+}
+
+impl ParsedName {
+    fn is_series(&self) -> bool {
+        self.season.is_some() || self.episode.is_some()
+    }
+
+    /// Like "S02E05", "S01E01-E02" or "E05"
+    pub fn episode_label(&self) -> Option<String> {
+        let episode = self.episode?;
+        let mut res = match self.season {
+            Some(season) => format!("S{:02}E{:02}", season, episode),
+            None => format!("E{:02}", episode),
+        };
+        if let Some(last_episode) = self.last_episode {
+            res += &format!("-E{:02}", last_episode);
+        }
+
+        Some(res)
+    }
+
+    /// What to show instead of the raw name: "S02E05 – Title" for episodes, "Show – Season 2" for seasons
+    pub fn title(&self) -> Option<String> {
+        if let Some(label) = self.episode_label() {
+            return Some(match &self.episode_title {
+                Some(episode_title) => format!("{} – {}", label, episode_title),
+                None => label,
+            });
+        }
+
+        let season = self.season?;
+        Some(match &self.show {
+            Some(show) => format!("{} – Season {}", show, season),
+            None => format!("Season {}", season),
+        })
+    }
+
+    /// Series and season episodes are listed under, seasons are listed under their series
+    pub fn group(&self) -> Option<String> {
+        let show = self.show.as_ref().filter(|_| self.is_series())?;
+        match (self.episode, self.season) {
+            (Some(_), Some(season)) => Some(format!("{} – Season {}", show, season)),
+            _ => Some(show.clone()),
+        }
+    }
+}
+
+/// Splits off the run of ASCII digits `chars` start with, without leading zeros
+fn take_digits(chars: &mut Peekable<Chars>) -> String {
+    let mut number = String::new();
+    while let Some(ch) = chars.next_if(|ch| ch.is_ascii_digit()) {
+        if !(number.is_empty() && ch == '0') {
+            number.push(ch);
+        }
+    }
+
+    number
+}
+
+/// Order of names as people read them: case-insensitive, with numbers compared by value,
+/// so "Episode 2" comes before "episode 10"
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+
+    loop {
+        let ordering = match (a_chars.peek(), b_chars.peek()) {
+            // Names differing only in case or zeros still need an order
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a_ch), Some(b_ch)) if a_ch.is_ascii_digit() && b_ch.is_ascii_digit() => {
+                let a_number = take_digits(&mut a_chars);
+                let b_number = take_digits(&mut b_chars);
+                a_number
+                    .len()
+                    .cmp(&b_number.len())
+                    .then_with(|| a_number.cmp(&b_number))
+            }
+            (Some(a_ch), Some(b_ch)) => {
+                let ordering = a_ch.to_lowercase().cmp(b_ch.to_lowercase());
+                a_chars.next();
+                b_chars.next();
+                ordering
+            }
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Listing order: episodes and seasons of a series by their numbers, everything else by name
+pub fn compare(a: &ParsedName, a_name: &str, b: &ParsedName, b_name: &str) -> Ordering {
+    let key = |parsed: &ParsedName, name: &str| -> (String, u32, u32) {
+        match (parsed.is_series(), &parsed.show) {
+            (true, Some(show)) => (
+                show.clone(),
+                parsed.season.unwrap_or(0),
+                parsed.episode.unwrap_or(0),
+            ),
+            // "Season 2" directories of a series
+            (true, None) => (
+                String::new(),
+                parsed.season.unwrap_or(0),
+                parsed.episode.unwrap_or(0),
+            ),
+            (false, _) => (String::from(name), 0, 0),
+        }
+    };
+
+    let (a_key, a_season, a_episode) = key(a, a_name);
+    let (b_key, b_season, b_episode) = key(b, b_name);
+    natural_cmp(&a_key, &b_key)
+        .then(a_season.cmp(&b_season))
+        .then(a_episode.cmp(&b_episode))
+        .then_with(|| natural_cmp(a_name, b_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scene_episode() {
+        let parsed = parse("Show.Name.S02E05.The.Title.1080p.WEB-DL.x264-GROUP");
+        assert_eq!(
+            parsed,
+            ParsedName {
+                show: Some(String::from("Show Name")),
+                season: Some(2),
+                episode: Some(5),
+                last_episode: None,
+                episode_title: Some(String::from("The Title")),
+                year: None,
+                resolution: Some(String::from("1080p")),
+                release_group: Some(String::from("GROUP")),
+            }
+        );
+        assert_eq!(parsed.title().unwrap(), "S02E05 – The Title");
+        assert_eq!(parsed.group().unwrap(), "Show Name – Season 2");
+    }
+
+    #[test]
+    fn parses_episode_variants() {
+        let parsed = parse("Show Name (2019) - 1x05 - Pilot");
+        assert_eq!(parsed.show.as_deref(), Some("Show Name"));
+        assert_eq!(parsed.year, Some(2019));
+        assert_eq!((parsed.season, parsed.episode), (Some(1), Some(5)));
+        assert_eq!(parsed.episode_title.as_deref(), Some("Pilot"));
+
+        let parsed = parse("show.name.s01e01e02.720p");
+        assert_eq!(parsed.episode_label().unwrap(), "S01E01-E02");
+        assert_eq!(parsed.episode_title, None);
+
+        let parsed = parse("[SubGroup] Show Name - 05 [1080p]");
+        assert_eq!(parsed.release_group.as_deref(), Some("SubGroup"));
+        assert_eq!(parsed.show.as_deref(), Some("Show Name"));
+        assert_eq!((parsed.season, parsed.episode), (None, Some(5)));
+        assert_eq!(parsed.resolution.as_deref(), Some("1080p"));
+    }
+
+    #[test]
+    fn parses_movies() {
+        let parsed = parse("Movie.Title.2010.2160p.BluRay-GRP");
+        assert_eq!(parsed.show.as_deref(), Some("Movie Title"));
+        assert_eq!(parsed.year, Some(2010));
+        assert_eq!(parsed.resolution.as_deref(), Some("2160p"));
+        assert_eq!(parsed.release_group.as_deref(), Some("GRP"));
+        assert_eq!(parsed.title(), None);
+        assert_eq!(parsed.group(), None);
+
+        let parsed = parse("1917");
+        assert_eq!(parsed.show.as_deref(), Some("1917"));
+        assert_eq!(parsed.year, None);
+
+        // Dashes in titles aren't groups
+        let parsed = parse("Spider-Man");
+        assert_eq!(parsed.show.as_deref(), Some("Spider-Man"));
+        assert_eq!(parsed.release_group, None);
+    }
+
+    #[test]
+    fn parses_seasons() {
+        assert_eq!(parse("Season 2").title().unwrap(), "Season 2");
+
+        let parsed = parse("Show.Name.S03.1080p.WEB-DL");
+        assert_eq!(parsed.season, Some(3));
+        assert_eq!(parsed.title().unwrap(), "Show Name – Season 3");
+        assert_eq!(parsed.group().unwrap(), "Show Name");
+    }
+
+    #[test]
+    fn sorts_by_episode_number() {
+        let mut names = vec!["Show.S01E10", "Show.S02E01", "Another.Movie", "Show.S01E02"];
+        names.sort_by(|a, b| compare(&parse(a), a, &parse(b), b));
+        assert_eq!(
+            names,
+            ["Another.Movie", "Show.S01E02", "Show.S01E10", "Show.S02E01"]
+        );
+
+        let mut names = vec!["Season 10", "Season 2", "Extras"];
+        names.sort_by(|a, b| compare(&parse(a), a, &parse(b), b));
+        assert_eq!(names, ["Season 2", "Season 10", "Extras"]);
+    }
+
+    #[test]
+    fn sorts_names_naturally() {
+        let mut names = vec![
+            "episode 10.mkv",
+            "Episode 2.mkv",
+            "Episode 02.mkv",
+            "bonus.mkv",
+            "Episode 1b.mkv",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            [
+                "bonus.mkv",
+                "Episode 1b.mkv",
+                "Episode 02.mkv",
+                "Episode 2.mkv",
+                "episode 10.mkv"
+            ]
+        );
+    }
+}
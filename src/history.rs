@@ -0,0 +1,336 @@
+use crate::db;
+use crate::library::{self, ItemKind};
+use crate::reading_dirs;
+use anyhow::{anyhow, Result};
+use rocket::serde::Serialize;
+use rocket::tokio::sync::Mutex;
+use sea_orm::sea_query::Expr;
+use sea_orm::ActiveValue::Set;
+use sea_orm::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use db::prelude::*;
+
+/*
+Watch history. Every /files request ends up in `playback_sessions`: mpv makes a new request on each seek
+and sometimes a few at once, so requests of a file coming close to each other are merged into one session.
+Requests which only probed the file (see ProgressTracker) don't start sessions.
+*/
+
+// Requests closer than this to the end of the previous one are the same session
+const SESSION_GAP_SECS: i64 = 10 * 60;
+const MAX_ENTRIES: u64 = 500;
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// What a finished /files request tells about playback
+#[derive(Debug)]
+pub struct StreamRecord {
+    pub user_id: String,
+    /// Library key of the file
+    pub key: String,
+    pub started_at: i64,
+    pub ended_at: i64,
+    /// Byte range that looks like playback, `None` when the file was only probed
+    pub played: Option<(u64, u64)>,
+    pub bytes_sent: u64,
+    pub client_address: Option<String>,
+}
+
+fn to_db_int(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+/// Writes finished requests into sessions one at a time, so overlapping requests of a file
+/// can't both miss each other's session; cheap to clone
+#[derive(Clone, Default)]
+pub struct Recorder {
+    lock: Arc<Mutex<()>>,
+}
+
+impl Recorder {
+    pub async fn record(&self, stream: &StreamRecord, conn: &DatabaseConnection) -> Result<()> {
+        let _guard = self.lock.lock().await;
+
+        let txn = conn.begin().await?;
+        merge_into_session(stream, &txn).await?;
+        txn.commit().await?;
+
+        Ok(())
+    }
+}
+
+async fn merge_into_session<C: ConnectionTrait>(stream: &StreamRecord, conn: &C) -> Result<()> {
+    let previous = PlaybackSession::find()
+        .filter(db::playback_sessions::Column::UserId.eq(stream.user_id.as_str()))
+        .filter(db::playback_sessions::Column::Item.eq(stream.key.as_str()))
+        .filter(db::playback_sessions::Column::EndedAt.gte(stream.started_at - SESSION_GAP_SECS))
+        .order_by_desc(db::playback_sessions::Column::EndedAt)
+        .one(conn)
+        .await?;
+
+    match (previous, stream.played) {
+        (Some(session), played) => {
+            let mut active_session: db::playback_sessions::ActiveModel = session.clone().into();
+            active_session.ended_at = Set(session.ended_at.max(stream.ended_at));
+            active_session.bytes_sent = Set(session
+                .bytes_sent
+                .saturating_add(to_db_int(stream.bytes_sent)));
+            if let Some((start, end)) = played {
+                // Requests overlap, the one which started first has the session's start
+                if stream.started_at < session.started_at {
+                    active_session.started_at = Set(stream.started_at);
+                    active_session.start_pos = Set(to_db_int(start));
+                }
+                if stream.ended_at >= session.ended_at {
+                    active_session.end_pos = Set(to_db_int(end));
+                }
+            }
+            if session.client_address.is_none() {
+                active_session.client_address = Set(stream.client_address.clone());
+            }
+            active_session.update(conn).await?;
+        }
+        (None, Some((start, end))) => {
+            let session = db::playback_sessions::ActiveModel {
+                user_id: Set(stream.user_id.clone()),
+                item: Set(stream.key.clone()),
+                started_at: Set(stream.started_at),
+                ended_at: Set(stream.ended_at),
+                start_pos: Set(to_db_int(start)),
+                end_pos: Set(to_db_int(end)),
+                bytes_sent: Set(to_db_int(stream.bytes_sent)),
+                client_address: Set(stream.client_address.clone()),
+                ..Default::default()
+            };
+            session.insert(conn).await?;
+        }
+        (None, None) => {}
+    }
+
+    Ok(())
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Midnight UTC of a "YYYY-MM-DD" date, as UNIX timestamp
+fn parse_date(date: &str) -> Result<i64> {
+    let invalid = || anyhow!("invalid date {:?}, expected YYYY-MM-DD", date);
+
+    let parts: Vec<i64> = date
+        .split('-')
+        .map(|part| part.parse::<i64>().map_err(|_| invalid()))
+        .collect::<Result<_>>()?;
+    let (year, month, day) = match parts.as_slice() {
+        [year, month, day] => (*year, *month, *day),
+        _ => return Err(invalid()),
+    };
+
+    let is_leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let month_days = match month {
+        2 if is_leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err(invalid()),
+    };
+    if !(1..=month_days).contains(&day) {
+        return Err(invalid());
+    }
+
+    Ok(days_from_civil(year, month, day) * DAY_SECS)
+}
+
+/// Range of days to list, both ends included
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryFilter {
+    pub from: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl HistoryFilter {
+    /// Filter from "YYYY-MM-DD" dates, empty ones are as good as missing
+    pub fn from_dates(from: Option<&str>, to: Option<&str>) -> Result<Self> {
+        let parse = |date: Option<&str>| match date.map(str::trim) {
+            Some(date) if !date.is_empty() => parse_date(date).map(Some),
+            _ => Ok(None),
+        };
+
+        Ok(HistoryFilter {
+            from: parse(from)?,
+            // Sessions started any time during the last day count
+            until: parse(to)?.map(|to| to + DAY_SECS),
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct HistoryEntry {
+    pub item: String,
+    pub name: String,
+    pub dir_link: String,
+    pub started_at: i64,
+    pub ended_at: i64,
+    /// Played part of the file, when it's still in the library
+    pub start_percentage: Option<i64>,
+    pub end_percentage: Option<i64>,
+    pub bytes_sent: i64,
+    pub client_address: Option<String>,
+}
+
+fn percentage(position: i64, size: Option<i64>) -> Option<i64> {
+    match size {
+        Some(size) if size > 0 => Some((position * 100 / size).min(100)),
+        _ => None,
+    }
+}
+
+/// Sessions of the user, most recent first
+pub async fn list(
+    user_id: &str,
+    filter: HistoryFilter,
+    conn: &DatabaseConnection,
+) -> Result<Vec<HistoryEntry>> {
+    let mut query = PlaybackSession::find()
+        .filter(db::playback_sessions::Column::UserId.eq(user_id))
+        .order_by_desc(db::playback_sessions::Column::StartedAt);
+    if let Some(from) = filter.from {
+        query = query.filter(db::playback_sessions::Column::StartedAt.gte(from));
+    }
+    if let Some(until) = filter.until {
+        query = query.filter(db::playback_sessions::Column::StartedAt.lt(until));
+    }
+    let sessions = query.limit(MAX_ENTRIES).all(conn).await?;
+
+    let keys: Vec<String> = sessions.iter().map(|el| el.item.clone()).collect();
+    let sizes: HashMap<String, i64> = LibraryItem::find()
+        .filter(db::library_items::Column::Path.is_in(keys))
+        .filter(db::library_items::Column::Kind.eq(ItemKind::File.as_str()))
+        .all(conn)
+        .await?
+        .into_iter()
+        .map(|el| (el.path, el.size))
+        .collect();
+
+    Ok(sessions
+        .into_iter()
+        .map(|session| {
+            let size = sizes.get(&session.item).copied();
+            let name = match session.item.rsplit_once('/') {
+                Some((_, name)) => String::from(name),
+                None => session.item.clone(),
+            };
+
+            HistoryEntry {
+                dir_link: reading_dirs::get_parent_link(&session.item),
+                name,
+                started_at: session.started_at,
+                ended_at: session.ended_at,
+                start_percentage: percentage(session.start_pos, size),
+                end_percentage: percentage(session.end_pos, size),
+                bytes_sent: session.bytes_sent,
+                client_address: session.client_address,
+                item: session.item,
+            }
+        })
+        .collect())
+}
+
+/// Moves history of an anonymous id to the user's key
+pub async fn claim<C: ConnectionTrait>(anonymous_id: &str, user_key: &str, conn: &C) -> Result<()> {
+    PlaybackSession::update_many()
+        .col_expr(db::playback_sessions::Column::UserId, Expr::value(user_key))
+        .filter(db::playback_sessions::Column::UserId.eq(anonymous_id))
+        .exec(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Follows a renamed or moved file or directory, so its history stays with it
+pub async fn move_items<C: ConnectionTrait>(from: &str, to: &str, conn: &C) -> Result<()> {
+    let sessions = PlaybackSession::find()
+        .filter(library::subtree_condition(
+            db::playback_sessions::Column::Item,
+            from,
+        ))
+        .all(conn)
+        .await?;
+
+    for session in sessions
+        .into_iter()
+        .filter(|el| library::is_same_or_descendant(&el.item, from))
+    {
+        let new_item = String::from(to) + &session.item[from.len()..];
+        let mut active_session: db::playback_sessions::ActiveModel = session.into();
+        active_session.item = Set(new_item);
+        active_session.update(conn).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+    use rocket::futures::future::join_all;
+
+    #[rocket::async_test]
+    async fn overlapping_requests_make_one_session() {
+        let conn = test_fixtures::connect().await;
+        let recorder = Recorder::default();
+        let streams: Vec<StreamRecord> = (0..4)
+            .map(|idx| StreamRecord {
+                user_id: String::from("user"),
+                key: String::from("lib/a.mkv"),
+                started_at: 1000 + idx,
+                ended_at: 1100 + idx,
+                played: Some((idx as u64 * 100, idx as u64 * 100 + 50)),
+                bytes_sent: 10,
+                client_address: None,
+            })
+            .collect();
+
+        let recorded = join_all(streams.iter().map(|stream| recorder.record(stream, &conn))).await;
+        assert!(recorded.iter().all(|result| result.is_ok()));
+
+        let sessions = PlaybackSession::find().all(&conn).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        let session = &sessions[0];
+        assert_eq!((session.started_at, session.ended_at), (1000, 1103));
+        assert_eq!((session.start_pos, session.end_pos), (0, 350));
+        assert_eq!(session.bytes_sent, 40);
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2000-03-01").unwrap(), 951868800);
+        assert_eq!(parse_date("2024-02-29").unwrap(), 1709164800);
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("2023-13-01").is_err());
+        assert!(parse_date("2023-01").is_err());
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    #[test]
+    fn filter_includes_the_last_day() {
+        let filter = HistoryFilter::from_dates(Some("2023-01-01"), Some("2023-01-01")).unwrap();
+        assert_eq!(filter.from, Some(1672531200));
+        assert_eq!(filter.until, Some(1672531200 + DAY_SECS));
+
+        let filter = HistoryFilter::from_dates(Some(""), None).unwrap();
+        assert_eq!(filter, HistoryFilter::default());
+    }
+}
@@ -1,10 +1,15 @@
+use crate::{accounts, db};
 use rocket::http::Cookie;
+use rocket::outcome::try_outcome;
 use rocket::request::{FromRequest, Outcome};
 use rocket::serde::Serialize;
 use rocket::{http, Request};
+use rocket_db_pools::Database;
 use shrinkwraprs::Shrinkwrap;
 use uuid::Uuid;
 
+pub const ANONYMOUS_USER_COOKIE: &str = "mpvserve_user_id";
+
 #[derive(Debug)]
 pub enum NeverHappensError {
     MissingHostHeader,
@@ -14,6 +19,11 @@ pub enum NeverHappensError {
 pub struct HostHeader(String);
 
 impl HostHeader {
+    #[cfg(test)]
+    pub fn new(host: &str) -> Self {
+        HostHeader(String::from(host))
+    }
+
     pub fn to_string(&self) -> &String {
         match self {
             HostHeader(s) => s,
@@ -37,14 +47,64 @@ impl<'r> FromRequest<'r> for HostHeader {
     }
 }
 
+/// User logged in with a session cookie
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SessionUser {
+    pub id: i32,
+    pub name: String,
+    #[serde(skip)]
+    pub user_key: String,
+}
+
+async fn find_session_user(request: &Request<'_>) -> Option<SessionUser> {
+    let token = request
+        .cookies()
+        .get(accounts::SESSION_COOKIE)?
+        .value()
+        .to_string();
+    let db = db::Db::fetch(request.rocket())?;
+
+    match accounts::session_user(&token, &db.conn).await {
+        Ok(Some(user)) => Some(SessionUser {
+            id: user.id,
+            name: user.name,
+            user_key: user.user_key,
+        }),
+        Ok(None) => None,
+        Err(err) => {
+            log::error!("Failed to look up the session: {:?}", err);
+            None
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionUser {
+    type Error = NeverHappensError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // Both UserId and routes themselves ask for it, the session is looked up once
+        let user = request
+            .local_cache_async(async { find_session_user(request).await })
+            .await;
+
+        match user {
+            Some(user) => Outcome::Success(user.clone()),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Key progress records are stored under: `user_key` of the logged in user,
+/// or a random UUID remembered in a cookie for anonymous ones
 #[derive(Debug, Serialize, Shrinkwrap)]
 pub struct UserId(String);
 
 impl UserId {
-    pub fn to_string(&self) -> &String {
-        match self {
-            UserId(s) => s,
-        }
+    /// User id from a signed link, which mpv has instead of cookies
+    pub fn new(user_id: String) -> Self {
+        UserId(user_id)
     }
 }
 #[rocket::async_trait]
@@ -52,17 +112,39 @@ impl<'r> FromRequest<'r> for UserId {
     type Error = NeverHappensError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Outcome::Success(user) = request.guard::<SessionUser>().await {
+            return Outcome::Success(UserId(user.user_key));
+        }
+
         let cookies = request.cookies();
 
-        match cookies.get("mpvserve_user_id") {
+        match cookies.get(ANONYMOUS_USER_COOKIE) {
             Some(mpvserve_user_id) => {
                 Outcome::Success(UserId(mpvserve_user_id.value().to_string()))
             }
             None => {
                 let mpvserve_user_id = Uuid::new_v4().to_string();
-                cookies.add(Cookie::new("mpvserve_user_id", mpvserve_user_id.clone()));
+                cookies.add(Cookie::new(ANONYMOUS_USER_COOKIE, mpvserve_user_id.clone()));
                 Outcome::Success(UserId(mpvserve_user_id))
             }
         }
     }
 }
+
+/// Who the page is for: the key of their progress, and the account when they're logged in
+pub struct Viewer {
+    pub user_id: UserId,
+    pub account: Option<SessionUser>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Viewer {
+    type Error = NeverHappensError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user_id = try_outcome!(request.guard::<UserId>().await);
+        let account = request.guard::<SessionUser>().await.succeeded();
+
+        Outcome::Success(Viewer { user_id, account })
+    }
+}
@@ -0,0 +1,810 @@
+use crate::paths::{self, Libraries, PathResolver, ResolveError};
+use crate::servings::{self, ServingKey};
+use crate::{db, history, metadata, nfo, search};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use rocket::serde::Serialize;
+use rocket::tokio::sync::Mutex;
+use rocket::tokio::task;
+use sea_orm::sea_query::{Expr, LikeExpr};
+use sea_orm::ActiveValue::Set;
+use sea_orm::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, UNIX_EPOCH};
+
+use db::prelude::*;
+
+/*
+Walking network-mounted folders on every request is slow, so the directory tree is kept in
+`library_items` table instead. Paths there are '/'-separated, not urlencoded, and start with the library name.
+Roots of the libraries are stored as directories with the library name as a path and empty string as a parent.
+
+The index is filled by a full scan on startup (or on request) and then kept up to date by the watcher.
+Both go through the Scanner, so they never write at the same time.
+*/
+
+const INSERT_CHUNK_SIZE: usize = db::insert_chunk_size(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemKind {
+    Dir,
+    File,
+}
+
+impl ItemKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ItemKind::Dir => "dir",
+            ItemKind::File => "file",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ScannedItem {
+    path: String,
+    parent: String,
+    size: i64,
+    mtime: i64,
+    kind: ItemKind,
+}
+
+impl ScannedItem {
+    fn into_active_model(self) -> db::library_items::ActiveModel {
+        db::library_items::ActiveModel {
+            path: Set(self.path),
+            parent: Set(self.parent),
+            size: Set(self.size),
+            mtime: Set(self.mtime),
+            kind: Set(String::from(self.kind.as_str())),
+        }
+    }
+
+    fn differs_from(&self, model: &db::library_items::Model) -> bool {
+        self.parent != model.parent
+            || self.size != model.size
+            || self.mtime != model.mtime
+            || self.kind.as_str() != model.kind
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ScanStats {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub probed: usize,
+    /// NFO files read
+    pub nfo: usize,
+    /// Rows of the search index written
+    pub indexed: usize,
+    pub total: usize,
+    pub duration_ms: u128,
+}
+
+/// Converts index key to the urlencoded form used in links
+pub fn urlencode_key(key: &str) -> String {
+    let chunks: Vec<String> = key
+        .split('/')
+        .map(|el| urlencoding::encode(el).to_string())
+        .collect();
+
+    chunks.join("/")
+}
+
+/// Reverse of `urlencode_key`
+pub fn urldecode_key(urlencoded: &str) -> Result<String> {
+    let chunks: Vec<String> = urlencoded
+        .split('/')
+        .map(|el| urlencoding::decode(el).map(|el| el.into_owned()))
+        .collect::<Result<_, _>>()?;
+
+    Ok(chunks.join("/"))
+}
+
+pub fn is_same_or_descendant(key: &str, ancestor: &str) -> bool {
+    key == ancestor || (key.starts_with(ancestor) && key[ancestor.len()..].starts_with('/'))
+}
+
+/// Rows of `key` and everything under it in `column`, all of them when `key` is empty.
+/// LIKE ignores case on SQLite, so the rows still have to be checked with `is_same_or_descendant`
+pub fn subtree_condition<C: ColumnTrait>(column: C, key: &str) -> Condition {
+    if key.is_empty() {
+        return Condition::all();
+    }
+    let pattern = db::escape_like(key) + "/%";
+    Condition::any()
+        .add(column.eq(key))
+        .add(Expr::col(column.as_column_ref()).like(LikeExpr::str(&pattern).escape('\\')))
+}
+
+pub fn parent_key(key: &str) -> String {
+    match key.rfind('/') {
+        Some(idx) => String::from(&key[..idx]),
+        None => String::new(),
+    }
+}
+
+/// Last component of the key
+pub fn name_of(key: &str) -> &str {
+    key.rsplit_once('/').map_or(key, |(_, name)| name)
+}
+
+/// Walks the directory with the `dir_key` key. `ancestors` are canonical paths of directories
+/// being walked, so a symlink to one of them doesn't send the scan into a loop
+fn walk_dir(
+    paths: &PathResolver,
+    dir_key: &str,
+    ancestors: &mut Vec<PathBuf>,
+    result: &mut Vec<ScannedItem>,
+) {
+    let abs_dir = match paths.abs_path(dir_key) {
+        Some(abs_dir) => abs_dir,
+        None => return,
+    };
+    match fs::canonicalize(&abs_dir) {
+        Ok(canonical_dir) if ancestors.contains(&canonical_dir) => {
+            warn!("Skipping {:?}, it's a symlink to its own parent", abs_dir);
+            return;
+        }
+        Ok(canonical_dir) => ancestors.push(canonical_dir),
+        Err(err) => {
+            warn!(
+                "Failed to resolve dir {:?} while scanning: {}",
+                abs_dir, err
+            );
+            return;
+        }
+    }
+
+    walk_dir_entries(paths, dir_key, &abs_dir, ancestors, result);
+    ancestors.pop();
+}
+
+fn walk_dir_entries(
+    paths: &PathResolver,
+    dir_key: &str,
+    abs_dir: &Path,
+    ancestors: &mut Vec<PathBuf>,
+    result: &mut Vec<ScannedItem>,
+) {
+    let read_dir_res = match fs::read_dir(abs_dir) {
+        Ok(res) => res,
+        Err(err) => {
+            warn!("Failed to read dir {:?} while scanning: {}", abs_dir, err);
+            return;
+        }
+    };
+
+    for entry in read_dir_res {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!(
+                    "Error while iterating over directory {:?}: {}",
+                    abs_dir, err
+                );
+                continue;
+            }
+        };
+
+        let key = match entry.file_name().to_str() {
+            Some(name) => paths::join_key(dir_key, name),
+            None => {
+                warn!("Skipping non-unicode path {:?}", entry.path());
+                continue;
+            }
+        };
+
+        let metadata = match paths.entry_metadata(&entry.path()) {
+            Ok(Some(metadata)) => metadata,
+            Ok(None) => {
+                debug!("Skipping symlink {:?}", entry.path());
+                continue;
+            }
+            Err(err) => {
+                warn!("Failed to stat {:?}: {}", entry.path(), err);
+                continue;
+            }
+        };
+
+        if let Some(item) = scanned_item(key, &metadata) {
+            let dir_key = match item.kind {
+                ItemKind::Dir => Some(item.path.clone()),
+                ItemKind::File => None,
+            };
+            result.push(item);
+
+            if let Some(dir_key) = dir_key {
+                walk_dir(paths, &dir_key, ancestors, result);
+            }
+        }
+    }
+}
+
+fn scanned_item(key: String, metadata: &fs::Metadata) -> Option<ScannedItem> {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        ItemKind::Dir
+    } else if file_type.is_file() {
+        ItemKind::File
+    } else {
+        return None;
+    };
+
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| i64::try_from(duration.as_secs()).unwrap_or(0))
+        .unwrap_or(0);
+    let size = match kind {
+        ItemKind::Dir => 0,
+        ItemKind::File => i64::try_from(metadata.len()).unwrap_or(i64::MAX),
+    };
+
+    Some(ScannedItem {
+        parent: parent_key(&key),
+        path: key,
+        size,
+        mtime,
+        kind,
+    })
+}
+
+/// Scans `key` and everything below it, if it's a directory
+fn walk_subtree(paths: &PathResolver, key: &str) -> Vec<ScannedItem> {
+    let mut result = Vec::new();
+
+    // Whatever can't be resolved is removed from the index, as if it's gone
+    let metadata = match paths.resolve_key(key).map(fs::metadata) {
+        Ok(Ok(metadata)) => metadata,
+        Ok(Err(err)) => {
+            debug!("Failed to stat {:?}: {}", key, err);
+            return result;
+        }
+        Err(ResolveError::NotFound) => return result,
+        Err(err) => {
+            debug!("Not indexing {:?}: {}", key, err);
+            return result;
+        }
+    };
+
+    if let Some(item) = scanned_item(String::from(key), &metadata) {
+        let is_dir = item.kind == ItemKind::Dir;
+        result.push(item);
+
+        if is_dir {
+            walk_dir(paths, key, &mut Vec::new(), &mut result);
+        }
+    }
+
+    result
+}
+
+/// Makes the index match `items` within `scope` (index key of a subtree, empty string for everything)
+async fn sync_items(
+    items: Vec<ScannedItem>,
+    scope: &str,
+    conn: &DatabaseConnection,
+) -> Result<ScanStats> {
+    let mut stats = ScanStats {
+        total: items.len(),
+        ..Default::default()
+    };
+
+    let txn = conn.begin().await?;
+
+    let mut query = LibraryItem::find();
+    if !scope.is_empty() {
+        query = query.filter(db::library_items::Column::Path.starts_with(scope));
+    }
+
+    let mut existing: HashMap<String, db::library_items::Model> = query
+        .all(&txn)
+        .await?
+        .into_iter()
+        .filter(|model| scope.is_empty() || is_same_or_descendant(&model.path, scope))
+        .map(|model| (model.path.clone(), model))
+        .collect();
+
+    // Nothing can be orphaned on the very first scan, no need to look
+    let check_orphans = !existing.is_empty();
+    let mut to_insert = Vec::new();
+
+    for item in items {
+        match existing.remove(&item.path) {
+            None => {
+                if check_orphans && item.kind == ItemKind::File {
+                    set_servings_orphaned(&item.path, false, &txn).await?;
+                }
+                to_insert.push(item.into_active_model());
+            }
+            Some(model) => {
+                if item.differs_from(&model) {
+                    item.into_active_model().update(&txn).await?;
+                    stats.updated += 1;
+                }
+            }
+        }
+    }
+
+    stats.added = to_insert.len();
+    while !to_insert.is_empty() {
+        let rest = to_insert.split_off(to_insert.len().min(INSERT_CHUNK_SIZE));
+        LibraryItem::insert_many(to_insert).exec(&txn).await?;
+        to_insert = rest;
+    }
+
+    // Whatever is left wasn't found on disk
+    let removed: Vec<db::library_items::Model> = existing.into_values().collect();
+    stats.removed = removed.len();
+    for model in removed.iter() {
+        if model.kind == ItemKind::File.as_str() {
+            set_servings_orphaned(&model.path, true, &txn).await?;
+        }
+    }
+    let removed: Vec<String> = removed.into_iter().map(|model| model.path).collect();
+    for chunk in removed.chunks(db::LOOKUP_CHUNK_SIZE) {
+        LibraryItem::delete_many()
+            .filter(db::library_items::Column::Path.is_in(chunk.iter().cloned()))
+            .exec(&txn)
+            .await?;
+    }
+
+    txn.commit().await?;
+
+    Ok(stats)
+}
+
+/// Keeps only one scan running at a time; cheap to clone
+#[derive(Clone, Default)]
+pub struct Scanner {
+    lock: Arc<Mutex<()>>,
+}
+
+impl Scanner {
+    /// Scans all libraries, removing whatever is left of the ones not configured anymore
+    pub async fn scan(
+        &self,
+        libraries: &Libraries,
+        conn: &DatabaseConnection,
+    ) -> Result<ScanStats> {
+        let guard = self.lock.lock().await;
+        let started = Instant::now();
+
+        for library in libraries.iter() {
+            info!(
+                "Scanning library {} at {:?}",
+                library.name(),
+                library.root()
+            );
+        }
+
+        let walk_libraries = libraries.clone();
+        let items = task::spawn_blocking(move || {
+            let mut items = Vec::new();
+            for library in walk_libraries.iter() {
+                items.append(&mut walk_subtree(library, library.name()));
+            }
+            items
+        })
+        .await
+        .map_err(|err| anyhow!("scanning task failed: {}", err))?;
+
+        debug!("Found {} items in the libraries", items.len());
+
+        let mut stats = sync_items(items, "", conn)
+            .await
+            .context("failed to store scan results")?;
+
+        // Probing reads files, no reason to hold the watcher back meanwhile
+        drop(guard);
+        stats.probed = probe_metadata(libraries, "", conn).await;
+        stats.nfo = import_nfo(libraries, "", conn).await;
+        stats.indexed = update_search("", conn).await;
+        stats.duration_ms = started.elapsed().as_millis();
+
+        info!("Library scan finished: {:?}", stats);
+
+        Ok(stats)
+    }
+
+    /// Runs the scan in background, logging the failure if there's one
+    pub fn spawn_scan(&self, libraries: Libraries, conn: DatabaseConnection) {
+        let scanner = self.clone();
+        task::spawn(async move {
+            if let Err(err) = scanner.scan(&libraries, &conn).await {
+                log::error!("Library scan failed: {:?}", err);
+            }
+        });
+    }
+
+    /// Re-reads `key` from disk, adding, updating or removing it and its subtree
+    pub async fn index_path(
+        &self,
+        libraries: &Libraries,
+        key: &str,
+        conn: &DatabaseConnection,
+    ) -> Result<ScanStats> {
+        let guard = self.lock.lock().await;
+
+        let paths = libraries.by_key(key).cloned();
+        let key_string = String::from(key);
+        let items = task::spawn_blocking(move || match paths {
+            Some(paths) => walk_subtree(&paths, &key_string),
+            None => Vec::new(),
+        })
+        .await
+        .map_err(|err| anyhow!("scanning task failed: {}", err))?;
+
+        let mut stats = sync_items(items, key, conn)
+            .await
+            .with_context(|| format!("failed to update index for {:?}", key))?;
+
+        drop(guard);
+        stats.probed = probe_metadata(libraries, key, conn).await;
+        stats.nfo = import_nfo(libraries, key, conn).await;
+        // NFO files change titles of what's next to them
+        let search_scope = if nfo::is_nfo(key) {
+            parent_key(key)
+        } else {
+            String::from(key)
+        };
+        stats.indexed = update_search(&search_scope, conn).await;
+
+        Ok(stats)
+    }
+
+    /// Moves `from` and its subtree to `to`, carrying progress of the moved files over
+    pub async fn rename_path(
+        &self,
+        libraries: &Libraries,
+        from: &str,
+        to: &str,
+        conn: &DatabaseConnection,
+    ) -> Result<()> {
+        {
+            let _guard = self.lock.lock().await;
+
+            let txn = conn.begin().await?;
+            move_servings(from, to, &txn).await?;
+            history::move_items(from, to, &txn).await?;
+            txn.commit().await?;
+        }
+
+        // Old rows are removed and new ones are picked up from disk, stat is needed anyway
+        self.index_path(libraries, from, conn).await?;
+        self.index_path(libraries, to, conn).await?;
+
+        Ok(())
+    }
+}
+
+/// Failing to probe is not a reason to fail the scan
+async fn probe_metadata(libraries: &Libraries, scope: &str, conn: &DatabaseConnection) -> usize {
+    match metadata::probe_library(libraries, scope, conn).await {
+        Ok(count) => count,
+        Err(err) => {
+            log::error!("Metadata probing failed: {:?}", err);
+            0
+        }
+    }
+}
+
+/// Same goes for NFO files
+async fn import_nfo(libraries: &Libraries, scope: &str, conn: &DatabaseConnection) -> usize {
+    match nfo::import_library(libraries, scope, conn).await {
+        Ok(count) => count,
+        Err(err) => {
+            log::error!("NFO import failed: {:?}", err);
+            0
+        }
+    }
+}
+
+/// And so is failing to update the search index
+async fn update_search(scope: &str, conn: &DatabaseConnection) -> usize {
+    match search::reindex(scope, conn).await {
+        Ok(count) => count,
+        Err(err) => {
+            log::error!("Search index update failed: {:?}", err);
+            0
+        }
+    }
+}
+
+async fn set_servings_orphaned<C: ConnectionTrait>(
+    key: &str,
+    orphaned: bool,
+    conn: &C,
+) -> Result<()> {
+    for serving in servings::find_under(key, conn).await? {
+        if serving.orphaned == orphaned {
+            continue;
+        }
+
+        let mut active_serving: db::movie_servings::ActiveModel = serving.into();
+        active_serving.orphaned = Set(orphaned);
+        active_serving.update(conn).await?;
+    }
+
+    Ok(())
+}
+
+async fn move_servings<C: ConnectionTrait>(from: &str, to: &str, conn: &C) -> Result<()> {
+    for serving in servings::find_under(from, conn).await? {
+        let new_key = String::from(to) + &servings::item_key(&serving)[from.len()..];
+
+        // Progress of the moved file wins over whatever was recorded for the destination
+        servings::move_to(&serving, &ServingKey::new(&new_key, &serving.user_id), conn).await?;
+        MovieServing::update_many()
+            .col_expr(db::movie_servings::Column::Orphaned, Expr::value(false))
+            .filter(db::movie_servings::Column::Id.eq(serving.id))
+            .exec(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Returns whether `rel_dir` is a directory known to the index
+pub async fn dir_exists(rel_dir: &str, conn: &DatabaseConnection) -> Result<bool> {
+    let item = LibraryItem::find_by_id(String::from(rel_dir))
+        .one(conn)
+        .await?;
+
+    Ok(matches!(item, Some(item) if item.kind == ItemKind::Dir.as_str()))
+}
+
+/// Returns indexed children of `rel_dir`
+pub async fn list_dir(
+    rel_dir: &str,
+    conn: &DatabaseConnection,
+) -> Result<Vec<db::library_items::Model>> {
+    let items = LibraryItem::find()
+        .filter(db::library_items::Column::Parent.eq(rel_dir))
+        .all(conn)
+        .await?;
+
+    Ok(items)
+}
+
+/// Children of all the directories, for listings that look inside the listed subdirectories
+pub async fn list_dirs(
+    rel_dirs: &[String],
+    conn: &DatabaseConnection,
+) -> Result<Vec<db::library_items::Model>> {
+    let mut items = Vec::new();
+    for chunk in rel_dirs.chunks(db::LOOKUP_CHUNK_SIZE) {
+        items.extend(
+            LibraryItem::find()
+                .filter(db::library_items::Column::Parent.is_in(chunk.iter().cloned()))
+                .all(conn)
+                .await?,
+        );
+    }
+
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{self, connect, TempLibrary};
+
+    fn scanned(path: &str, kind: ItemKind, size: i64) -> ScannedItem {
+        ScannedItem {
+            path: String::from(path),
+            parent: parent_key(path),
+            size,
+            mtime: 0,
+            kind,
+        }
+    }
+
+    async fn indexed_paths(conn: &DatabaseConnection) -> Vec<String> {
+        let mut paths: Vec<String> = LibraryItem::find()
+            .all(conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|model| model.path)
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    async fn add_serving(key: &str, position: i64, conn: &DatabaseConnection) {
+        test_fixtures::serving(key, "user", position, 1000)
+            .insert(conn)
+            .await
+            .unwrap();
+    }
+
+    async fn serving(key: &str, conn: &DatabaseConnection) -> Option<db::movie_servings::Model> {
+        servings::find(&ServingKey::new(key, "user"), conn)
+            .await
+            .unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn sync_adds_updates_and_removes() {
+        let conn = connect().await;
+
+        let items = vec![
+            scanned("lib", ItemKind::Dir, 0),
+            scanned("lib/a.mkv", ItemKind::File, 1),
+            scanned("lib/b.mkv", ItemKind::File, 1),
+        ];
+        let stats = sync_items(items, "", &conn).await.unwrap();
+        assert_eq!((stats.added, stats.updated, stats.removed), (3, 0, 0));
+
+        let items = vec![
+            scanned("lib", ItemKind::Dir, 0),
+            scanned("lib/a.mkv", ItemKind::File, 2),
+            scanned("lib/c.mkv", ItemKind::File, 1),
+        ];
+        let stats = sync_items(items, "", &conn).await.unwrap();
+        assert_eq!((stats.added, stats.updated, stats.removed), (1, 1, 1));
+        assert_eq!(
+            indexed_paths(&conn).await,
+            ["lib", "lib/a.mkv", "lib/c.mkv"]
+        );
+        let updated = LibraryItem::find_by_id(String::from("lib/a.mkv"))
+            .one(&conn)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.size, 2);
+    }
+
+    #[rocket::async_test]
+    async fn sync_keeps_what_is_out_of_scope() {
+        let conn = connect().await;
+
+        let items = vec![
+            scanned("lib", ItemKind::Dir, 0),
+            scanned("lib/a", ItemKind::Dir, 0),
+            scanned("lib/a/1.mkv", ItemKind::File, 1),
+            scanned("lib/ab.mkv", ItemKind::File, 1),
+            scanned("other", ItemKind::Dir, 0),
+        ];
+        sync_items(items, "", &conn).await.unwrap();
+
+        let items = vec![scanned("lib/a", ItemKind::Dir, 0)];
+        let stats = sync_items(items, "lib/a", &conn).await.unwrap();
+        assert_eq!((stats.added, stats.updated, stats.removed), (0, 0, 1));
+        assert_eq!(
+            indexed_paths(&conn).await,
+            ["lib", "lib/a", "lib/ab.mkv", "other"]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn removed_files_orphan_their_progress() {
+        let conn = connect().await;
+        let items = || {
+            vec![
+                scanned("lib", ItemKind::Dir, 0),
+                scanned("lib/a.mkv", ItemKind::File, 1),
+            ]
+        };
+
+        sync_items(items(), "", &conn).await.unwrap();
+        add_serving("lib/a.mkv", 100, &conn).await;
+
+        sync_items(vec![scanned("lib", ItemKind::Dir, 0)], "", &conn)
+            .await
+            .unwrap();
+        let orphaned = serving("lib/a.mkv", &conn).await.unwrap();
+        assert!(orphaned.orphaned);
+        assert_eq!(orphaned.last_file_position, 100);
+
+        // The file coming back takes its progress back
+        sync_items(items(), "", &conn).await.unwrap();
+        assert!(!serving("lib/a.mkv", &conn).await.unwrap().orphaned);
+    }
+
+    #[rocket::async_test]
+    async fn moving_servings_overwrites_the_destination() {
+        let conn = connect().await;
+        add_serving("lib/Old/a.mkv", 100, &conn).await;
+        add_serving("lib/Old/b.mkv", 200, &conn).await;
+        add_serving("lib/New/a.mkv", 300, &conn).await;
+        add_serving("lib/Older/a.mkv", 400, &conn).await;
+
+        move_servings("lib/Old", "lib/New", &conn).await.unwrap();
+
+        assert!(serving("lib/Old/a.mkv", &conn).await.is_none());
+        assert!(serving("lib/Old/b.mkv", &conn).await.is_none());
+        let moved = serving("lib/New/a.mkv", &conn).await.unwrap();
+        assert_eq!(moved.last_file_position, 100);
+        assert!(!moved.orphaned);
+        assert_eq!(
+            serving("lib/New/b.mkv", &conn)
+                .await
+                .unwrap()
+                .last_file_position,
+            200
+        );
+        assert_eq!(
+            serving("lib/Older/a.mkv", &conn)
+                .await
+                .unwrap()
+                .last_file_position,
+            400
+        );
+    }
+
+    #[rocket::async_test]
+    async fn renames_carry_progress_over() {
+        let temp = TempLibrary::new();
+        temp.add_file("Old/a.mkv");
+        let libraries = &temp.libraries;
+        let conn = connect().await;
+        let scanner = Scanner::default();
+
+        scanner.scan(libraries, &conn).await.unwrap();
+        add_serving("lib/Old/a.mkv", 100, &conn).await;
+
+        fs::rename(temp.root().join("Old"), temp.root().join("New")).unwrap();
+        scanner
+            .rename_path(libraries, "lib/Old", "lib/New", &conn)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            indexed_paths(&conn).await,
+            ["lib", "lib/New", "lib/New/a.mkv"]
+        );
+        assert!(serving("lib/Old/a.mkv", &conn).await.is_none());
+        let moved = serving("lib/New/a.mkv", &conn).await.unwrap();
+        assert_eq!(moved.last_file_position, 100);
+        assert!(!moved.orphaned);
+    }
+
+    #[rocket::async_test]
+    async fn subtree_condition_takes_names_literally() {
+        let conn = connect().await;
+        for path in [
+            "lib/Show_1",
+            "lib/Show_1/a.mkv",
+            "lib/ShowA1/b.mkv",
+            "lib/100%/c.mkv",
+            "lib/100 Films/d.mkv",
+        ] {
+            scanned(path, ItemKind::File, 0)
+                .into_active_model()
+                .insert(&conn)
+                .await
+                .unwrap();
+        }
+
+        let subtree = |key: &'static str| {
+            let conn = &conn;
+            async move {
+                let mut paths: Vec<String> = LibraryItem::find()
+                    .filter(subtree_condition(db::library_items::Column::Path, key))
+                    .all(conn)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|model| model.path)
+                    .collect();
+                paths.sort();
+                paths
+            }
+        };
+        assert_eq!(
+            subtree("lib/Show_1").await,
+            vec!["lib/Show_1", "lib/Show_1/a.mkv"]
+        );
+        assert_eq!(subtree("lib/100%").await, vec!["lib/100%/c.mkv"]);
+        assert_eq!(subtree("").await.len(), 5);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::utils::strings;
+use anyhow::{anyhow, Result};
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum MediaCategory {
+    Video,
+    Audio,
+    Subtitle,
+    Image,
+}
+
+impl MediaCategory {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "video" => Some(MediaCategory::Video),
+            "audio" => Some(MediaCategory::Audio),
+            "subtitle" => Some(MediaCategory::Subtitle),
+            "image" => Some(MediaCategory::Image),
+            _ => None,
+        }
+    }
+
+    /// Whether files of the category get mpv:// links in the listing
+    pub fn is_playable(&self) -> bool {
+        matches!(self, MediaCategory::Video | MediaCategory::Audio)
+    }
+}
+
+/// `media` section of Rocket.toml
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct MediaConfig {
+    pub video: Vec<String>,
+    pub audio: Vec<String>,
+    pub subtitle: Vec<String>,
+    pub image: Vec<String>,
+    /// Extension to Content-Type overrides
+    pub mime_types: HashMap<String, String>,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        MediaConfig {
+            video: strings(&["mkv", "avi", "mp4", "webm", "m4v", "ts", "mov"]),
+            audio: strings(&["flac", "mp3", "opus", "ogg", "m4a", "wav"]),
+            subtitle: strings(&["srt", "ass", "ssa", "vtt", "sub"]),
+            image: strings(&["jpg", "jpeg", "png", "webp"]),
+            mime_types: HashMap::new(),
+        }
+    }
+}
+
+impl MediaConfig {
+    /// Adds extensions given on command line as "category=ext1,ext2"
+    pub fn add_cli_extensions(&mut self, arg: &str) -> Result<()> {
+        let (category, extensions) = arg
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected CATEGORY=EXT[,EXT...], got {:?}", arg))?;
+        let category = MediaCategory::from_name(category)
+            .ok_or_else(|| anyhow!("unknown media category {:?}", category))?;
+
+        let list = match category {
+            MediaCategory::Video => &mut self.video,
+            MediaCategory::Audio => &mut self.audio,
+            MediaCategory::Subtitle => &mut self.subtitle,
+            MediaCategory::Image => &mut self.image,
+        };
+        list.extend(
+            extensions
+                .split(',')
+                .map(|el| el.trim().trim_start_matches('.'))
+                .filter(|el| !el.is_empty())
+                .map(String::from),
+        );
+
+        Ok(())
+    }
+}
+
+static KNOWN_MIME_TYPES: &[(&str, &str)] = &[
+    ("mkv", "video/x-matroska"),
+    ("avi", "video/x-msvideo"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/x-m4v"),
+    ("webm", "video/webm"),
+    ("ts", "video/mp2t"),
+    ("mov", "video/quicktime"),
+    ("flac", "audio/flac"),
+    ("mp3", "audio/mpeg"),
+    ("opus", "audio/opus"),
+    ("ogg", "audio/ogg"),
+    ("m4a", "audio/mp4"),
+    ("wav", "audio/wav"),
+    ("srt", "application/x-subrip"),
+    ("ass", "text/x-ssa"),
+    ("ssa", "text/x-ssa"),
+    ("vtt", "text/vtt"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("webp", "image/webp"),
+];
+
+/// Extension lookups built from MediaConfig. Extensions are matched case-insensitively
+#[derive(Debug)]
+pub struct MediaTypes {
+    categories: HashMap<String, MediaCategory>,
+    mime_types: HashMap<String, String>,
+}
+
+impl MediaTypes {
+    pub fn from_config(config: &MediaConfig) -> Self {
+        let mut categories = HashMap::new();
+        let lists = [
+            (MediaCategory::Video, &config.video),
+            (MediaCategory::Audio, &config.audio),
+            (MediaCategory::Subtitle, &config.subtitle),
+            (MediaCategory::Image, &config.image),
+        ];
+        for (category, extensions) in lists {
+            for ext in extensions.iter() {
+                categories.insert(ext.to_lowercase(), category);
+            }
+        }
+
+        let mut mime_types: HashMap<String, String> = KNOWN_MIME_TYPES
+            .iter()
+            .map(|(ext, mime)| (String::from(*ext), String::from(*mime)))
+            .collect();
+        for (ext, mime) in config.mime_types.iter() {
+            mime_types.insert(ext.to_lowercase(), mime.clone());
+        }
+
+        MediaTypes {
+            categories,
+            mime_types,
+        }
+    }
+
+    pub fn category_by_extension(&self, ext: &str) -> Option<MediaCategory> {
+        self.categories.get(&ext.to_lowercase()).copied()
+    }
+
+    /// Lowercase extensions of the category, sorted
+    pub fn extensions(&self, category: MediaCategory) -> Vec<String> {
+        let mut result: Vec<String> = self
+            .categories
+            .iter()
+            .filter(|(_, el)| **el == category)
+            .map(|(ext, _)| ext.clone())
+            .collect();
+        result.sort();
+
+        result
+    }
+
+    /// Content-Type for serving the file. None for files outside of configured categories
+    pub fn content_type(&self, path: &Path) -> Option<&str> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        let category = self.category_by_extension(&ext)?;
+
+        match self.mime_types.get(&ext) {
+            Some(mime) => Some(mime.as_str()),
+            None => Some(match category {
+                MediaCategory::Subtitle => "text/plain",
+                _ => "application/octet-stream",
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_are_case_insensitive() {
+        let media_types = MediaTypes::from_config(&MediaConfig::default());
+
+        assert_eq!(
+            media_types.category_by_extension("MKV"),
+            Some(MediaCategory::Video)
+        );
+        assert_eq!(
+            media_types.category_by_extension("Flac"),
+            Some(MediaCategory::Audio)
+        );
+        assert_eq!(
+            media_types.content_type(Path::new("dir/Movie.MP4")),
+            Some("video/mp4")
+        );
+    }
+
+    #[test]
+    fn adds_cli_extensions() {
+        let mut config = MediaConfig::default();
+        config.add_cli_extensions("Video=.MTS, Rmvb,").unwrap();
+        config.add_cli_extensions("AUDIO=Ape").unwrap();
+        assert!(config.add_cli_extensions("video").is_err());
+        assert!(config.add_cli_extensions("books=epub").is_err());
+
+        let media_types = MediaTypes::from_config(&config);
+        assert_eq!(
+            media_types.category_by_extension("mts"),
+            Some(MediaCategory::Video)
+        );
+        assert_eq!(
+            media_types.category_by_extension("RMVB"),
+            Some(MediaCategory::Video)
+        );
+        assert_eq!(
+            media_types.category_by_extension("ape"),
+            Some(MediaCategory::Audio)
+        );
+        assert!(media_types
+            .extensions(MediaCategory::Video)
+            .contains(&String::from("mts")));
+    }
+
+    #[test]
+    fn content_types_by_category() {
+        let mut config = MediaConfig::default();
+        config.add_cli_extensions("video=mts").unwrap();
+        config.add_cli_extensions("subtitle=idx").unwrap();
+        config
+            .mime_types
+            .insert(String::from("MKV"), String::from("video/webm"));
+        let media_types = MediaTypes::from_config(&config);
+
+        let content_type = |path: &str| media_types.content_type(Path::new(path));
+        assert_eq!(content_type("a.mkv"), Some("video/webm"));
+        assert_eq!(content_type("a.avi"), Some("video/x-msvideo"));
+        assert_eq!(content_type("a.mp3"), Some("audio/mpeg"));
+        assert_eq!(content_type("a.srt"), Some("application/x-subrip"));
+        assert_eq!(content_type("a.png"), Some("image/png"));
+        // Known category, unknown MIME type
+        assert_eq!(content_type("a.mts"), Some("application/octet-stream"));
+        assert_eq!(content_type("a.idx"), Some("text/plain"));
+    }
+
+    #[test]
+    fn unknown_extensions() {
+        let media_types = MediaTypes::from_config(&MediaConfig::default());
+
+        assert_eq!(media_types.category_by_extension("exe"), None);
+        assert_eq!(media_types.category_by_extension(""), None);
+        assert_eq!(media_types.content_type(Path::new("setup.exe")), None);
+        assert_eq!(media_types.content_type(Path::new("README")), None);
+    }
+}
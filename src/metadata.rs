@@ -0,0 +1,485 @@
+mod avi;
+mod ebml;
+mod mp4;
+mod seek_index;
+
+pub use seek_index::{playback_position, SeekIndex};
+
+use crate::db;
+use crate::library::{self, ItemKind};
+use crate::paths::Libraries;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use rocket::serde::{json, Deserialize, Serialize};
+use rocket::tokio::task;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use db::prelude::*;
+
+/*
+Container probing without ffprobe: only headers are parsed, so it's cheap enough to run over the whole library.
+Results are stored in `media_metadata` table as JSON, together with size and mtime of the probed file,
+so files are re-probed only when they change. Seek index is stored next to it as a separate column,
+listings load both but parse the index only for files with tracked progress.
+*/
+
+// Extensions of containers the parsers understand; everything else isn't even opened
+static PROBED_EXTENSIONS: &[&str] = &[
+    "mkv", "mka", "mk3d", "webm", "mp4", "m4v", "m4a", "mov", "avi",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct TrackInfo {
+    pub codec: Option<String>,
+    pub language: Option<String>,
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Chapter {
+    pub start_secs: f64,
+    pub title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct MediaInfo {
+    pub container: String,
+    pub duration_secs: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub video_codec: Option<String>,
+    pub audio_tracks: Vec<TrackInfo>,
+    pub subtitle_tracks: Vec<TrackInfo>,
+    pub chapters: Vec<Chapter>,
+    /// Cover image among Matroska attachments, see `read_cover`
+    #[serde(default)]
+    pub has_cover: bool,
+}
+
+impl MediaInfo {
+    fn new(container: &str) -> Self {
+        MediaInfo {
+            container: String::from(container),
+            ..Default::default()
+        }
+    }
+
+    fn resolution_label(&self) -> Option<String> {
+        let width = self.width.unwrap_or(0);
+        let height = self.height?;
+
+        // Cinema aspect ratios make height smaller than the nominal one, width is more telling
+        let label = if width >= 3800 || height >= 2000 {
+            String::from("2160p")
+        } else if width >= 1900 || height >= 1000 {
+            String::from("1080p")
+        } else if width >= 1260 || height >= 700 {
+            String::from("720p")
+        } else {
+            format!("{}p", height)
+        };
+
+        Some(label)
+    }
+
+    /// Short human-readable description, like "1080p HEVC, 2 audio tracks"
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+
+        let video: Vec<String> = [self.resolution_label(), self.video_codec.clone()]
+            .into_iter()
+            .flatten()
+            .collect();
+        if !video.is_empty() {
+            parts.push(video.join(" "));
+        }
+
+        match self.audio_tracks.len() {
+            0 => {}
+            1 => parts.push(String::from("1 audio track")),
+            n => parts.push(format!("{} audio tracks", n)),
+        }
+
+        match self.subtitle_tracks.len() {
+            0 => {}
+            1 => parts.push(String::from("1 subtitle")),
+            n => parts.push(format!("{} subtitles", n)),
+        }
+
+        parts.join(", ")
+    }
+}
+
+#[derive(Debug)]
+pub struct ProbeResult {
+    pub info: MediaInfo,
+    pub seek_index: Option<SeekIndex>,
+}
+
+/// Turns container-specific codec ids into names people know
+fn friendly_codec_name(codec_id: &str) -> String {
+    let name = match codec_id.trim_end_matches('\0').trim() {
+        "V_MPEGH/ISO/HEVC" | "hvc1" | "hev1" | "HEVC" => "HEVC",
+        "V_MPEG4/ISO/AVC" | "avc1" | "avc3" | "H264" | "h264" | "X264" | "x264" => "H.264",
+        "V_AV1" | "av01" => "AV1",
+        "V_VP9" | "vp09" => "VP9",
+        "V_VP8" => "VP8",
+        "V_MPEG4/ISO/ASP" | "V_MPEG4/ISO/SP" | "mp4v" | "XVID" | "xvid" | "DIVX" | "divx"
+        | "DX50" | "FMP4" => "MPEG-4",
+        "V_MPEG2" | "V_MPEG1" | "mp2v" => "MPEG-2",
+        "A_AAC" | "mp4a" => "AAC",
+        "A_AC3" | "ac-3" => "AC3",
+        "A_EAC3" | "ec-3" => "E-AC3",
+        "A_DTS" => "DTS",
+        "A_TRUEHD" => "TrueHD",
+        "A_FLAC" | "fLaC" => "FLAC",
+        "A_OPUS" | "Opus" => "Opus",
+        "A_VORBIS" => "Vorbis",
+        "A_MPEG/L3" | ".mp3" => "MP3",
+        "S_TEXT/UTF8" => "SRT",
+        "S_TEXT/ASS" | "S_TEXT/SSA" | "S_ASS" | "S_SSA" => "ASS",
+        "S_TEXT/WEBVTT" => "WebVTT",
+        "S_HDMV/PGS" => "PGS",
+        "S_VOBSUB" => "VobSub",
+        "tx3g" => "Timed Text",
+        other if other.starts_with("A_AAC") => "AAC",
+        other if other.starts_with("A_PCM") => "PCM",
+        other => return String::from(other),
+    };
+
+    String::from(name)
+}
+
+// Byte readers used by the container parsers. All return None when data doesn't fit the type
+
+/// Big-endian unsigned integer of up to 8 bytes, empty data is 0 as in EBML
+fn be_uint(data: &[u8]) -> Option<u64> {
+    if data.len() > 8 {
+        return None;
+    }
+
+    Some(
+        data.iter()
+            .fold(0, |acc, byte| (acc << 8) | u64::from(*byte)),
+    )
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + 8)?;
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn le_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn le_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn fourcc(data: &[u8], offset: usize) -> Option<String> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(String::from_utf8_lossy(bytes).to_string())
+}
+
+/// Reads exactly `len` bytes at `offset`, refusing to allocate more than `limit`
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: u64, limit: u64) -> Result<Vec<u8>> {
+    if len > limit {
+        return Err(anyhow!("refusing to read {} bytes at {}", len, offset));
+    }
+
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; usize::try_from(len)?];
+    reader.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+/// Parses container headers of the file. Ok(None) means the format is not recognized
+pub fn probe(path: &Path) -> Result<Option<ProbeResult>> {
+    let mut file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    let file_len = file.metadata()?.len();
+
+    let mut magic = [0u8; 12];
+    if file_len < magic.len() as u64 {
+        return Ok(None);
+    }
+    file.read_exact(&mut magic)?;
+
+    let result = if magic[..4] == [0x1A, 0x45, 0xDF, 0xA3] {
+        ebml::probe(&mut file, file_len)?
+    } else if &magic[..4] == b"RIFF" && &magic[8..12] == b"AVI " {
+        avi::probe(&mut file, file_len)?
+    } else if matches!(
+        &magic[4..8],
+        b"ftyp" | b"moov" | b"mdat" | b"free" | b"skip" | b"wide"
+    ) {
+        mp4::probe(&mut file, file_len)?
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(result))
+}
+
+/// Cover art attached to the file, only Matroska has those
+pub fn read_cover(path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    let file_len = file.metadata()?.len();
+
+    let mut magic = [0u8; 4];
+    if file_len < magic.len() as u64 {
+        return Ok(None);
+    }
+    file.read_exact(&mut magic)?;
+
+    if magic == [0x1A, 0x45, 0xDF, 0xA3] {
+        ebml::read_cover(&mut file, file_len)
+    } else {
+        Ok(None)
+    }
+}
+
+fn is_probed(path: &str) -> bool {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => PROBED_EXTENSIONS.contains(&ext.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// Probes library files within `scope` (index key, empty for everything)
+/// that have no metadata yet or changed since the last probe
+pub async fn probe_library(
+    libraries: &Libraries,
+    scope: &str,
+    conn: &DatabaseConnection,
+) -> Result<usize> {
+    let in_scope = |path: &str| scope.is_empty() || library::is_same_or_descendant(path, scope);
+
+    let mut metadata_query = MediaMetadata::find();
+    let mut items_query =
+        LibraryItem::find().filter(db::library_items::Column::Kind.eq(ItemKind::File.as_str()));
+    if !scope.is_empty() {
+        metadata_query = metadata_query.filter(db::media_metadata::Column::Path.starts_with(scope));
+        items_query = items_query.filter(db::library_items::Column::Path.starts_with(scope));
+    }
+
+    // Rows without seek index were probed before it existed and need another go
+    let mut probed: HashMap<String, (i64, i64, bool)> = metadata_query
+        .all(conn)
+        .await?
+        .into_iter()
+        .filter(|model| in_scope(&model.path))
+        .map(|model| {
+            let has_seek_index = model.seek_index.is_some();
+            (model.path, (model.size, model.mtime, has_seek_index))
+        })
+        .collect();
+
+    let items: Vec<db::library_items::Model> = items_query
+        .all(conn)
+        .await?
+        .into_iter()
+        .filter(|item| in_scope(&item.path) && is_probed(&item.path))
+        .collect();
+
+    let mut to_probe = Vec::new();
+    for item in items {
+        if probed.remove(&item.path) != Some((item.size, item.mtime, true)) {
+            to_probe.push(item);
+        }
+    }
+
+    // Leftovers belong to files which are gone
+    let stale: Vec<String> = probed.into_keys().collect();
+    for chunk in stale.chunks(db::LOOKUP_CHUNK_SIZE) {
+        MediaMetadata::delete_many()
+            .filter(db::media_metadata::Column::Path.is_in(chunk.iter().cloned()))
+            .exec(conn)
+            .await?;
+    }
+
+    if to_probe.is_empty() {
+        return Ok(0);
+    }
+    info!("Probing metadata of {} files", to_probe.len());
+
+    let mut count = 0;
+    for item in to_probe {
+        let abs_path = match libraries.resolve_key(&item.path) {
+            Ok(abs_path) => abs_path,
+            Err(err) => {
+                debug!("Not probing {:?}: {}", item.path, err);
+                continue;
+            }
+        };
+        let probe_path = abs_path.clone();
+        // A parser panicking on one file shouldn't stop the rest
+        let probed = task::spawn_blocking(move || probe(&probe_path))
+            .await
+            .unwrap_or_else(|err| Err(anyhow!("parser panicked: {}", err)));
+        let (info, seek_index) = match probed {
+            Ok(Some(result)) => (result.info, result.seek_index),
+            Ok(None) => {
+                debug!("Unrecognized container in {:?}", abs_path);
+                (MediaInfo::default(), None)
+            }
+            Err(err) => {
+                // Stored anyway, so broken files are not re-read on every scan
+                warn!("Failed to probe {:?}: {:#}", abs_path, err);
+                (MediaInfo::default(), None)
+            }
+        };
+
+        store(&item, &info, seek_index.as_ref(), conn).await?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+async fn store(
+    item: &db::library_items::Model,
+    info: &MediaInfo,
+    seek_index: Option<&SeekIndex>,
+    conn: &DatabaseConnection,
+) -> Result<()> {
+    let model = db::media_metadata::ActiveModel {
+        path: Set(item.path.clone()),
+        size: Set(item.size),
+        mtime: Set(item.mtime),
+        info: Set(json::to_string(info)?),
+        // "null" when the file has no index, NULL is for rows not probed for it yet
+        seek_index: Set(Some(json::to_string(&seek_index)?)),
+    };
+
+    // The scan and listings can probe the same file at once, so it's an upsert
+    MediaMetadata::insert(model)
+        .on_conflict(
+            OnConflict::column(db::media_metadata::Column::Path)
+                .update_columns([
+                    db::media_metadata::Column::Size,
+                    db::media_metadata::Column::Mtime,
+                    db::media_metadata::Column::Info,
+                    db::media_metadata::Column::SeekIndex,
+                ])
+                .to_owned(),
+        )
+        .exec_without_returning(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Metadata stored for a file
+#[derive(Debug, Clone)]
+pub struct StoredMetadata {
+    pub info: MediaInfo,
+    // JSON, only files with progress need it parsed
+    seek_index: Option<String>,
+}
+
+impl StoredMetadata {
+    /// Seek index of the file, if the container has one. Indexes of long files are large,
+    /// so they're parsed on a blocking thread
+    pub async fn seek_index(&self) -> Result<Option<SeekIndex>> {
+        let seek_index = match &self.seek_index {
+            Some(seek_index) => seek_index.clone(),
+            None => return Ok(None),
+        };
+
+        task::spawn_blocking(move || Ok(json::from_str(&seek_index)?)).await?
+    }
+}
+
+/// Returns stored metadata for the given library paths
+pub async fn load(
+    paths: Vec<String>,
+    conn: &DatabaseConnection,
+) -> Result<HashMap<String, StoredMetadata>> {
+    let mut result = HashMap::new();
+
+    for chunk in paths.chunks(db::LOOKUP_CHUNK_SIZE) {
+        let models = MediaMetadata::find()
+            .filter(db::media_metadata::Column::Path.is_in(chunk.iter().cloned()))
+            .all(conn)
+            .await?;
+
+        for model in models {
+            match json::from_str::<MediaInfo>(&model.info) {
+                // Empty container means the probe failed
+                Ok(info) if !info.container.is_empty() => {
+                    let stored = StoredMetadata {
+                        info,
+                        seek_index: model.seek_index,
+                    };
+                    result.insert(model.path, stored);
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Broken metadata stored for {}: {}", model.path, err),
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+
+    #[rocket::async_test]
+    async fn storing_again_replaces_metadata() {
+        let conn = test_fixtures::connect().await;
+        let mut item = db::library_items::Model {
+            path: String::from("lib/a.mkv"),
+            parent: String::from("lib"),
+            size: 1,
+            mtime: 0,
+            kind: String::from(ItemKind::File.as_str()),
+        };
+
+        store(&item, &MediaInfo::new("matroska"), None, &conn)
+            .await
+            .unwrap();
+        item.size = 2;
+        let seek_index = SeekIndex::from_points(vec![(0, 0.0), (100, 10.0)]);
+        store(&item, &MediaInfo::new("webm"), seek_index.as_ref(), &conn)
+            .await
+            .unwrap();
+
+        let model = MediaMetadata::find_by_id(item.path.clone())
+            .one(&conn)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(model.size, 2);
+
+        let stored = load(vec![item.path.clone()], &conn).await.unwrap();
+        let stored = &stored[&item.path];
+        assert_eq!(stored.info.container, "webm");
+        assert!(stored.seek_index().await.unwrap().is_some());
+    }
+}
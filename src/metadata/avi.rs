@@ -0,0 +1,431 @@
+use super::{
+    fourcc, friendly_codec_name, le_u16, le_u32, read_at, MediaInfo, ProbeResult, SeekIndex,
+    TrackInfo,
+};
+use anyhow::{anyhow, Result};
+use std::io::{Read, Seek};
+
+/*
+AVI is a RIFF file: chunks are (fourcc, 32-bit little-endian size, body padded to even length).
+LIST chunks have a list type fourcc followed by subchunks. Stream headers live in LIST hdrl.
+*/
+
+const MAX_HDRL_SIZE: u64 = 4 * 1024 * 1024;
+// 16 bytes per frame of every stream, a few hours of video fit easily
+const MAX_IDX1_SIZE: u64 = 64 * 1024 * 1024;
+
+const IDX1_ENTRY_LEN: usize = 16;
+
+pub(super) struct Chunk<'a> {
+    pub(super) id: [u8; 4],
+    // Set for LIST and RIFF chunks
+    pub(super) list_type: Option<[u8; 4]>,
+    pub(super) body: &'a [u8],
+}
+
+/// Iterates over chunks of an in-memory LIST body
+pub(super) struct Chunks<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Chunks<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Chunks { data, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Chunk<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id: [u8; 4] = self.data.get(self.pos..self.pos + 4)?.try_into().ok()?;
+        let size = le_u32(self.data, self.pos + 4)? as usize;
+        let body_start = self.pos + 8;
+        let body_end = body_start.saturating_add(size).min(self.data.len());
+        self.pos = body_end + (size & 1);
+
+        let body = self.data.get(body_start..body_end)?;
+        if &id == b"LIST" {
+            Some(Chunk {
+                id,
+                list_type: body.get(..4)?.try_into().ok(),
+                body: body.get(4..)?,
+            })
+        } else {
+            Some(Chunk {
+                id,
+                list_type: None,
+                body,
+            })
+        }
+    }
+}
+
+fn audio_codec_name(format_tag: u16) -> String {
+    let name = match format_tag {
+        0x0001 => "PCM",
+        0x0050 => "MP2",
+        0x0055 => "MP3",
+        0x00FF | 0x1610 => "AAC",
+        0x0161 => "WMA",
+        0x2000 => "AC3",
+        0x2001 => "DTS",
+        0x674F => "Vorbis",
+        other => return format!("0x{:04X}", other),
+    };
+
+    String::from(name)
+}
+
+fn null_terminated(data: &[u8]) -> String {
+    let end = data
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).to_string()
+}
+
+struct StreamHeader {
+    kind: String,
+    scale: u32,
+    rate: u32,
+    length: u32,
+}
+
+fn parse_strl(strl: &[u8], info: &mut MediaInfo) -> Option<StreamHeader> {
+    let mut header = None;
+    let mut format = None;
+    let mut name = None;
+
+    for chunk in Chunks::new(strl) {
+        match &chunk.id {
+            b"strh" => {
+                header = Some(StreamHeader {
+                    kind: fourcc(chunk.body, 0)?,
+                    scale: le_u32(chunk.body, 20)?,
+                    rate: le_u32(chunk.body, 24)?,
+                    length: le_u32(chunk.body, 32)?,
+                })
+            }
+            b"strf" => format = Some(chunk.body),
+            b"strn" => name = Some(null_terminated(chunk.body)),
+            _ => {}
+        }
+    }
+
+    let header = header?;
+    match (header.kind.as_str(), format) {
+        ("vids", Some(format)) if info.video_codec.is_none() => {
+            // BITMAPINFOHEADER
+            info.video_codec = fourcc(format, 16).map(|codec| friendly_codec_name(&codec));
+            info.width = le_u32(format, 4);
+            info.height = le_u32(format, 8).map(|height| (height as i32).unsigned_abs());
+        }
+        ("auds", Some(format)) => {
+            // WAVEFORMATEX
+            info.audio_tracks.push(TrackInfo {
+                codec: le_u16(format, 0).map(audio_codec_name),
+                language: None,
+                name,
+            });
+        }
+        ("txts", _) => info.subtitle_tracks.push(TrackInfo {
+            codec: None,
+            language: None,
+            name,
+        }),
+        _ => {}
+    }
+
+    Some(header)
+}
+
+/// Video stream, whose frames are used to build the seek index
+struct VideoStream {
+    // Chunk ids of the stream's frames start with its number, like "00dc"
+    number: usize,
+    scale: u32,
+    rate: u32,
+}
+
+fn parse_hdrl(hdrl: &[u8], info: &mut MediaInfo) -> Option<VideoStream> {
+    let mut frame_duration_us = None;
+    let mut total_frames = None;
+    let mut video_duration = None;
+    let mut video_stream = None;
+    let mut stream_number = 0;
+
+    for chunk in Chunks::new(hdrl) {
+        if &chunk.id == b"avih" {
+            frame_duration_us = le_u32(chunk.body, 0);
+            total_frames = le_u32(chunk.body, 16);
+            info.width = le_u32(chunk.body, 32);
+            info.height = le_u32(chunk.body, 36);
+        } else if chunk.list_type.as_ref() == Some(b"strl") {
+            if let Some(header) = parse_strl(chunk.body, info) {
+                if header.kind == "vids" && header.rate > 0 && video_duration.is_none() {
+                    video_duration = Some(
+                        f64::from(header.length) * f64::from(header.scale) / f64::from(header.rate),
+                    );
+                    video_stream = Some(VideoStream {
+                        number: stream_number,
+                        scale: header.scale,
+                        rate: header.rate,
+                    });
+                }
+            }
+            stream_number += 1;
+        }
+    }
+
+    // Stream header is more precise, main header's frame count is limited to the first RIFF chunk
+    info.duration_secs = video_duration.or(match (frame_duration_us, total_frames) {
+        (Some(frame_duration_us), Some(total_frames)) if frame_duration_us > 0 => {
+            Some(f64::from(total_frames) * f64::from(frame_duration_us) / 1e6)
+        }
+        _ => None,
+    });
+
+    video_stream
+}
+
+/// Maps offsets of the video frames listed in idx1 to their times.
+/// `movi_offset` is the position of "movi" list type, which idx1 offsets are usually relative to
+fn parse_idx1(idx1: &[u8], movi_offset: u64, stream: &VideoStream) -> Option<SeekIndex> {
+    let stream_prefix = format!("{:02}", stream.number);
+    let mut entries = idx1
+        .chunks_exact(IDX1_ENTRY_LEN)
+        .filter(|entry| {
+            // Compressed or uncompressed frames, not palette changes
+            entry.starts_with(stream_prefix.as_bytes()) && matches!(&entry[2..4], b"dc" | b"db")
+        })
+        .peekable();
+
+    // Some muxers write absolute offsets instead
+    let base = match entries.peek().and_then(|entry| le_u32(entry, 8)) {
+        Some(first_offset) if u64::from(first_offset) >= movi_offset => 0,
+        _ => movi_offset,
+    };
+
+    let frame_secs = f64::from(stream.scale) / f64::from(stream.rate);
+    let points = entries
+        .enumerate()
+        .filter_map(|(frame, entry)| {
+            let offset = base + u64::from(le_u32(entry, 8)?);
+            Some((offset, frame as f64 * frame_secs))
+        })
+        .collect();
+
+    SeekIndex::from_points(points)
+}
+
+/// Walks top-level chunks of the RIFF AVI body, calling `visit` with (id, list type, body offset, body size)
+pub(super) fn walk_top_level<R: Read + Seek, F: FnMut(&[u8; 4], Option<&[u8; 4]>, u64, u64)>(
+    reader: &mut R,
+    file_len: u64,
+    mut visit: F,
+) -> Result<()> {
+    // Skipping "RIFF", size and "AVI "
+    let mut offset = 12;
+
+    while offset + 8 <= file_len {
+        let header = read_at(reader, offset, (file_len - offset).min(12), 12)?;
+        let id: [u8; 4] = header[..4].try_into()?;
+        let size = u64::from(le_u32(&header, 4).ok_or_else(|| anyhow!("truncated chunk"))?);
+
+        if &id == b"LIST" {
+            let list_type: Option<[u8; 4]> = header.get(8..12).and_then(|t| t.try_into().ok());
+            visit(&id, list_type.as_ref(), offset + 12, size.saturating_sub(4));
+        } else {
+            visit(&id, None, offset + 8, size);
+        }
+
+        offset += 8 + size + (size & 1);
+    }
+
+    Ok(())
+}
+
+pub fn probe<R: Read + Seek>(reader: &mut R, file_len: u64) -> Result<ProbeResult> {
+    let mut hdrl_position = None;
+    let mut movi_offset = None;
+    let mut idx1_position = None;
+    walk_top_level(reader, file_len, |id, list_type, offset, size| {
+        if list_type == Some(b"hdrl") && hdrl_position.is_none() {
+            hdrl_position = Some((offset, size));
+        } else if list_type == Some(b"movi") && movi_offset.is_none() {
+            // Body offset is right after the list type
+            movi_offset = Some(offset - 4);
+        } else if id == b"idx1" && idx1_position.is_none() {
+            idx1_position = Some((offset, size));
+        }
+    })?;
+
+    let (offset, size) = hdrl_position.ok_or_else(|| anyhow!("no hdrl list"))?;
+    let hdrl = read_at(reader, offset, size, MAX_HDRL_SIZE)?;
+
+    let mut info = MediaInfo::new("avi");
+    let video_stream = parse_hdrl(&hdrl, &mut info);
+
+    // OpenDML files have a different index, they're only described
+    let seek_index = match (video_stream, movi_offset, idx1_position) {
+        (Some(stream), Some(movi_offset), Some((offset, size))) => {
+            let idx1 = read_at(reader, offset, size.min(file_len - offset), MAX_IDX1_SIZE)?;
+            parse_idx1(&idx1, movi_offset, &stream)
+        }
+        _ => None,
+    };
+
+    Ok(ProbeResult { info, seek_index })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = id.to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            data.push(0);
+        }
+        data
+    }
+
+    fn list(list_type: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        chunk(b"LIST", &[&list_type[..], body].concat())
+    }
+
+    fn idx1_entry(id: &[u8; 4], offset: u32) -> Vec<u8> {
+        [id, &[0; 4][..], &offset.to_le_bytes(), &[0; 4]].concat()
+    }
+
+    fn video_stream() -> VideoStream {
+        VideoStream {
+            number: 0,
+            scale: 1,
+            rate: 25,
+        }
+    }
+
+    fn hdrl() -> Vec<u8> {
+        let mut avih = vec![0; 56];
+        avih[0..4].copy_from_slice(&40_000u32.to_le_bytes());
+        avih[16..20].copy_from_slice(&100u32.to_le_bytes());
+
+        let mut strh = vec![0; 56];
+        strh[0..4].copy_from_slice(b"vids");
+        strh[20..24].copy_from_slice(&1u32.to_le_bytes());
+        strh[24..28].copy_from_slice(&25u32.to_le_bytes());
+        strh[32..36].copy_from_slice(&250u32.to_le_bytes());
+
+        let mut strf = vec![0; 40];
+        strf[4..8].copy_from_slice(&640u32.to_le_bytes());
+        // Negative height means top-down rows
+        strf[8..12].copy_from_slice(&(-480i32).to_le_bytes());
+        strf[16..20].copy_from_slice(b"XVID");
+
+        let strl = list(
+            b"strl",
+            &[chunk(b"strh", &strh), chunk(b"strf", &strf)].concat(),
+        );
+        list(b"hdrl", &[chunk(b"avih", &avih), strl].concat())
+    }
+
+    #[test]
+    fn iterates_chunks() {
+        let data = [chunk(b"JUNK", b"odd"), list(b"strl", &chunk(b"strn", b"x"))].concat();
+        let chunks: Vec<Chunk> = Chunks::new(&data).collect();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].body, b"odd");
+        assert_eq!(chunks[1].list_type, Some(*b"strl"));
+        assert_eq!(chunks[1].body, chunk(b"strn", b"x").as_slice());
+    }
+
+    #[test]
+    fn relative_idx1_offsets() {
+        let idx1 = [
+            idx1_entry(b"00dc", 4),
+            idx1_entry(b"01wb", 54),
+            idx1_entry(b"00db", 104),
+            idx1_entry(b"00pc", 154),
+            idx1_entry(b"00dc", 204),
+        ]
+        .concat();
+        let index = parse_idx1(&idx1, 1000, &video_stream()).unwrap();
+
+        assert_eq!(index.time_at(1004, 2000, None), 0.0);
+        assert_eq!(index.time_at(1104, 2000, None), 0.04);
+        assert_eq!(index.time_at(1204, 2000, None), 0.08);
+        assert_eq!(index.time_at(1154, 2000, None), 0.06);
+    }
+
+    #[test]
+    fn absolute_idx1_offsets() {
+        let idx1 = [idx1_entry(b"00dc", 1004), idx1_entry(b"00dc", 1104)].concat();
+        let index = parse_idx1(&idx1, 1000, &video_stream()).unwrap();
+
+        assert_eq!(index.time_at(1104, 2000, None), 0.04);
+        assert_eq!(index.time_at(2104, 2000, None), 0.04);
+    }
+
+    #[test]
+    fn idx1_without_video_frames() {
+        let idx1 = [idx1_entry(b"01wb", 4), vec![0; 8]].concat();
+        assert!(parse_idx1(&idx1, 1000, &video_stream()).is_none());
+    }
+
+    #[test]
+    fn probes_file() {
+        let hdrl = hdrl();
+        let movi = list(
+            b"movi",
+            &[chunk(b"00dc", &[0; 10]), chunk(b"00dc", &[0; 10])].concat(),
+        );
+        // Offset of "movi" list type, RIFF header and LIST header before it
+        let movi_offset = (12 + hdrl.len() + 8) as u64;
+        let idx1 = chunk(
+            b"idx1",
+            &[idx1_entry(b"00dc", 4), idx1_entry(b"00dc", 22)].concat(),
+        );
+
+        let body = [b"AVI ".to_vec(), hdrl, movi, idx1].concat();
+        let data = [
+            b"RIFF".to_vec(),
+            (body.len() as u32).to_le_bytes().to_vec(),
+            body,
+        ]
+        .concat();
+        let result = probe(&mut Cursor::new(&data), data.len() as u64).unwrap();
+
+        assert_eq!(result.info.container, "avi");
+        assert_eq!(result.info.video_codec.as_deref(), Some("MPEG-4"));
+        assert_eq!(
+            (result.info.width, result.info.height),
+            (Some(640), Some(480))
+        );
+        assert_eq!(result.info.duration_secs, Some(10.0));
+
+        let index = result.seek_index.unwrap();
+        assert_eq!(
+            index.time_at(movi_offset + 22, data.len() as u64, None),
+            0.04
+        );
+    }
+
+    #[test]
+    fn truncated_file() {
+        let data = [
+            b"RIFF".to_vec(),
+            vec![0; 4],
+            b"AVI ".to_vec(),
+            b"LIST".to_vec(),
+        ]
+        .concat();
+        assert!(probe(&mut Cursor::new(&data), data.len() as u64).is_err());
+    }
+}
@@ -0,0 +1,852 @@
+use super::{
+    be_uint, friendly_codec_name, read_at, Chapter, MediaInfo, ProbeResult, SeekIndex, TrackInfo,
+};
+use anyhow::{anyhow, Result};
+use log::debug;
+use std::io::{Read, Seek, SeekFrom};
+
+/*
+Matroska/WebM: EBML elements are (id, size, body), ids and sizes being variable-length integers.
+Everything interesting lives in top-level children of the Segment, which usually come before the first Cluster.
+When they don't (e.g. Cues written at the end), SeekHead tells where they are.
+*/
+
+const ID_EBML: u32 = 0x1A45DFA3;
+const ID_DOC_TYPE: u32 = 0x4282;
+const ID_SEGMENT: u32 = 0x18538067;
+const ID_SEEK_HEAD: u32 = 0x114D9B74;
+const ID_SEEK: u32 = 0x4DBB;
+const ID_SEEK_ID: u32 = 0x53AB;
+const ID_SEEK_POSITION: u32 = 0x53AC;
+const ID_INFO: u32 = 0x1549A966;
+const ID_TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const ID_DURATION: u32 = 0x4489;
+const ID_TRACKS: u32 = 0x1654AE6B;
+const ID_TRACK_ENTRY: u32 = 0xAE;
+const ID_TRACK_TYPE: u32 = 0x83;
+const ID_CODEC_ID: u32 = 0x86;
+const ID_LANGUAGE: u32 = 0x22B59C;
+const ID_LANGUAGE_BCP47: u32 = 0x22B59D;
+const ID_NAME: u32 = 0x536E;
+const ID_VIDEO: u32 = 0xE0;
+const ID_PIXEL_WIDTH: u32 = 0xB0;
+const ID_PIXEL_HEIGHT: u32 = 0xBA;
+const ID_CHAPTERS: u32 = 0x1043A770;
+const ID_EDITION_ENTRY: u32 = 0x45B9;
+const ID_CHAPTER_ATOM: u32 = 0xB6;
+const ID_CHAPTER_TIME_START: u32 = 0x91;
+const ID_CHAPTER_DISPLAY: u32 = 0x80;
+const ID_CHAP_STRING: u32 = 0x85;
+const ID_CLUSTER: u32 = 0x1F43B675;
+const ID_CUES: u32 = 0x1C53BB6B;
+const ID_CUE_POINT: u32 = 0xBB;
+const ID_CUE_TIME: u32 = 0xB3;
+const ID_CUE_TRACK_POSITIONS: u32 = 0xB7;
+const ID_CUE_CLUSTER_POSITION: u32 = 0xF1;
+const ID_ATTACHMENTS: u32 = 0x1941A469;
+const ID_ATTACHED_FILE: u32 = 0x61A7;
+const ID_FILE_NAME: u32 = 0x466E;
+const ID_FILE_MIME_TYPE: u32 = 0x4660;
+const ID_FILE_DATA: u32 = 0x465C;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+const TRACK_TYPE_SUBTITLE: u64 = 17;
+
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+// Names the Matroska spec gives cover art, best first. Other images only if there are none of these
+const COVER_NAMES: &[&str] = &["cover", "cover_land", "small_cover", "small_cover_land"];
+const COVER_MIME_TYPES: &[&str] = &["image/jpeg", "image/jpg", "image/png"];
+
+// Element bodies larger than this are never loaded
+const MAX_ELEMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+struct Header {
+    id: u32,
+    // None for "unknown size" elements, which continue until the parent ends
+    size: Option<u64>,
+    len: u64,
+}
+
+fn vint_len(first: u8) -> Option<usize> {
+    if first == 0 {
+        None
+    } else {
+        Some(first.leading_zeros() as usize + 1)
+    }
+}
+
+fn parse_id(data: &[u8]) -> Option<(u32, usize)> {
+    let len = vint_len(*data.first()?)?;
+    if len > 4 {
+        return None;
+    }
+
+    Some((u32::try_from(be_uint(data.get(..len)?)?).ok()?, len))
+}
+
+fn parse_size(data: &[u8]) -> Option<(Option<u64>, usize)> {
+    let len = vint_len(*data.first()?)?;
+    let marker = 1u64 << (7 * len);
+    let value = be_uint(data.get(..len)?)? - marker;
+
+    if value == marker - 1 {
+        Some((None, len))
+    } else {
+        Some((Some(value), len))
+    }
+}
+
+fn parse_header(data: &[u8]) -> Option<Header> {
+    let (id, id_len) = parse_id(data)?;
+    let (size, size_len) = parse_size(data.get(id_len..)?)?;
+
+    Some(Header {
+        id,
+        size,
+        len: (id_len + size_len) as u64,
+    })
+}
+
+fn parse_float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f64::from(f32::from_be_bytes(data.try_into().ok()?))),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn parse_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+/// Iterates over child elements of an in-memory master element body
+pub(super) struct Children<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Children<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Children { data, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = (u32, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = parse_header(self.data.get(self.pos..)?)?;
+        let body_start = self.pos + header.len as usize;
+        let body_end = match header.size {
+            Some(size) => body_start
+                .saturating_add(usize::try_from(size).ok()?)
+                .min(self.data.len()),
+            None => self.data.len(),
+        };
+        self.pos = body_end;
+
+        Some((header.id, self.data.get(body_start..body_end)?))
+    }
+}
+
+pub(super) struct EbmlReader<'r, R: Read + Seek> {
+    pub(super) reader: &'r mut R,
+    pub(super) file_len: u64,
+}
+
+impl<'r, R: Read + Seek> EbmlReader<'r, R> {
+    fn header_at(&mut self, offset: u64) -> Result<Option<Header>> {
+        if offset >= self.file_len {
+            return Ok(None);
+        }
+
+        // 4 bytes of id and up to 8 bytes of size
+        let len = (self.file_len - offset).min(12);
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; len as usize];
+        self.reader.read_exact(&mut buf)?;
+
+        Ok(parse_header(&buf))
+    }
+
+    pub(super) fn body_at(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        read_at(self.reader, offset, size, MAX_ELEMENT_SIZE)
+    }
+}
+
+/// Position of Segment body, needed to resolve SeekHead and Cues offsets
+pub(super) struct SegmentBounds {
+    pub(super) start: u64,
+    pub(super) end: u64,
+    pub(super) doc_type: String,
+}
+
+pub(super) fn find_segment<R: Read + Seek>(reader: &mut EbmlReader<R>) -> Result<SegmentBounds> {
+    let ebml = reader
+        .header_at(0)?
+        .filter(|header| header.id == ID_EBML)
+        .ok_or_else(|| anyhow!("no EBML header"))?;
+    let ebml_size = ebml
+        .size
+        .ok_or_else(|| anyhow!("EBML header of unknown size"))?;
+
+    let ebml_body = reader.body_at(ebml.len, ebml_size)?;
+    let doc_type = Children::new(&ebml_body)
+        .find(|(id, _)| *id == ID_DOC_TYPE)
+        .map(|(_, data)| parse_string(data))
+        .unwrap_or_else(|| String::from("matroska"));
+
+    let mut offset = ebml.len + ebml_size;
+    loop {
+        let header = reader
+            .header_at(offset)?
+            .ok_or_else(|| anyhow!("no Segment element"))?;
+
+        if header.id == ID_SEGMENT {
+            let start = offset + header.len;
+            let end = match header.size {
+                Some(size) => (start + size).min(reader.file_len),
+                None => reader.file_len,
+            };
+
+            return Ok(SegmentBounds {
+                start,
+                end,
+                doc_type,
+            });
+        }
+
+        let size = header
+            .size
+            .ok_or_else(|| anyhow!("top-level element of unknown size"))?;
+        offset += header.len + size;
+    }
+}
+
+/// Finds the given top-level Segment children without reading them, following SeekHead if they're after Clusters.
+/// Returns (id, body offset, body size) of each
+fn locate_segment_children<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    segment: &SegmentBounds,
+    wanted: &[u32],
+) -> Result<Vec<(u32, u64, u64)>> {
+    let mut result: Vec<(u32, u64, u64)> = Vec::new();
+    let mut seek_positions: Vec<(u32, u64)> = Vec::new();
+
+    let mut offset = segment.start;
+    while offset < segment.end {
+        let header = match reader.header_at(offset)? {
+            Some(header) => header,
+            None => break,
+        };
+        if header.id == ID_CLUSTER {
+            break;
+        }
+        let size = match header.size {
+            Some(size) => size,
+            None => break,
+        };
+
+        let body_offset = offset + header.len;
+        if header.id == ID_SEEK_HEAD {
+            let body = reader.body_at(body_offset, size)?;
+            seek_positions.extend(parse_seek_head(&body));
+        } else if wanted.contains(&header.id) {
+            result.push((header.id, body_offset, size));
+        }
+
+        offset = body_offset + size;
+    }
+
+    // Elements written after the clusters, and possibly another SeekHead
+    let mut idx = 0;
+    while idx < seek_positions.len() {
+        let (id, position) = seek_positions[idx];
+        idx += 1;
+
+        let already_found = result.iter().any(|(found_id, _, _)| *found_id == id);
+        if already_found || !(wanted.contains(&id) || id == ID_SEEK_HEAD) {
+            continue;
+        }
+
+        let offset = segment
+            .start
+            .checked_add(position)
+            .ok_or_else(|| anyhow!("broken seek position {}", position))?;
+        let header = match reader.header_at(offset)? {
+            Some(header) if header.id == id => header,
+            _ => continue,
+        };
+        let size = match header.size {
+            Some(size) => size,
+            None => continue,
+        };
+
+        if id == ID_SEEK_HEAD {
+            // Guarding against SeekHeads pointing at each other
+            if seek_positions.len() < 1024 {
+                let body = reader.body_at(offset + header.len, size)?;
+                seek_positions.extend(parse_seek_head(&body));
+            }
+        } else {
+            result.push((id, offset + header.len, size));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Reads bodies of the given top-level Segment children
+pub(super) fn read_segment_children<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    segment: &SegmentBounds,
+    wanted: &[u32],
+) -> Result<Vec<(u32, Vec<u8>)>> {
+    locate_segment_children(reader, segment, wanted)?
+        .into_iter()
+        .map(|(id, offset, size)| Ok((id, reader.body_at(offset, size)?)))
+        .collect()
+}
+
+fn parse_seek_head(data: &[u8]) -> Vec<(u32, u64)> {
+    Children::new(data)
+        .filter(|(id, _)| *id == ID_SEEK)
+        .filter_map(|(_, seek)| {
+            let mut seek_id = None;
+            let mut position = None;
+
+            for (id, data) in Children::new(seek) {
+                match id {
+                    ID_SEEK_ID => seek_id = be_uint(data).and_then(|id| u32::try_from(id).ok()),
+                    ID_SEEK_POSITION => position = be_uint(data),
+                    _ => {}
+                }
+            }
+
+            Some((seek_id?, position?))
+        })
+        .collect()
+}
+
+/// Returns (timestamp scale, duration in seconds)
+fn parse_info(data: &[u8]) -> (u64, Option<f64>) {
+    let mut timestamp_scale = DEFAULT_TIMESTAMP_SCALE;
+    let mut duration = None;
+
+    for (id, data) in Children::new(data) {
+        match id {
+            ID_TIMESTAMP_SCALE => {
+                timestamp_scale = be_uint(data).unwrap_or(DEFAULT_TIMESTAMP_SCALE)
+            }
+            ID_DURATION => duration = parse_float(data),
+            _ => {}
+        }
+    }
+
+    (
+        timestamp_scale,
+        duration.map(|duration| duration * timestamp_scale as f64 / 1e9),
+    )
+}
+
+fn parse_tracks(data: &[u8], info: &mut MediaInfo) {
+    for (_, entry) in Children::new(data).filter(|(id, _)| *id == ID_TRACK_ENTRY) {
+        let mut track_type = 0;
+        let mut codec = None;
+        // Matroska default, when the element is missing
+        let mut language = Some(String::from("eng"));
+        let mut language_bcp47 = None;
+        let mut name = None;
+        let mut width = None;
+        let mut height = None;
+
+        for (id, data) in Children::new(entry) {
+            match id {
+                ID_TRACK_TYPE => track_type = be_uint(data).unwrap_or(0),
+                ID_CODEC_ID => codec = Some(friendly_codec_name(&parse_string(data))),
+                ID_LANGUAGE => language = Some(parse_string(data)),
+                ID_LANGUAGE_BCP47 => language_bcp47 = Some(parse_string(data)),
+                ID_NAME => name = Some(parse_string(data)),
+                ID_VIDEO => {
+                    for (id, data) in Children::new(data) {
+                        match id {
+                            ID_PIXEL_WIDTH => {
+                                width = be_uint(data).and_then(|el| u32::try_from(el).ok())
+                            }
+                            ID_PIXEL_HEIGHT => {
+                                height = be_uint(data).and_then(|el| u32::try_from(el).ok())
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let track = TrackInfo {
+            codec,
+            language: language_bcp47.or(language).filter(|lang| lang != "und"),
+            name,
+        };
+
+        match track_type {
+            // Only the first video track is described, others are usually covers or previews
+            TRACK_TYPE_VIDEO if info.video_codec.is_none() => {
+                info.video_codec = track.codec;
+                info.width = width;
+                info.height = height;
+            }
+            TRACK_TYPE_AUDIO => info.audio_tracks.push(track),
+            TRACK_TYPE_SUBTITLE => info.subtitle_tracks.push(track),
+            _ => {}
+        }
+    }
+}
+
+fn parse_chapters(data: &[u8]) -> Vec<Chapter> {
+    // Only the first edition; others are alternative cuts
+    let edition = match Children::new(data).find(|(id, _)| *id == ID_EDITION_ENTRY) {
+        Some((_, edition)) => edition,
+        None => return Vec::new(),
+    };
+
+    Children::new(edition)
+        .filter(|(id, _)| *id == ID_CHAPTER_ATOM)
+        .map(|(_, atom)| {
+            let mut start_secs = 0.0;
+            let mut title = None;
+
+            for (id, data) in Children::new(atom) {
+                match id {
+                    ID_CHAPTER_TIME_START => {
+                        start_secs = be_uint(data).map_or(0.0, |time| time as f64 / 1e9)
+                    }
+                    ID_CHAPTER_DISPLAY if title.is_none() => {
+                        title = Children::new(data)
+                            .find(|(id, _)| *id == ID_CHAP_STRING)
+                            .map(|(_, data)| parse_string(data));
+                    }
+                    _ => {}
+                }
+            }
+
+            Chapter { start_secs, title }
+        })
+        .collect()
+}
+
+/// Cue points of all tracks; clusters are shared between tracks, so positions agree anyway
+fn parse_cues(data: &[u8], segment: &SegmentBounds, timestamp_scale: u64) -> Option<SeekIndex> {
+    let mut points = Vec::new();
+
+    for (_, cue_point) in Children::new(data).filter(|(id, _)| *id == ID_CUE_POINT) {
+        let mut time = None;
+        let mut positions = Vec::new();
+
+        for (id, data) in Children::new(cue_point) {
+            match id {
+                ID_CUE_TIME => time = be_uint(data),
+                ID_CUE_TRACK_POSITIONS => positions.extend(
+                    Children::new(data)
+                        .filter(|(id, _)| *id == ID_CUE_CLUSTER_POSITION)
+                        .filter_map(|(_, data)| be_uint(data)),
+                ),
+                _ => {}
+            }
+        }
+
+        if let Some(time) = time {
+            let secs = time as f64 * timestamp_scale as f64 / 1e9;
+            points.extend(
+                positions
+                    .into_iter()
+                    .map(|position| (segment.start.saturating_add(position), secs)),
+            );
+        }
+    }
+
+    SeekIndex::from_points(points)
+}
+
+/// Attached file, its data is left where it is
+#[derive(Debug)]
+struct Attachment {
+    name: String,
+    mime_type: String,
+    data_offset: u64,
+    data_size: u64,
+}
+
+/// Calls `f` with (id, body offset, body size) of each child of the element at `offset`
+fn for_each_child<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    offset: u64,
+    size: u64,
+    mut f: impl FnMut(&mut EbmlReader<R>, u32, u64, u64) -> Result<()>,
+) -> Result<()> {
+    let end = (offset + size).min(reader.file_len);
+    let mut child_offset = offset;
+    while child_offset < end {
+        let header = match reader.header_at(child_offset)? {
+            Some(header) => header,
+            None => break,
+        };
+        let size = match header.size {
+            Some(size) => size,
+            None => break,
+        };
+
+        let body_offset = child_offset + header.len;
+        f(reader, header.id, body_offset, size)?;
+        child_offset = body_offset + size;
+    }
+
+    Ok(())
+}
+
+/// Attachments of the Segment. Fonts of anime subtitles can take megabytes, so only headers are read
+fn list_attachments<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    segment: &SegmentBounds,
+) -> Result<Vec<Attachment>> {
+    let mut result = Vec::new();
+    for (_, offset, size) in locate_segment_children(reader, segment, &[ID_ATTACHMENTS])? {
+        for_each_child(reader, offset, size, |reader, id, offset, size| {
+            if id != ID_ATTACHED_FILE {
+                return Ok(());
+            }
+
+            let mut name = None;
+            let mut mime_type = None;
+            let mut data = None;
+            for_each_child(reader, offset, size, |reader, id, offset, size| {
+                match id {
+                    ID_FILE_NAME => name = Some(parse_string(&reader.body_at(offset, size)?)),
+                    ID_FILE_MIME_TYPE => {
+                        mime_type = Some(parse_string(&reader.body_at(offset, size)?))
+                    }
+                    ID_FILE_DATA => data = Some((offset, size)),
+                    _ => {}
+                }
+                Ok(())
+            })?;
+
+            if let (Some(name), Some(mime_type), Some((data_offset, data_size))) =
+                (name, mime_type, data)
+            {
+                result.push(Attachment {
+                    name,
+                    mime_type,
+                    data_offset,
+                    data_size,
+                });
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(result)
+}
+
+/// How good the attachment is as cover art, lower is better. `None` for what isn't an image
+fn cover_rank(attachment: &Attachment) -> Option<usize> {
+    let mime_type = attachment.mime_type.to_lowercase();
+    if !COVER_MIME_TYPES.contains(&mime_type.as_str()) {
+        return None;
+    }
+
+    let name = attachment.name.to_lowercase();
+    let stem = name
+        .rsplit_once('.')
+        .map_or(name.as_str(), |(stem, _)| stem);
+    Some(
+        COVER_NAMES
+            .iter()
+            .position(|el| *el == stem)
+            .unwrap_or(COVER_NAMES.len()),
+    )
+}
+
+fn find_cover<R: Read + Seek>(
+    reader: &mut EbmlReader<R>,
+    segment: &SegmentBounds,
+) -> Result<Option<Attachment>> {
+    Ok(list_attachments(reader, segment)?
+        .into_iter()
+        .filter_map(|attachment| Some((cover_rank(&attachment)?, attachment)))
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, attachment)| attachment))
+}
+
+/// Data of the attached cover image, JPEG or PNG
+pub fn read_cover<R: Read + Seek>(reader: &mut R, file_len: u64) -> Result<Option<Vec<u8>>> {
+    let mut reader = EbmlReader { reader, file_len };
+    let segment = find_segment(&mut reader)?;
+
+    match find_cover(&mut reader, &segment)? {
+        Some(cover) => Ok(Some(reader.body_at(cover.data_offset, cover.data_size)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn probe<R: Read + Seek>(reader: &mut R, file_len: u64) -> Result<ProbeResult> {
+    let mut reader = EbmlReader { reader, file_len };
+    let segment = find_segment(&mut reader)?;
+
+    let mut info = MediaInfo::new(&segment.doc_type);
+    let children = read_segment_children(
+        &mut reader,
+        &segment,
+        &[ID_INFO, ID_TRACKS, ID_CHAPTERS, ID_CUES],
+    )?;
+
+    let mut timestamp_scale = DEFAULT_TIMESTAMP_SCALE;
+    for (id, body) in children.iter() {
+        match *id {
+            ID_INFO => (timestamp_scale, info.duration_secs) = parse_info(body),
+            ID_TRACKS => parse_tracks(body, &mut info),
+            ID_CHAPTERS => info.chapters = parse_chapters(body),
+            _ => {}
+        }
+    }
+
+    // Cues need the timestamp scale, and Info isn't necessarily before them
+    let seek_index = children
+        .iter()
+        .find(|(id, _)| *id == ID_CUES)
+        .and_then(|(_, body)| parse_cues(body, &segment, timestamp_scale));
+
+    // Broken attachments shouldn't cost the rest of the metadata
+    info.has_cover = match find_cover(&mut reader, &segment) {
+        Ok(cover) => cover.is_some(),
+        Err(err) => {
+            debug!("Failed to look for cover art: {:#}", err);
+            false
+        }
+    };
+
+    Ok(ProbeResult { info, seek_index })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn element(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut data: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        // 8-byte size, so lengths of elements don't depend on their contents
+        data.push(0x01);
+        data.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn seek(id: u32, position: u64) -> Vec<u8> {
+        let body = [
+            element(ID_SEEK_ID, &id.to_be_bytes()),
+            element(ID_SEEK_POSITION, &position.to_be_bytes()),
+        ]
+        .concat();
+        element(ID_SEEK, &body)
+    }
+
+    fn info() -> Vec<u8> {
+        let body = [
+            element(ID_TIMESTAMP_SCALE, &[0x0F, 0x42, 0x40]),
+            element(ID_DURATION, &90_000f64.to_be_bytes()),
+        ]
+        .concat();
+        element(ID_INFO, &body)
+    }
+
+    fn cue_point(time_ms: u64, position: u64) -> Vec<u8> {
+        let positions = element(
+            ID_CUE_TRACK_POSITIONS,
+            &element(ID_CUE_CLUSTER_POSITION, &position.to_be_bytes()),
+        );
+        let body = [element(ID_CUE_TIME, &time_ms.to_be_bytes()), positions].concat();
+        element(ID_CUE_POINT, &body)
+    }
+
+    /// EBML header and Segment with the given children. Returns the file and offset of the Segment body
+    fn file(children: &[Vec<u8>]) -> (Vec<u8>, u64) {
+        let body = children.concat();
+        let mut data = element(ID_EBML, &element(ID_DOC_TYPE, b"webm"));
+        data.extend(element(ID_SEGMENT, &body));
+        let segment_start = (data.len() - body.len()) as u64;
+
+        (data, segment_start)
+    }
+
+    fn probe_data(data: &[u8]) -> Result<ProbeResult> {
+        probe(&mut Cursor::new(data), data.len() as u64)
+    }
+
+    #[test]
+    fn uint_decoding() {
+        assert_eq!(be_uint(&[]), Some(0));
+        assert_eq!(be_uint(&[0x01, 0x00]), Some(256));
+        assert_eq!(be_uint(&[0xFF; 8]), Some(u64::MAX));
+        assert_eq!(be_uint(&[0x00; 9]), None);
+
+        // Overlong values are skipped rather than folded into something else
+        let video = [
+            element(ID_PIXEL_WIDTH, &[0; 9]),
+            element(ID_PIXEL_HEIGHT, &[0x04, 0x38]),
+        ]
+        .concat();
+        let track = [
+            element(ID_TRACK_TYPE, &[TRACK_TYPE_VIDEO as u8]),
+            element(ID_VIDEO, &video),
+        ]
+        .concat();
+        let mut info = MediaInfo::default();
+        parse_tracks(&element(ID_TRACK_ENTRY, &track), &mut info);
+        assert_eq!((info.width, info.height), (None, Some(1080)));
+    }
+
+    #[test]
+    fn vint_decoding() {
+        assert_eq!(parse_id(&[0x1A, 0x45, 0xDF, 0xA3]), Some((ID_EBML, 4)));
+        assert_eq!(parse_id(&[0xAE, 0x00]), Some((ID_TRACK_ENTRY, 1)));
+        // Ids are at most 4 bytes long
+        assert_eq!(parse_id(&[0x08, 0, 0, 0, 0]), None);
+        assert_eq!(parse_id(&[0x00]), None);
+        assert_eq!(parse_id(&[0x1A, 0x45]), None);
+
+        assert_eq!(parse_size(&[0x81]), Some((Some(1), 1)));
+        assert_eq!(parse_size(&[0x40, 0x02]), Some((Some(2), 2)));
+        assert_eq!(
+            parse_size(&[0x01, 0, 0, 0, 0, 0, 0x01, 0x00]),
+            Some((Some(256), 8))
+        );
+        assert_eq!(parse_size(&[0xFF]), Some((None, 1)));
+        assert_eq!(
+            parse_size(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+            Some((None, 8))
+        );
+        assert_eq!(parse_size(&[0x40]), None);
+        assert_eq!(parse_size(&[]), None);
+
+        let header = parse_header(&[0x1F, 0x43, 0xB6, 0x75, 0x84]).unwrap();
+        assert_eq!(header.id, ID_CLUSTER);
+        assert_eq!(header.size, Some(4));
+        assert_eq!(header.len, 5);
+    }
+
+    #[test]
+    fn children_are_cut_at_parent_end() {
+        let data = [element(ID_TRACK_TYPE, &[1]), vec![0x86, 0x88, b'V']].concat();
+        let children: Vec<(u32, &[u8])> = Children::new(&data).collect();
+
+        assert_eq!(
+            children,
+            vec![(ID_TRACK_TYPE, &[1][..]), (ID_CODEC_ID, &b"V"[..])]
+        );
+    }
+
+    #[test]
+    fn probes_info() {
+        let (data, _) = file(&[info()]);
+        let result = probe_data(&data).unwrap();
+
+        assert_eq!(result.info.container, "webm");
+        assert_eq!(result.info.duration_secs, Some(90.0));
+        assert!(result.seek_index.is_none());
+    }
+
+    #[test]
+    fn follows_seek_head_past_clusters() {
+        let cluster = element(ID_CLUSTER, &[0; 32]);
+        let cues = element(
+            ID_CUES,
+            &[cue_point(0, 0), cue_point(30_000, 1000)].concat(),
+        );
+        let seek_head_len = element(ID_SEEK_HEAD, &seek(ID_CUES, 0)).len();
+        let cues_position = (seek_head_len + info().len() + cluster.len()) as u64;
+        let seek_head = element(ID_SEEK_HEAD, &seek(ID_CUES, cues_position));
+
+        let (data, segment_start) = file(&[seek_head, info(), cluster, cues]);
+        let result = probe_data(&data).unwrap();
+
+        let index = result.seek_index.unwrap();
+        assert_eq!(
+            index.time_at(segment_start + 1000, data.len() as u64, None),
+            30.0
+        );
+        assert_eq!(
+            index.time_at(segment_start + 500, data.len() as u64, None),
+            15.0
+        );
+    }
+
+    #[test]
+    fn seek_head_past_eof_is_ignored() {
+        let seek_head = element(ID_SEEK_HEAD, &seek(ID_CUES, 1 << 40));
+        let (data, _) = file(&[seek_head, info()]);
+        let result = probe_data(&data).unwrap();
+
+        assert_eq!(result.info.duration_secs, Some(90.0));
+        assert!(result.seek_index.is_none());
+    }
+
+    #[test]
+    fn finds_cover() {
+        let attached_file = |name: &[u8], mime_type: &[u8]| {
+            let body = [
+                element(ID_FILE_NAME, name),
+                element(ID_FILE_MIME_TYPE, mime_type),
+                element(ID_FILE_DATA, b"image"),
+            ]
+            .concat();
+            element(ID_ATTACHED_FILE, &body)
+        };
+        let attachments = element(
+            ID_ATTACHMENTS,
+            &[
+                attached_file(b"font.ttf", b"font/ttf"),
+                attached_file(b"cover.jpg", b"image/jpeg"),
+            ]
+            .concat(),
+        );
+        let (data, _) = file(&[info(), attachments]);
+
+        assert!(probe_data(&data).unwrap().info.has_cover);
+        assert_eq!(
+            read_cover(&mut Cursor::new(&data), data.len() as u64).unwrap(),
+            Some(b"image".to_vec())
+        );
+    }
+
+    #[test]
+    fn truncated_attachments_mean_no_cover() {
+        // FileName claims more than there is
+        let mut name = element(ID_FILE_NAME, b"cover.jpg");
+        name.truncate(name.len() - 4);
+        let attachments = element(ID_ATTACHMENTS, &element(ID_ATTACHED_FILE, &name));
+        let (data, _) = file(&[info(), attachments]);
+
+        let result = probe_data(&data).unwrap();
+        assert_eq!(result.info.duration_secs, Some(90.0));
+        assert!(!result.info.has_cover);
+    }
+
+    #[test]
+    fn overflowing_seek_position_is_an_error() {
+        let seek_head = element(ID_SEEK_HEAD, &seek(ID_CUES, u64::MAX));
+        let (data, _) = file(&[seek_head, info()]);
+
+        assert!(probe_data(&data).is_err());
+    }
+}
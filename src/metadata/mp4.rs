@@ -0,0 +1,507 @@
+use super::{
+    be_u16, be_u32, be_u64, fourcc, friendly_codec_name, read_at, Chapter, MediaInfo, ProbeResult,
+    SeekIndex, TrackInfo,
+};
+use anyhow::{anyhow, Result};
+use std::io::{Read, Seek};
+
+/*
+MP4/QuickTime: atoms are (32-bit size, fourcc, body), size 1 meaning 64-bit size follows and 0 meaning "until EOF".
+All the metadata is in `moov`, which can be either at the start or at the end of the file.
+*/
+
+// moov of a long movie with big sample tables can be tens of megabytes
+const MAX_MOOV_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Iterates over atoms of an in-memory container atom body
+pub(super) struct Atoms<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Atoms<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Atoms { data, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for Atoms<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let size = be_u32(self.data, self.pos)? as usize;
+        let kind: [u8; 4] = self.data.get(self.pos + 4..self.pos + 8)?.try_into().ok()?;
+
+        let (header_len, size) = match size {
+            0 => (8, self.data.len() - self.pos),
+            1 => (16, usize::try_from(be_u64(self.data, self.pos + 8)?).ok()?),
+            size => (8, size),
+        };
+        if size < header_len {
+            return None;
+        }
+
+        let body_start = self.pos + header_len;
+        let body_end = self.pos.saturating_add(size).min(self.data.len());
+        self.pos = body_end;
+
+        Some((kind, self.data.get(body_start..body_end)?))
+    }
+}
+
+pub(super) fn find_atom<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (first, rest) = path.split_first()?;
+    let (_, body) = Atoms::new(data).find(|(kind, _)| kind == *first)?;
+
+    if rest.is_empty() {
+        Some(body)
+    } else {
+        find_atom(body, rest)
+    }
+}
+
+/// Reads `moov` body, wherever it is in the file
+pub(super) fn read_moov<R: Read + Seek>(reader: &mut R, file_len: u64) -> Result<Vec<u8>> {
+    let mut offset = 0;
+
+    while file_len.saturating_sub(offset) >= 8 {
+        let header = read_at(reader, offset, (file_len - offset).min(16), 16)?;
+        let kind = &header[4..8];
+        let (header_len, size) = match be_u32(&header, 0) {
+            Some(0) => (8, file_len - offset),
+            Some(1) => (
+                16,
+                be_u64(&header, 8).ok_or_else(|| anyhow!("truncated atom header"))?,
+            ),
+            Some(size) => (8, u64::from(size)),
+            None => break,
+        };
+        if size < header_len {
+            return Err(anyhow!("broken atom at {}", offset));
+        }
+        let next_offset = offset
+            .checked_add(size)
+            .ok_or_else(|| anyhow!("broken atom position at {}", offset))?;
+
+        if kind == b"moov" {
+            return read_at(
+                reader,
+                offset + header_len,
+                size - header_len,
+                MAX_MOOV_SIZE,
+            );
+        }
+
+        offset = next_offset;
+    }
+
+    Err(anyhow!("no moov atom"))
+}
+
+/// Returns (version, body after version and flags) of a "full box"
+fn full_box(data: &[u8]) -> Option<(u8, &[u8])> {
+    Some((*data.first()?, data.get(4..)?))
+}
+
+/// Returns (timescale, duration) of mvhd or mdhd, which share the layout of these fields
+fn timescale_and_duration(data: &[u8]) -> Option<(u32, u64)> {
+    let (version, body) = full_box(data)?;
+
+    if version == 1 {
+        Some((be_u32(body, 16)?, be_u64(body, 20)?))
+    } else {
+        Some((be_u32(body, 8)?, u64::from(be_u32(body, 12)?)))
+    }
+}
+
+fn mdhd_language(data: &[u8]) -> Option<String> {
+    let (version, body) = full_box(data)?;
+    let packed = if version == 1 {
+        be_u16(body, 28)?
+    } else {
+        be_u16(body, 16)?
+    };
+
+    // ISO-639-2/T code, three 5-bit letters offset by 0x60
+    let language: String = [10, 5, 0]
+        .iter()
+        .map(|shift| char::from((((packed >> shift) & 0x1f) as u8) + 0x60))
+        .collect();
+
+    if packed == 0 || language == "und" || !language.chars().all(|c| c.is_ascii_lowercase()) {
+        None
+    } else {
+        Some(language)
+    }
+}
+
+fn tkhd_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let (version, body) = full_box(data)?;
+    let offset = if version == 1 { 84 } else { 72 };
+
+    // 16.16 fixed point
+    Some((be_u32(body, offset)? >> 16, be_u32(body, offset + 4)? >> 16))
+}
+
+/// Format of the first sample description, along with the dimensions of visual samples
+fn stsd_format(data: &[u8]) -> Option<(String, Option<(u32, u32)>)> {
+    let (_, body) = full_box(data)?;
+    // Skipping entry count
+    let entry = body.get(4..)?;
+    let format = fourcc(entry, 4)?;
+
+    // Visual sample entry: 6 reserved, data reference index, 16 bytes of predefined stuff, then width and height
+    let dimensions = match (be_u16(entry, 32), be_u16(entry, 34)) {
+        (Some(width), Some(height)) => Some((u32::from(width), u32::from(height))),
+        _ => None,
+    };
+
+    Some((format, dimensions))
+}
+
+fn trak_handler(trak: &[u8]) -> Option<&[u8]> {
+    find_atom(trak, &[b"mdia", b"hdlr"])
+        .and_then(|hdlr| full_box(hdlr))
+        .and_then(|(_, body)| body.get(4..8))
+}
+
+/// Entries of stts, stsc, stco and co64, which are all "count, then fixed-size records"
+fn table_entries(data: &[u8], entry_len: usize) -> Option<impl Iterator<Item = &[u8]>> {
+    let (_, body) = full_box(data)?;
+    let count = be_u32(body, 0)? as usize;
+    let entries = body.get(4..)?;
+
+    Some(entries.chunks_exact(entry_len).take(count))
+}
+
+/// Maps offsets of the track's chunks to times of their first samples
+fn trak_seek_index(trak: &[u8]) -> Option<SeekIndex> {
+    let timescale = find_atom(trak, &[b"mdia", b"mdhd"])
+        .and_then(timescale_and_duration)
+        .map(|(timescale, _)| timescale)
+        .filter(|timescale| *timescale > 0)?;
+    let stbl = find_atom(trak, &[b"mdia", b"minf", b"stbl"])?;
+
+    let chunk_offsets: Vec<u64> = match find_atom(stbl, &[b"stco"]) {
+        Some(stco) => table_entries(stco, 4)?
+            .filter_map(|entry| be_u32(entry, 0).map(u64::from))
+            .collect(),
+        None => table_entries(find_atom(stbl, &[b"co64"])?, 8)?
+            .filter_map(|entry| be_u64(entry, 0))
+            .collect(),
+    };
+    // (first chunk, samples per chunk), chunks are numbered from 1
+    let sample_to_chunk: Vec<(u32, u32)> = table_entries(find_atom(stbl, &[b"stsc"])?, 12)?
+        .filter_map(|entry| Some((be_u32(entry, 0)?, be_u32(entry, 4)?)))
+        .collect();
+    // (sample count, sample duration)
+    let mut time_to_sample = table_entries(find_atom(stbl, &[b"stts"])?, 8)?
+        .filter_map(|entry| Some((be_u32(entry, 0)?, be_u32(entry, 4)?)));
+
+    let mut points = Vec::with_capacity(chunk_offsets.len());
+    let mut time: u64 = 0;
+    let mut stsc_idx = 0;
+    // Samples left in the current stts entry and their duration
+    let mut current_run = (0, 0);
+
+    for (idx, offset) in chunk_offsets.into_iter().enumerate() {
+        let chunk_number = idx as u32 + 1;
+        while matches!(sample_to_chunk.get(stsc_idx + 1), Some((first_chunk, _)) if *first_chunk <= chunk_number)
+        {
+            stsc_idx += 1;
+        }
+        let samples_in_chunk = match sample_to_chunk.get(stsc_idx) {
+            Some((first_chunk, samples)) if *first_chunk <= chunk_number => *samples,
+            _ => 0,
+        };
+
+        points.push((offset, time as f64 / f64::from(timescale)));
+
+        let mut samples_left = samples_in_chunk;
+        while samples_left > 0 {
+            if current_run.0 == 0 {
+                current_run = match time_to_sample.next() {
+                    Some(run) => run,
+                    None => break,
+                };
+                continue;
+            }
+
+            let taken = samples_left.min(current_run.0);
+            time += u64::from(taken) * u64::from(current_run.1);
+            samples_left -= taken;
+            current_run.0 -= taken;
+        }
+    }
+
+    SeekIndex::from_points(points)
+}
+
+fn parse_trak(trak: &[u8], info: &mut MediaInfo) {
+    let handler = trak_handler(trak);
+    let stsd = find_atom(trak, &[b"mdia", b"minf", b"stbl", b"stsd"]).and_then(stsd_format);
+    let language = find_atom(trak, &[b"mdia", b"mdhd"]).and_then(mdhd_language);
+
+    let (codec, stsd_dimensions) = match stsd {
+        Some((format, dimensions)) => (Some(friendly_codec_name(&format)), dimensions),
+        None => (None, None),
+    };
+    let track = TrackInfo {
+        codec,
+        language,
+        name: None,
+    };
+
+    match handler {
+        Some(b"vide") if info.video_codec.is_none() => {
+            let dimensions = find_atom(trak, &[b"tkhd"])
+                .and_then(tkhd_dimensions)
+                .filter(|(width, height)| *width > 0 && *height > 0)
+                .or(stsd_dimensions);
+
+            info.video_codec = track.codec;
+            if let Some((width, height)) = dimensions {
+                info.width = Some(width);
+                info.height = Some(height);
+            }
+        }
+        Some(b"soun") => info.audio_tracks.push(track),
+        Some(b"sbtl") | Some(b"subt") | Some(b"text") => info.subtitle_tracks.push(track),
+        _ => {}
+    }
+}
+
+/// Nero-style chapter list, the one most tools write
+fn parse_chpl(data: &[u8]) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    let (version, body) = match full_box(data) {
+        Some(res) => res,
+        None => return chapters,
+    };
+
+    let mut pos = if version == 1 { 4 } else { 0 };
+    let count = match body.get(pos) {
+        Some(count) => *count,
+        None => return chapters,
+    };
+    pos += 1;
+
+    for _ in 0..count {
+        let start = match be_u64(body, pos) {
+            Some(start) => start,
+            None => break,
+        };
+        let title_len = match body.get(pos + 8) {
+            Some(len) => *len as usize,
+            None => break,
+        };
+        let title = match body.get(pos + 9..pos + 9 + title_len) {
+            Some(title) => String::from_utf8_lossy(title).to_string(),
+            None => break,
+        };
+        pos += 9 + title_len;
+
+        chapters.push(Chapter {
+            // 100ns units
+            start_secs: start as f64 / 1e7,
+            title: Some(title),
+        });
+    }
+
+    chapters
+}
+
+pub fn probe<R: Read + Seek>(reader: &mut R, file_len: u64) -> Result<ProbeResult> {
+    let moov = read_moov(reader, file_len)?;
+    let mut info = MediaInfo::new("mp4");
+    let mut seek_index = None;
+
+    for (kind, body) in Atoms::new(&moov) {
+        match &kind {
+            b"mvhd" => {
+                info.duration_secs = timescale_and_duration(body)
+                    .filter(|(timescale, _)| *timescale > 0)
+                    .map(|(timescale, duration)| duration as f64 / f64::from(timescale));
+            }
+            b"trak" => {
+                // Video track decides, audio one is only used for audio files
+                let handler = trak_handler(body);
+                if (matches!(handler, Some(b"vide")) && info.video_codec.is_none())
+                    || (matches!(handler, Some(b"soun")) && seek_index.is_none())
+                {
+                    seek_index = trak_seek_index(body).or(seek_index);
+                }
+                parse_trak(body, &mut info);
+            }
+            b"udta" => {
+                if let Some(chpl) = find_atom(body, &[b"chpl"]) {
+                    info.chapters = parse_chpl(chpl);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(ProbeResult { info, seek_index })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn large_atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(&((body.len() + 16) as u64).to_be_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn full_atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        atom(kind, &[&[0, 0, 0, 0], body].concat())
+    }
+
+    fn read_moov_of(data: &[u8]) -> Result<Vec<u8>> {
+        read_moov(&mut Cursor::new(data), data.len() as u64)
+    }
+
+    /// Visual sample entry of the given format, optionally cut after `len` bytes
+    fn stsd(format: &[u8; 4], len: usize) -> Vec<u8> {
+        let mut entry = vec![0; 36];
+        entry[4..8].copy_from_slice(format);
+        entry[32..34].copy_from_slice(&1280u16.to_be_bytes());
+        entry[34..36].copy_from_slice(&720u16.to_be_bytes());
+        entry.truncate(len);
+
+        full_atom(b"stsd", &[&1u32.to_be_bytes(), entry.as_slice()].concat())
+    }
+
+    /// Body of a video trak
+    fn video_trak(tkhd_body: &[u8], stsd: Vec<u8>) -> Vec<u8> {
+        let hdlr = full_atom(b"hdlr", &[&[0; 4][..], b"vide"].concat());
+        let minf = atom(b"minf", &atom(b"stbl", &stsd));
+        let mdia = atom(b"mdia", &[hdlr, minf].concat());
+        [full_atom(b"tkhd", tkhd_body), mdia].concat()
+    }
+
+    #[test]
+    fn iterates_atoms() {
+        let data = [
+            atom(b"free", b"abc"),
+            large_atom(b"mdat", b"data"),
+            // Size 0 runs until the end
+            vec![0, 0, 0, 0],
+            b"skip".to_vec(),
+            b"rest".to_vec(),
+        ]
+        .concat();
+        let atoms: Vec<([u8; 4], &[u8])> = Atoms::new(&data).collect();
+
+        assert_eq!(
+            atoms,
+            vec![
+                (*b"free", &b"abc"[..]),
+                (*b"mdat", &b"data"[..]),
+                (*b"skip", &b"rest"[..])
+            ]
+        );
+    }
+
+    #[test]
+    fn atom_smaller_than_header_stops_iteration() {
+        let data = [vec![0, 0, 0, 4], b"free".to_vec(), atom(b"moov", b"")].concat();
+        assert_eq!(Atoms::new(&data).count(), 0);
+    }
+
+    #[test]
+    fn finds_moov_after_large_atom() {
+        let data = [
+            atom(b"ftyp", b"isom"),
+            large_atom(b"mdat", &[0; 100]),
+            atom(b"moov", b"body"),
+        ]
+        .concat();
+
+        assert_eq!(read_moov_of(&data).unwrap(), b"body");
+    }
+
+    #[test]
+    fn finds_moov_running_until_eof() {
+        let data = [
+            atom(b"ftyp", b"isom"),
+            vec![0, 0, 0, 0],
+            b"moov".to_vec(),
+            b"body".to_vec(),
+        ]
+        .concat();
+
+        assert_eq!(read_moov_of(&data).unwrap(), b"body");
+    }
+
+    #[test]
+    fn broken_atom_sizes_are_errors() {
+        let too_small = [atom(b"ftyp", b"isom"), vec![0, 0, 0, 4], b"free".to_vec()].concat();
+        assert!(read_moov_of(&too_small).is_err());
+
+        let mut overflowing = atom(b"ftyp", b"isom");
+        overflowing.extend_from_slice(&1u32.to_be_bytes());
+        overflowing.extend_from_slice(b"mdat");
+        overflowing.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(read_moov_of(&overflowing).is_err());
+
+        let truncated_header =
+            [atom(b"ftyp", b"isom"), vec![0, 0, 0, 1], b"mdat".to_vec()].concat();
+        assert!(read_moov_of(&truncated_header).is_err());
+
+        assert!(read_moov_of(&atom(b"ftyp", b"isom")).is_err());
+    }
+
+    #[test]
+    fn parses_video_track() {
+        let mut tkhd = vec![0; 80];
+        tkhd[72..76].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+        tkhd[76..80].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+
+        let mut info = MediaInfo::new("mp4");
+        parse_trak(&video_trak(&tkhd, stsd(b"avc1", 36)), &mut info);
+
+        assert_eq!(info.video_codec.as_deref(), Some("H.264"));
+        assert_eq!((info.width, info.height), (Some(1920), Some(1080)));
+    }
+
+    #[test]
+    fn truncated_tkhd_falls_back_to_stsd() {
+        assert_eq!(tkhd_dimensions(&[0; 40]), None);
+
+        let mut info = MediaInfo::new("mp4");
+        parse_trak(&video_trak(&[0; 40], stsd(b"hvc1", 36)), &mut info);
+
+        assert_eq!(info.video_codec.as_deref(), Some("HEVC"));
+        assert_eq!((info.width, info.height), (Some(1280), Some(720)));
+    }
+
+    #[test]
+    fn truncated_stsd() {
+        let no_dimensions = stsd(b"avc1", 8);
+        assert_eq!(
+            stsd_format(&no_dimensions[8..]),
+            Some((String::from("avc1"), None))
+        );
+
+        let no_format = stsd(b"avc1", 6);
+        assert_eq!(stsd_format(&no_format[8..]), None);
+
+        let mut info = MediaInfo::new("mp4");
+        parse_trak(&video_trak(&[0; 40], no_format), &mut info);
+        assert_eq!(info.video_codec, None);
+        assert_eq!(info.width, None);
+    }
+}
@@ -0,0 +1,186 @@
+use rocket::serde::{Deserialize, Serialize};
+
+/*
+Maps byte offsets of a media file to playback time. Built from the container's own index
+(Matroska Cues, MP4 sample tables, AVI idx1), so it's exact at the indexed points and
+linearly interpolated between them, which is good enough even for VBR files.
+*/
+
+// Indexes of long movies can have hundreds of thousands of entries, there's no point in keeping them all
+const MAX_POINTS: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct SeekIndex {
+    // (byte offset, seconds), sorted by offset
+    points: Vec<(u64, f64)>,
+}
+
+impl SeekIndex {
+    /// Builds an index out of unordered points, returns None if there's nothing usable
+    pub fn from_points(mut points: Vec<(u64, f64)>) -> Option<Self> {
+        points.retain(|(_, secs)| secs.is_finite() && *secs >= 0.0);
+        points.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+
+        // Time has to grow along with the offset, points breaking that are dropped
+        let mut monotonic: Vec<(u64, f64)> = Vec::with_capacity(points.len());
+        for point in points {
+            match monotonic.last() {
+                Some(last) if last.0 == point.0 || last.1 > point.1 => {}
+                _ => monotonic.push(point),
+            }
+        }
+
+        if monotonic.is_empty() {
+            return None;
+        }
+
+        if monotonic.len() > MAX_POINTS {
+            let step = monotonic.len() as f64 / MAX_POINTS as f64;
+            let last = monotonic[monotonic.len() - 1];
+            monotonic = (0..MAX_POINTS)
+                .map(|idx| monotonic[(idx as f64 * step) as usize])
+                .collect();
+            monotonic.push(last);
+        }
+
+        Some(SeekIndex { points: monotonic })
+    }
+
+    /// Playback time at `offset`. Past the last indexed point it interpolates
+    /// towards the end of the file, if the duration is known
+    pub fn time_at(&self, offset: u64, file_len: u64, duration_secs: Option<f64>) -> f64 {
+        let next_idx = self
+            .points
+            .partition_point(|(point_offset, _)| *point_offset <= offset);
+        if next_idx == 0 {
+            // Headers, nothing has been played yet
+            return 0.0;
+        }
+
+        let (prev_offset, prev_secs) = self.points[next_idx - 1];
+        let next = match self.points.get(next_idx) {
+            Some(next) => Some(*next),
+            None => match duration_secs {
+                Some(duration) if file_len > prev_offset => Some((file_len, duration)),
+                _ => None,
+            },
+        };
+
+        let secs = match next {
+            Some((next_offset, next_secs)) => {
+                let fraction = (offset - prev_offset) as f64 / (next_offset - prev_offset) as f64;
+                prev_secs + (next_secs - prev_secs).max(0.0) * fraction.min(1.0)
+            }
+            None => prev_secs,
+        };
+
+        match duration_secs {
+            Some(duration) => secs.min(duration),
+            None => secs,
+        }
+    }
+}
+
+/// Best guess of playback time at `offset`: the seek index if there's one, proportion of the duration otherwise
+pub fn playback_position(
+    offset: u64,
+    file_len: u64,
+    duration_secs: Option<f64>,
+    seek_index: Option<&SeekIndex>,
+) -> Option<f64> {
+    match (seek_index, duration_secs) {
+        (Some(index), _) => Some(index.time_at(offset, file_len, duration_secs)),
+        (None, Some(duration)) if file_len > 0 => {
+            Some(duration * (offset.min(file_len) as f64 / file_len as f64))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(points: &[(u64, f64)]) -> SeekIndex {
+        SeekIndex::from_points(points.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let index = index(&[(100, 0.0), (1100, 10.0), (2100, 30.0)]);
+
+        assert_eq!(index.time_at(100, 5000, None), 0.0);
+        assert_eq!(index.time_at(600, 5000, None), 5.0);
+        assert_eq!(index.time_at(1100, 5000, None), 10.0);
+        assert_eq!(index.time_at(1350, 5000, None), 15.0);
+    }
+
+    #[test]
+    fn before_first_point() {
+        let index = index(&[(100, 5.0), (1100, 10.0)]);
+
+        assert_eq!(index.time_at(0, 5000, Some(60.0)), 0.0);
+        assert_eq!(index.time_at(99, 5000, Some(60.0)), 0.0);
+    }
+
+    #[test]
+    fn after_last_point() {
+        let index = index(&[(100, 0.0), (1100, 10.0)]);
+
+        // Towards the end of the file when the duration is known, stuck at the last point otherwise
+        assert_eq!(index.time_at(1600, 2100, Some(20.0)), 15.0);
+        assert_eq!(index.time_at(1600, 2100, None), 10.0);
+        assert_eq!(index.time_at(5000, 2100, Some(20.0)), 20.0);
+        // Never past the duration
+        assert_eq!(index.time_at(1100, 2100, Some(8.0)), 8.0);
+    }
+
+    #[test]
+    fn sorts_and_drops_broken_points() {
+        let index = index(&[
+            (1100, 10.0),
+            (100, 0.0),
+            (100, 3.0),
+            (600, 2.0),
+            // Time going back
+            (800, 1.0),
+            (900, f64::NAN),
+            (1000, -1.0),
+        ]);
+
+        assert_eq!(index.points, vec![(100, 0.0), (600, 2.0), (1100, 10.0)]);
+        assert_eq!(SeekIndex::from_points(Vec::new()), None);
+        assert_eq!(SeekIndex::from_points(vec![(100, f64::INFINITY)]), None);
+    }
+
+    #[test]
+    fn thins_long_indexes() {
+        let points: Vec<(u64, f64)> = (0..10_000).map(|idx| (idx * 1000, idx as f64)).collect();
+        let index = SeekIndex::from_points(points).unwrap();
+
+        assert_eq!(index.points.len(), MAX_POINTS + 1);
+        assert_eq!(index.points.first(), Some(&(0, 0.0)));
+        assert_eq!(index.points.last(), Some(&(9_999_000, 9999.0)));
+        assert!(index.points.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        // Still linear, so interpolation gives the same
+        assert_eq!(index.time_at(5_000_500, 10_000_000, None), 5000.5);
+    }
+
+    #[test]
+    fn playback_position_falls_back_to_duration() {
+        let index = index(&[(0, 0.0), (1000, 100.0)]);
+
+        assert_eq!(
+            playback_position(500, 2000, Some(200.0), Some(&index)),
+            Some(50.0)
+        );
+        assert_eq!(playback_position(500, 2000, Some(200.0), None), Some(50.0));
+        assert_eq!(
+            playback_position(5000, 2000, Some(200.0), None),
+            Some(200.0)
+        );
+        assert_eq!(playback_position(500, 2000, None, None), None);
+        assert_eq!(playback_position(500, 0, Some(200.0), None), None);
+    }
+}
@@ -0,0 +1,502 @@
+use crate::db;
+use crate::episodes::ParsedName;
+use crate::library::{self, ItemKind};
+use crate::paths::Libraries;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use rocket::serde::{json, Deserialize, Serialize};
+use rocket::tokio::task;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::*;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use db::prelude::*;
+
+/*
+NFO files written by Kodi, Jellyfin and the like: XML with title, plot, year and so on of a movie, a show or an episode.
+"Movie.nfo" is about "Movie.mkv" next to it, "tvshow.nfo" and "movie.nfo" are about the directory they're in.
+Parsed ones are kept in `nfo_metadata` with size and mtime of the file, so they're read again only when they change.
+The scan imports all of them, listings re-read changed ones of what they show.
+*/
+
+// Compared case-insensitively
+const DIR_NFO_NAMES: &[&str] = &["tvshow.nfo", "movie.nfo"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum NfoKind {
+    Movie,
+    TvShow,
+    Episode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct NfoInfo {
+    pub kind: NfoKind,
+    pub title: Option<String>,
+    pub original_title: Option<String>,
+    /// Series the episode is of
+    pub show_title: Option<String>,
+    pub plot: Option<String>,
+    pub year: Option<u32>,
+    pub rating: Option<f64>,
+    pub genres: Vec<String>,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+}
+
+impl NfoInfo {
+    /// Title to list the item under. Episodes keep their "S01E02 – " label, the rest get "Title (year)"
+    pub fn apply(&self, parsed: &mut ParsedName) -> Option<String> {
+        match self.kind {
+            NfoKind::Episode => {
+                if self.title.is_some() {
+                    parsed.episode_title = self.title.clone();
+                }
+                parsed.season = parsed.season.or(self.season);
+                parsed.episode = parsed.episode.or(self.episode);
+                if parsed.show.is_none() {
+                    parsed.show = self.show_title.clone();
+                }
+                parsed.title().or_else(|| self.title.clone())
+            }
+            NfoKind::Movie | NfoKind::TvShow => {
+                let title = self.title.as_ref()?;
+                Some(match self.year {
+                    Some(year) => format!("{} ({})", title, year),
+                    None => title.clone(),
+                })
+            }
+        }
+    }
+}
+
+pub fn is_nfo(key: &str) -> bool {
+    matches!(library::name_of(key).rsplit_once('.'), Some((_, ext)) if ext.eq_ignore_ascii_case("nfo"))
+}
+
+fn is_dir_nfo(key: &str) -> bool {
+    let name = library::name_of(key).to_lowercase();
+    DIR_NFO_NAMES.contains(&name.as_str())
+}
+
+/// Key without the extension, which NFO info of the file is stored under
+fn file_key(key: &str) -> &str {
+    let name_start = key.rfind('/').map_or(0, |idx| idx + 1);
+    match key[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => &key[..name_start + dot],
+        _ => key,
+    }
+}
+
+/// Key of the directory, or the file without extension, the NFO is about
+fn described_key(nfo_key: &str) -> String {
+    if is_dir_nfo(nfo_key) {
+        library::parent_key(nfo_key)
+    } else {
+        String::from(file_key(nfo_key))
+    }
+}
+
+fn child_text(node: roxmltree::Node, name: &str) -> Option<String> {
+    let text = node.children().find(|el| el.has_tag_name(name))?.text()?;
+    Some(String::from(text.trim())).filter(|text| !text.is_empty())
+}
+
+fn parse_number<T: std::str::FromStr>(text: Option<String>) -> Option<T> {
+    text?.parse().ok()
+}
+
+fn parse_rating(text: Option<String>) -> Option<f64> {
+    let rating: f64 = text?.replace(',', ".").parse().ok()?;
+    Some((rating * 10.0).round() / 10.0).filter(|rating| *rating > 0.0)
+}
+
+/// The default one of <ratings>, or the first one, or the old-style <rating>
+fn rating(root: roxmltree::Node) -> Option<f64> {
+    let ratings: Vec<roxmltree::Node> = root
+        .children()
+        .filter(|el| el.has_tag_name("ratings"))
+        .flat_map(|el| el.children().filter(|el| el.has_tag_name("rating")))
+        .collect();
+    let rating_value = ratings
+        .iter()
+        .find(|el| el.attribute("default") == Some("true"))
+        .or_else(|| ratings.first())
+        .and_then(|el| parse_rating(child_text(*el, "value")));
+
+    rating_value.or_else(|| parse_rating(child_text(root, "rating")))
+}
+
+/// Kodi allows a URL after the XML, and multi-episode files have several <episodedetails> in a row
+fn first_document(text: &str) -> &str {
+    const EPISODE_END: &str = "</episodedetails>";
+
+    if let Some(end) = text.find(EPISODE_END) {
+        return &text[..end + EPISODE_END.len()];
+    }
+    match text.rfind('>') {
+        Some(end) => &text[..=end],
+        None => text,
+    }
+}
+
+fn parse(text: &str) -> Result<NfoInfo> {
+    let document = roxmltree::Document::parse(first_document(text.trim_start_matches('\u{feff}')))?;
+    let root = document.root_element();
+
+    let kind = match root.tag_name().name() {
+        "movie" => NfoKind::Movie,
+        "tvshow" => NfoKind::TvShow,
+        "episodedetails" => NfoKind::Episode,
+        other => return Err(anyhow!("unknown NFO root element <{}>", other)),
+    };
+
+    // Dates are "YYYY-MM-DD"
+    let year = parse_number(child_text(root, "year")).or_else(|| {
+        let date = child_text(root, "premiered").or_else(|| child_text(root, "aired"))?;
+        date.get(..4)?.parse().ok()
+    });
+
+    // Some tools put them all in one, "Drama / Comedy"
+    let genres = root
+        .children()
+        .filter(|el| el.has_tag_name("genre"))
+        .filter_map(|el| el.text())
+        .flat_map(|text| text.split(" / "))
+        .map(str::trim)
+        .filter(|genre| !genre.is_empty())
+        .map(String::from)
+        .collect();
+
+    Ok(NfoInfo {
+        kind,
+        title: child_text(root, "title"),
+        original_title: child_text(root, "originaltitle"),
+        show_title: child_text(root, "showtitle"),
+        plot: child_text(root, "plot").or_else(|| child_text(root, "outline")),
+        year,
+        rating: rating(root),
+        genres,
+        season: parse_number(child_text(root, "season")),
+        episode: parse_number(child_text(root, "episode")),
+    })
+}
+
+fn read(path: &Path) -> Result<NfoInfo> {
+    let data = fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+    // Not every tool writes UTF-8
+    parse(&String::from_utf8_lossy(&data))
+}
+
+async fn import(
+    file: &db::library_items::Model,
+    libraries: &Libraries,
+    conn: &DatabaseConnection,
+) -> Result<Option<db::nfo_metadata::Model>> {
+    let abs_path = match libraries.resolve_key(&file.path) {
+        Ok(abs_path) => abs_path,
+        Err(err) => {
+            debug!("Not reading {:?}: {}", file.path, err);
+            return Ok(None);
+        }
+    };
+
+    let read_path = abs_path.clone();
+    let info = match task::spawn_blocking(move || read(&read_path)).await? {
+        Ok(info) => Some(json::to_string(&info)?),
+        Err(err) => {
+            // Stored anyway, so broken files are not re-read on every listing
+            warn!("Failed to parse NFO {:?}: {:#}", abs_path, err);
+            None
+        }
+    };
+
+    let model = db::nfo_metadata::Model {
+        path: file.path.clone(),
+        item: described_key(&file.path),
+        size: file.size,
+        mtime: file.mtime,
+        info,
+    };
+    // Concurrent listings can import the same file, so it's an upsert
+    NfoMetadata::insert(db::nfo_metadata::ActiveModel {
+        path: Set(model.path.clone()),
+        item: Set(model.item.clone()),
+        size: Set(model.size),
+        mtime: Set(model.mtime),
+        info: Set(model.info.clone()),
+    })
+    .on_conflict(
+        OnConflict::column(db::nfo_metadata::Column::Path)
+            .update_columns([
+                db::nfo_metadata::Column::Item,
+                db::nfo_metadata::Column::Size,
+                db::nfo_metadata::Column::Mtime,
+                db::nfo_metadata::Column::Info,
+            ])
+            .to_owned(),
+    )
+    .exec_without_returning(conn)
+    .await?;
+
+    Ok(Some(model))
+}
+
+/// Rows of the NFO files, parsing the ones that are new or changed since they were stored.
+/// Returns them with the number of parsed files
+async fn refresh(
+    nfo_files: &[db::library_items::Model],
+    mut stored: HashMap<String, db::nfo_metadata::Model>,
+    libraries: &Libraries,
+    conn: &DatabaseConnection,
+) -> Result<(Vec<db::nfo_metadata::Model>, usize)> {
+    let mut result = Vec::new();
+    let mut parsed = 0;
+
+    for file in nfo_files {
+        match stored.remove(&file.path) {
+            Some(model) if model.size == file.size && model.mtime == file.mtime => {
+                result.push(model)
+            }
+            _ => {
+                if let Some(model) = import(file, libraries, conn).await? {
+                    result.push(model);
+                    parsed += 1;
+                }
+            }
+        }
+    }
+
+    Ok((result, parsed))
+}
+
+/// NFO info of what a listing shows
+#[derive(Debug, Default)]
+pub struct ListingNfo {
+    dirs: HashMap<String, NfoInfo>,
+    files: HashMap<String, NfoInfo>,
+}
+
+impl ListingNfo {
+    fn from_models(models: Vec<db::nfo_metadata::Model>) -> Self {
+        let mut result = ListingNfo::default();
+        for model in models {
+            let info = match model.info.as_deref().map(json::from_str::<NfoInfo>) {
+                Some(Ok(info)) => info,
+                Some(Err(err)) => {
+                    warn!("Broken NFO info stored for {}: {}", model.path, err);
+                    continue;
+                }
+                None => continue,
+            };
+
+            if is_dir_nfo(&model.path) {
+                result.dirs.insert(model.item, info);
+            } else {
+                result.files.insert(model.item, info);
+            }
+        }
+
+        result
+    }
+
+    pub fn of_dir(&self, key: &str) -> Option<&NfoInfo> {
+        self.dirs.get(key)
+    }
+
+    pub fn of_file(&self, key: &str) -> Option<&NfoInfo> {
+        self.files.get(file_key(key))
+    }
+}
+
+/// NFO info of `entries`, the indexed children of a directory, and of the directory itself.
+/// `subdir_items` are the children of the directories among the entries
+pub async fn load_for_listing(
+    entries: &[db::library_items::Model],
+    subdir_items: &[db::library_items::Model],
+    libraries: &Libraries,
+    conn: &DatabaseConnection,
+) -> Result<ListingNfo> {
+    // Subdirectories' own files are for their listings
+    let nfo_files: Vec<db::library_items::Model> = entries
+        .iter()
+        .filter(|el| is_nfo(&el.path))
+        .chain(subdir_items.iter().filter(|el| is_dir_nfo(&el.path)))
+        .filter(|el| el.kind == ItemKind::File.as_str())
+        .cloned()
+        .collect();
+
+    let mut stored = HashMap::new();
+    for chunk in nfo_files.chunks(db::LOOKUP_CHUNK_SIZE) {
+        let models = NfoMetadata::find()
+            .filter(db::nfo_metadata::Column::Path.is_in(chunk.iter().map(|el| el.path.clone())))
+            .all(conn)
+            .await?;
+        stored.extend(models.into_iter().map(|model| (model.path.clone(), model)));
+    }
+
+    let (models, _) = refresh(&nfo_files, stored, libraries, conn).await?;
+
+    Ok(ListingNfo::from_models(models))
+}
+
+/// Stored NFO info of `items` from anywhere in the libraries, as of the last import
+pub async fn load_for_items(
+    items: &[db::library_items::Model],
+    conn: &DatabaseConnection,
+) -> Result<ListingNfo> {
+    let described: HashSet<&str> = items
+        .iter()
+        .map(|el| {
+            if el.kind == ItemKind::Dir.as_str() {
+                el.path.as_str()
+            } else {
+                file_key(&el.path)
+            }
+        })
+        .collect();
+    let described: Vec<&str> = described.into_iter().collect();
+
+    let mut models = Vec::new();
+    for chunk in described.chunks(db::LOOKUP_CHUNK_SIZE) {
+        models.extend(
+            NfoMetadata::find()
+                .filter(db::nfo_metadata::Column::Item.is_in(chunk.iter().copied()))
+                .all(conn)
+                .await?,
+        );
+    }
+
+    Ok(ListingNfo::from_models(models))
+}
+
+/// Reads NFO files within `scope` (index key, empty for everything) that are new or changed,
+/// and forgets the ones which are gone
+pub async fn import_library(
+    libraries: &Libraries,
+    scope: &str,
+    conn: &DatabaseConnection,
+) -> Result<usize> {
+    let in_scope = |path: &str| scope.is_empty() || library::is_same_or_descendant(path, scope);
+
+    let mut stored_query = NfoMetadata::find();
+    let mut items_query =
+        LibraryItem::find().filter(db::library_items::Column::Kind.eq(ItemKind::File.as_str()));
+    if !scope.is_empty() {
+        stored_query = stored_query.filter(db::nfo_metadata::Column::Path.starts_with(scope));
+        items_query = items_query.filter(db::library_items::Column::Path.starts_with(scope));
+    }
+
+    let stored: HashMap<String, db::nfo_metadata::Model> = stored_query
+        .all(conn)
+        .await?
+        .into_iter()
+        .filter(|model| in_scope(&model.path))
+        .map(|model| (model.path.clone(), model))
+        .collect();
+    let nfo_files: Vec<db::library_items::Model> = items_query
+        .all(conn)
+        .await?
+        .into_iter()
+        .filter(|item| in_scope(&item.path) && is_nfo(&item.path))
+        .collect();
+
+    let present: HashSet<&str> = nfo_files.iter().map(|el| el.path.as_str()).collect();
+    let stale: Vec<String> = stored
+        .keys()
+        .filter(|path| !present.contains(path.as_str()))
+        .cloned()
+        .collect();
+    for chunk in stale.chunks(db::LOOKUP_CHUNK_SIZE) {
+        NfoMetadata::delete_many()
+            .filter(db::nfo_metadata::Column::Path.is_in(chunk.iter().cloned()))
+            .exec(conn)
+            .await?;
+    }
+
+    let (_, parsed) = refresh(&nfo_files, stored, libraries, conn).await?;
+    if parsed > 0 {
+        info!("Read {} NFO files", parsed);
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_movies() {
+        let info = parse(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes" ?>
+<movie>
+  <title>The Movie</title>
+  <originaltitle>Le Film</originaltitle>
+  <ratings>
+    <rating name="imdb" max="10"><value>6.1</value></rating>
+    <rating name="themoviedb" max="10" default="true"><value>7.349</value></rating>
+  </ratings>
+  <plot>  Things happen.  </plot>
+  <genre>Drama / Comedy</genre>
+  <genre>Crime</genre>
+  <premiered>2019-05-30</premiered>
+</movie>
+https://www.imdb.com/title/tt0000000/"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.kind, NfoKind::Movie);
+        assert_eq!(info.title.as_deref(), Some("The Movie"));
+        assert_eq!(info.original_title.as_deref(), Some("Le Film"));
+        assert_eq!(info.plot.as_deref(), Some("Things happen."));
+        assert_eq!(info.year, Some(2019));
+        assert_eq!(info.rating, Some(7.3));
+        assert_eq!(info.genres, vec!["Drama", "Comedy", "Crime"]);
+        assert_eq!(
+            info.apply(&mut ParsedName::default()).as_deref(),
+            Some("The Movie (2019)")
+        );
+    }
+
+    #[test]
+    fn parses_episodes() {
+        // Two episodes in one file, the first one is enough
+        let text = "\u{feff}<episodedetails><title>Pilot</title><showtitle>Show</showtitle>\
+            <season>1</season><episode>1</episode><rating>8,25</rating></episodedetails>\
+            <episodedetails><title>Second</title></episodedetails>";
+        let info = parse(text).unwrap();
+
+        assert_eq!(info.kind, NfoKind::Episode);
+        assert_eq!(info.rating, Some(8.3));
+
+        let mut parsed = ParsedName::default();
+        assert_eq!(info.apply(&mut parsed).as_deref(), Some("S01E01 – Pilot"));
+        assert_eq!(parsed.show.as_deref(), Some("Show"));
+
+        assert!(parse("<musicvideo><title>Song</title></musicvideo>").is_err());
+        assert!(parse("https://www.imdb.com/title/tt0000000/").is_err());
+    }
+
+    #[test]
+    fn nfo_keys() {
+        assert_eq!(described_key("tv/Show/tvshow.nfo"), "tv/Show");
+        assert_eq!(described_key("m/Movie.2019/MOVIE.NFO"), "m/Movie.2019");
+        assert_eq!(
+            described_key("m/Movie.2019/Movie.2019.nfo"),
+            "m/Movie.2019/Movie.2019"
+        );
+        assert_eq!(
+            file_key("m/Movie.2019/Movie.2019.mkv"),
+            "m/Movie.2019/Movie.2019"
+        );
+        assert_eq!(file_key("m/Dir.name/noext"), "m/Dir.name/noext");
+        assert!(is_nfo("m/a.NFO"));
+        assert!(!is_nfo("m/nfo"));
+    }
+}
@@ -0,0 +1,691 @@
+use anyhow::{anyhow, Context, Result};
+use rocket::serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/*
+Everything that turns a path from a request, the index or the watcher into a path on disk goes through here.
+Paths are joined to the canonical root and the result is canonicalized again, so neither "..",
+nor a symlink pointing elsewhere, nor a root given as a relative or symlinked path
+can make a resolved path end up outside of the library.
+
+There can be several libraries, each with its own root. The name of the library is the first chunk
+of every key, so "movies/Alien/Alien.mkv" is "Alien/Alien.mkv" in the root of the "movies" library.
+*/
+
+/// Library of `--dir`, and of everything indexed before there were several libraries
+pub const DEFAULT_LIBRARY: &str = "default";
+
+/// What to do with symlinks found in the library
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Symlinks are neither listed nor served
+    Ignore,
+    /// Symlinks are followed, as long as they point inside the library root
+    WithinRoot,
+}
+
+/// `paths` section of Rocket.toml
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct PathsConfig {
+    pub symlinks: SymlinkPolicy,
+    /// Library name to its root directory
+    pub libraries: BTreeMap<String, String>,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            symlinks: SymlinkPolicy::Ignore,
+            libraries: BTreeMap::new(),
+        }
+    }
+}
+
+impl PathsConfig {
+    /// Adds a library given on command line as "name=path", replacing the one with the same name
+    pub fn add_cli_library(&mut self, arg: &str) -> Result<()> {
+        let (name, path) = arg
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected NAME=PATH, got {:?}", arg))?;
+        self.libraries
+            .insert(String::from(name), String::from(path));
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    /// Path is absolute, has ".." or isn't valid unicode
+    Invalid,
+    NotFound,
+    /// Path leads outside of the root through a symlink
+    OutsideRoot,
+    /// Path goes through a symlink, and the policy is to ignore them
+    Symlink,
+    Io(io::Error),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::Invalid => write!(f, "invalid path"),
+            ResolveError::NotFound => write!(f, "path is not found"),
+            ResolveError::OutsideRoot => write!(f, "path leads outside of the library"),
+            ResolveError::Symlink => write!(f, "path goes through a symlink"),
+            ResolveError::Io(err) => write!(f, "failed to resolve path: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl From<io::Error> for ResolveError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => ResolveError::NotFound,
+            _ => ResolveError::Io(err),
+        }
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+/// Converts relative path to the form it's stored in the `library_items` table.
+/// Anything but plain names ("..", root, prefixes, non-unicode names) is rejected
+pub fn rel_path_to_key(rel_path: &Path) -> Result<String, ResolveError> {
+    let mut chunks = Vec::new();
+    for component in rel_path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(name) => match name.to_str() {
+                Some(name) if is_valid_name(name) => chunks.push(name),
+                _ => return Err(ResolveError::Invalid),
+            },
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(ResolveError::Invalid)
+            }
+        }
+    }
+
+    Ok(chunks.join("/"))
+}
+
+/// Library names are in URLs, so they are kept simple
+fn is_valid_library_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Appends a file name to the key of its directory
+pub fn join_key(dir_key: &str, name: &str) -> String {
+    if dir_key.is_empty() {
+        String::from(name)
+    } else {
+        format!("{}/{}", dir_key, name)
+    }
+}
+
+/// Returns the library name part of the key
+pub fn library_of(key: &str) -> &str {
+    key.split('/').next().unwrap_or(key)
+}
+
+/// Resolves paths within the root of one library; cheap to clone
+#[derive(Debug, Clone)]
+pub struct PathResolver {
+    name: String,
+    root: PathBuf,
+    policy: SymlinkPolicy,
+}
+
+impl PathResolver {
+    pub fn new(name: &str, root_dir: &Path, policy: SymlinkPolicy) -> Result<Self> {
+        if !is_valid_library_name(name) {
+            return Err(anyhow!(
+                "library name {:?} should only have letters, digits, '-' and '_'",
+                name
+            ));
+        }
+
+        let root = fs::canonicalize(root_dir)
+            .with_context(|| format!("library root {:?} is not accessible", root_dir))?;
+        if !root.is_dir() {
+            return Err(anyhow!("library root {:?} is not a directory", root_dir));
+        }
+
+        Ok(PathResolver {
+            name: String::from(name),
+            root,
+            policy,
+        })
+    }
+
+    /// Name of the library, which is also the key of its root
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Canonical path of the root
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Part of the key below the root, None if the key is from another library
+    fn rel_key<'a>(&self, key: &'a str) -> Option<&'a str> {
+        if key == self.name {
+            return Some("");
+        }
+
+        key.strip_prefix(self.name.as_str())?
+            .strip_prefix('/')
+            .filter(|rel_key| !rel_key.is_empty())
+    }
+
+    /// Path of the key on disk, as it is, without resolving symlinks
+    pub fn abs_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.root.join(self.rel_key(key)?))
+    }
+
+    /// Resolves an index key to a canonical path inside the root
+    pub fn resolve_key(&self, key: &str) -> Result<PathBuf, ResolveError> {
+        let rel_key = self.rel_key(key).ok_or(ResolveError::Invalid)?;
+        // Empty key is the root itself
+        let names = rel_key.split('/').filter(|_| !rel_key.is_empty());
+
+        let mut lexical = self.root.clone();
+        for name in names {
+            if !is_valid_name(name) {
+                return Err(ResolveError::Invalid);
+            }
+            lexical.push(name);
+
+            if self.policy == SymlinkPolicy::Ignore
+                && fs::symlink_metadata(&lexical)?.file_type().is_symlink()
+            {
+                return Err(ResolveError::Symlink);
+            }
+        }
+
+        let canonical = fs::canonicalize(&lexical)?;
+        if !canonical.starts_with(&self.root) {
+            return Err(ResolveError::OutsideRoot);
+        }
+
+        Ok(canonical)
+    }
+
+    /// Index key of an absolute path, if it's inside the root and isn't the root itself
+    pub fn key_of(&self, abs_path: &Path) -> Option<String> {
+        let rel_path = abs_path.strip_prefix(&self.root).ok()?;
+        match rel_path_to_key(rel_path) {
+            Ok(key) if !key.is_empty() => Some(join_key(&self.name, &key)),
+            _ => None,
+        }
+    }
+
+    /// Metadata of an entry found while walking the library, following symlinks if the policy allows.
+    /// None means the entry should be skipped
+    pub fn entry_metadata(&self, abs_path: &Path) -> io::Result<Option<fs::Metadata>> {
+        let metadata = fs::symlink_metadata(abs_path)?;
+        if !metadata.file_type().is_symlink() {
+            return Ok(Some(metadata));
+        }
+
+        match self.policy {
+            SymlinkPolicy::Ignore => Ok(None),
+            SymlinkPolicy::WithinRoot => match fs::canonicalize(abs_path) {
+                Ok(target) if target.starts_with(&self.root) => Ok(Some(fs::metadata(target)?)),
+                Ok(_) => Ok(None),
+                // Dangling symlink
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            },
+        }
+    }
+}
+
+/// All configured libraries; cheap to clone
+#[derive(Debug, Clone)]
+pub struct Libraries {
+    libraries: Vec<PathResolver>,
+}
+
+impl Libraries {
+    pub fn from_config(config: &PathsConfig) -> Result<Self> {
+        if config.libraries.is_empty() {
+            return Err(anyhow!("no libraries, add one with --library NAME=PATH"));
+        }
+
+        let libraries = config
+            .libraries
+            .iter()
+            .map(|(name, path)| {
+                let path = shellexpand::tilde(path);
+                PathResolver::new(name, Path::new(path.as_ref()), config.symlinks)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Libraries { libraries })
+    }
+
+    /// Libraries, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = &PathResolver> {
+        self.libraries.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PathResolver> {
+        self.libraries.iter().find(|library| library.name == name)
+    }
+
+    /// Library the key belongs to
+    pub fn by_key(&self, key: &str) -> Option<&PathResolver> {
+        self.get(library_of(key))
+    }
+
+    /// Resolves an index key to a canonical path inside the root of its library
+    pub fn resolve_key(&self, key: &str) -> Result<PathBuf, ResolveError> {
+        self.by_key(key)
+            .ok_or(ResolveError::NotFound)?
+            .resolve_key(key)
+    }
+
+    /// Index key of an absolute path, if it's inside one of the roots
+    pub fn key_of(&self, abs_path: &Path) -> Option<String> {
+        self.libraries
+            .iter()
+            .find_map(|library| library.key_of(abs_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use uuid::Uuid;
+
+    /// Temporary directory with `library` (the root of "lib") and `outside` in it
+    struct Sandbox {
+        dir: PathBuf,
+    }
+
+    impl Sandbox {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("mpvserve-paths-{}", Uuid::new_v4()));
+            fs::create_dir_all(dir.join("library/movies")).unwrap();
+            fs::create_dir_all(dir.join("outside")).unwrap();
+            fs::write(dir.join("library/movies/film.mkv"), b"film").unwrap();
+            fs::write(dir.join("outside/secret.mkv"), b"secret").unwrap();
+
+            Sandbox { dir }
+        }
+
+        fn library(&self) -> PathBuf {
+            self.dir.join("library")
+        }
+
+        fn resolver(&self, policy: SymlinkPolicy) -> PathResolver {
+            PathResolver::new("lib", &self.library(), policy).unwrap()
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Resolves a path as it comes in a request
+    fn resolve(resolver: &PathResolver, rel_path: &str) -> Result<PathBuf, ResolveError> {
+        resolver.resolve_key(&rel_path_to_key(Path::new(rel_path))?)
+    }
+
+    fn is_invalid(res: Result<PathBuf, ResolveError>) -> bool {
+        matches!(res, Err(ResolveError::Invalid))
+    }
+
+    #[test]
+    fn plain_paths_resolve() {
+        let sandbox = Sandbox::new();
+        let resolver = sandbox.resolver(SymlinkPolicy::Ignore);
+        let expected = fs::canonicalize(sandbox.library().join("movies/film.mkv")).unwrap();
+
+        assert_eq!(resolve(&resolver, "lib/movies/film.mkv").unwrap(), expected);
+        assert_eq!(
+            resolve(&resolver, "./lib/movies/./film.mkv").unwrap(),
+            expected
+        );
+        assert_eq!(
+            resolver.resolve_key("lib/movies/film.mkv").unwrap(),
+            expected
+        );
+        assert_eq!(resolver.resolve_key("lib").unwrap(), resolver.root());
+    }
+
+    #[test]
+    fn keys_of_other_libraries_are_rejected() {
+        let sandbox = Sandbox::new();
+        let resolver = sandbox.resolver(SymlinkPolicy::Ignore);
+
+        assert!(is_invalid(resolver.resolve_key("movies/film.mkv")));
+        assert!(is_invalid(resolver.resolve_key("lib2/movies/film.mkv")));
+        assert!(is_invalid(resolver.resolve_key("")));
+        assert_eq!(resolver.abs_path("library/movies"), None);
+    }
+
+    #[test]
+    fn parent_dirs_are_rejected() {
+        let sandbox = Sandbox::new();
+        let resolver = sandbox.resolver(SymlinkPolicy::WithinRoot);
+
+        assert!(is_invalid(resolve(&resolver, "lib/../outside/secret.mkv")));
+        assert!(is_invalid(resolve(&resolver, "lib/movies/../../outside")));
+        // Even when it would stay inside the root
+        assert!(is_invalid(resolve(&resolver, "lib/movies/../movies")));
+        assert!(is_invalid(
+            resolver.resolve_key("lib/../outside/secret.mkv")
+        ));
+        assert!(is_invalid(resolver.resolve_key("lib/movies/../../outside")));
+        assert!(is_invalid(resolver.resolve_key("lib/movies//film.mkv")));
+        assert!(is_invalid(resolver.resolve_key("lib/movies/./film.mkv")));
+        assert!(is_invalid(resolver.resolve_key("lib/")));
+    }
+
+    #[test]
+    fn absolute_paths_are_rejected() {
+        let sandbox = Sandbox::new();
+        let resolver = sandbox.resolver(SymlinkPolicy::WithinRoot);
+        let secret = sandbox.dir.join("outside/secret.mkv");
+
+        assert!(is_invalid(resolve(&resolver, secret.to_str().unwrap())));
+        assert!(is_invalid(resolve(&resolver, "/etc/passwd")));
+        assert!(is_invalid(resolver.resolve_key("/etc/passwd")));
+    }
+
+    #[test]
+    fn missing_paths_are_not_found() {
+        let sandbox = Sandbox::new();
+        let resolver = sandbox.resolver(SymlinkPolicy::Ignore);
+
+        assert!(matches!(
+            resolve(&resolver, "lib/movies/missing.mkv"),
+            Err(ResolveError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn symlinks_are_ignored_by_default() {
+        let sandbox = Sandbox::new();
+        symlink(
+            sandbox.library().join("movies/film.mkv"),
+            sandbox.library().join("link.mkv"),
+        )
+        .unwrap();
+        symlink(
+            sandbox.library().join("movies"),
+            sandbox.library().join("linked"),
+        )
+        .unwrap();
+        let resolver =
+            PathResolver::new("lib", &sandbox.library(), PathsConfig::default().symlinks).unwrap();
+
+        assert!(matches!(
+            resolve(&resolver, "lib/link.mkv"),
+            Err(ResolveError::Symlink)
+        ));
+        assert!(matches!(
+            resolve(&resolver, "lib/linked/film.mkv"),
+            Err(ResolveError::Symlink)
+        ));
+        assert!(resolver
+            .entry_metadata(&sandbox.library().join("link.mkv"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn symlinks_within_root_are_followed() {
+        let sandbox = Sandbox::new();
+        symlink(
+            sandbox.library().join("movies/film.mkv"),
+            sandbox.library().join("link.mkv"),
+        )
+        .unwrap();
+        symlink("movies", sandbox.library().join("linked")).unwrap();
+        let resolver = sandbox.resolver(SymlinkPolicy::WithinRoot);
+        let film = fs::canonicalize(sandbox.library().join("movies/film.mkv")).unwrap();
+
+        assert_eq!(resolve(&resolver, "lib/link.mkv").unwrap(), film);
+        assert_eq!(resolve(&resolver, "lib/linked/film.mkv").unwrap(), film);
+
+        let metadata = resolver
+            .entry_metadata(&sandbox.library().join("linked"))
+            .unwrap()
+            .unwrap();
+        assert!(metadata.is_dir());
+    }
+
+    #[test]
+    fn symlinks_out_of_root_are_rejected() {
+        let sandbox = Sandbox::new();
+        symlink(
+            sandbox.dir.join("outside/secret.mkv"),
+            sandbox.library().join("secret.mkv"),
+        )
+        .unwrap();
+        symlink("../../outside", sandbox.library().join("movies/escape")).unwrap();
+        let resolver = sandbox.resolver(SymlinkPolicy::WithinRoot);
+
+        assert!(matches!(
+            resolve(&resolver, "lib/secret.mkv"),
+            Err(ResolveError::OutsideRoot)
+        ));
+        assert!(matches!(
+            resolve(&resolver, "lib/movies/escape/secret.mkv"),
+            Err(ResolveError::OutsideRoot)
+        ));
+        assert!(resolver
+            .entry_metadata(&sandbox.library().join("movies/escape"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn dangling_symlinks_are_skipped() {
+        let sandbox = Sandbox::new();
+        symlink("nowhere.mkv", sandbox.library().join("dangling.mkv")).unwrap();
+        let resolver = sandbox.resolver(SymlinkPolicy::WithinRoot);
+
+        assert!(resolver
+            .entry_metadata(&sandbox.library().join("dangling.mkv"))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            resolve(&resolver, "lib/dangling.mkv"),
+            Err(ResolveError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn symlinked_root() {
+        let sandbox = Sandbox::new();
+        let root_link = sandbox.dir.join("root-link");
+        symlink(sandbox.library(), &root_link).unwrap();
+        let resolver = PathResolver::new("lib", &root_link, SymlinkPolicy::Ignore).unwrap();
+        let film = fs::canonicalize(sandbox.library().join("movies/film.mkv")).unwrap();
+
+        // The root itself being a symlink doesn't count
+        assert_eq!(
+            resolver.root(),
+            fs::canonicalize(sandbox.library()).unwrap()
+        );
+        assert_eq!(resolve(&resolver, "lib/movies/film.mkv").unwrap(), film);
+        assert_eq!(
+            resolver.key_of(&film).as_deref(),
+            Some("lib/movies/film.mkv")
+        );
+    }
+
+    #[test]
+    fn relative_root() {
+        let sandbox = Sandbox::new();
+        // Relative to the current directory, whatever it is
+        let current_dir = std::env::current_dir().unwrap();
+        let mut relative = PathBuf::new();
+        for _ in current_dir.components().skip(1) {
+            relative.push("..");
+        }
+        relative.push(sandbox.library().strip_prefix("/").unwrap());
+        let resolver = PathResolver::new("lib", &relative, SymlinkPolicy::Ignore).unwrap();
+
+        assert!(resolver.root().is_absolute());
+        assert!(resolve(&resolver, "lib/movies/film.mkv").is_ok());
+        assert_eq!(
+            resolver
+                .key_of(&resolver.root().join("movies/film.mkv"))
+                .as_deref(),
+            Some("lib/movies/film.mkv")
+        );
+    }
+
+    #[test]
+    fn odd_unicode_names() {
+        let sandbox = Sandbox::new();
+        let names = [
+            // Fullwidth and one-dot leaders look like ".." but aren't
+            "\u{ff0e}\u{ff0e}",
+            "\u{2024}\u{2024}",
+            // Zero-width space after the dots
+            "..\u{200b}",
+            // Right-to-left override, combining marks, decomposed and precomposed "é"
+            "\u{202e}vkm.film",
+            "e\u{301}te\u{301}.mkv",
+            "\u{e9}t\u{e9}.mkv",
+            "Z\u{335}\u{321}a\u{338}l\u{336}g\u{334}o\u{337}",
+            // Urlencoded dots are just a name on disk
+            "%2e%2e",
+        ];
+        for name in names {
+            fs::write(sandbox.library().join("movies").join(name), b"odd").unwrap();
+        }
+        let resolver = sandbox.resolver(SymlinkPolicy::Ignore);
+
+        for name in names {
+            let key = format!("lib/movies/{}", name);
+            let resolved = resolver.resolve_key(&key).unwrap();
+            assert!(resolved.starts_with(resolver.root()), "{:?}", name);
+            assert_eq!(resolver.key_of(&resolved), Some(key.clone()));
+            assert_eq!(resolve(&resolver, &key).unwrap(), resolved);
+        }
+
+        // These are not on disk, but still must not escape
+        assert!(matches!(
+            resolver.resolve_key("lib/\u{ff0e}\u{ff0e}/outside"),
+            Err(ResolveError::NotFound)
+        ));
+        assert!(is_invalid(resolver.resolve_key("lib/movies/nul\0.mkv")));
+    }
+
+    #[test]
+    fn keys_of_paths_outside_are_none() {
+        let sandbox = Sandbox::new();
+        let resolver = sandbox.resolver(SymlinkPolicy::WithinRoot);
+
+        assert_eq!(resolver.key_of(resolver.root()), None);
+        assert_eq!(
+            resolver.key_of(&sandbox.dir.join("outside/secret.mkv")),
+            None
+        );
+        // Prefix of the name is not a parent
+        assert_eq!(
+            resolver.key_of(&sandbox.dir.join("library2/film.mkv")),
+            None
+        );
+        assert_eq!(
+            resolver.key_of(&resolver.root().join("movies/../../outside")),
+            None
+        );
+    }
+
+    #[test]
+    fn keys_are_slash_separated() {
+        assert_eq!(
+            rel_path_to_key(Path::new("a/./b/c.mkv")).unwrap(),
+            "a/b/c.mkv"
+        );
+        assert_eq!(rel_path_to_key(Path::new("")).unwrap(), "");
+        assert!(rel_path_to_key(Path::new("a/../b")).is_err());
+        assert_eq!(join_key("", "a"), "a");
+        assert_eq!(join_key("a/b", "c"), "a/b/c");
+        assert_eq!(library_of("a/b/c"), "a");
+        assert_eq!(library_of("a"), "a");
+    }
+
+    #[test]
+    fn library_names() {
+        let sandbox = Sandbox::new();
+        for name in ["", ".", "..", "a/b", "a b", "movies?"] {
+            assert!(
+                PathResolver::new(name, &sandbox.library(), SymlinkPolicy::Ignore).is_err(),
+                "{:?}",
+                name
+            );
+        }
+        for name in ["movies", "tv-shows", "anime_2", "\u{30a2}\u{30cb}\u{30e1}"] {
+            assert!(
+                PathResolver::new(name, &sandbox.library(), SymlinkPolicy::Ignore).is_ok(),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn several_libraries() {
+        let sandbox = Sandbox::new();
+        let mut config = PathsConfig::default();
+        assert!(Libraries::from_config(&config).is_err());
+
+        config.libraries.insert(
+            String::from("tv"),
+            String::from(sandbox.dir.join("outside").to_str().unwrap()),
+        );
+        config
+            .add_cli_library(&format!("movies={}", sandbox.library().display()))
+            .unwrap();
+        assert!(config.add_cli_library("movies").is_err());
+        let libraries = Libraries::from_config(&config).unwrap();
+
+        let names: Vec<&str> = libraries.iter().map(|library| library.name()).collect();
+        assert_eq!(names, ["movies", "tv"]);
+
+        let film = fs::canonicalize(sandbox.library().join("movies/film.mkv")).unwrap();
+        let secret = fs::canonicalize(sandbox.dir.join("outside/secret.mkv")).unwrap();
+        assert_eq!(
+            libraries.resolve_key("movies/movies/film.mkv").unwrap(),
+            film
+        );
+        assert_eq!(libraries.resolve_key("tv/secret.mkv").unwrap(), secret);
+        assert_eq!(libraries.key_of(&secret).as_deref(), Some("tv/secret.mkv"));
+        // Same file name, but in the other library
+        assert!(matches!(
+            libraries.resolve_key("movies/secret.mkv"),
+            Err(ResolveError::NotFound)
+        ));
+        assert!(matches!(
+            libraries.resolve_key("anime/film.mkv"),
+            Err(ResolveError::NotFound)
+        ));
+        assert!(is_invalid(libraries.resolve_key("tv/../movies")));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::library;
+use crate::reading_dirs::{self, ListingContext, ResultItem};
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/*
+Extended M3U playlists of directories, so a whole season can be queued with one link.
+Entries are plain http /files/ links, each signed on its own, as mpv requests them one by one.
+*/
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PlaylistOptions {
+    /// Skip everything before the first file that's not watched yet
+    pub from_unwatched: bool,
+    /// Include subdirectories, each after the files of its parent
+    pub recursive: bool,
+}
+
+/// Playable files of the directory in playlist order
+async fn collect_movies(
+    dir: &str,
+    options: PlaylistOptions,
+    ctx: &ListingContext<'_>,
+) -> Result<Vec<ResultItem>> {
+    let mut movies = Vec::new();
+    let mut pending = vec![String::from(dir)];
+
+    while let Some(dir) = pending.pop() {
+        let result = reading_dirs::read_dir(Path::new(&dir), ctx)
+            .await
+            .with_context(|| format!("failed to list {:?}", dir))?;
+
+        movies.extend(result.movies);
+        if options.recursive {
+            // Stack pops the last one first, dirs are sorted by name
+            pending.extend(result.dirs.into_iter().rev().map(|el| el.rel_path));
+        }
+    }
+
+    if options.from_unwatched {
+        // Everything watched means it's a rewatch
+        if let Some(first) = movies
+            .iter()
+            .position(|el| !reading_dirs::is_watched(&el.progress))
+        {
+            movies.drain(..first);
+        }
+    }
+
+    Ok(movies)
+}
+
+fn extinf_title(item: &ResultItem) -> String {
+    let show = item.parsed.as_ref().and_then(|parsed| parsed.show.as_ref());
+    let title = match (show, &item.title) {
+        (Some(show), Some(title)) => format!("{} {}", show, title),
+        (None, Some(title)) => title.clone(),
+        (_, None) => item.name.clone(),
+    };
+
+    // Line breaks would end the directive
+    title.replace(['\r', '\n'], " ")
+}
+
+pub async fn generate(
+    dir: &str,
+    options: PlaylistOptions,
+    ctx: &ListingContext<'_>,
+) -> Result<String> {
+    let movies = collect_movies(dir, options, ctx).await?;
+
+    let mut res = String::from("#EXTM3U\n");
+    for item in movies.iter() {
+        let duration = item
+            .metadata
+            .as_ref()
+            .and_then(|info| info.duration_secs)
+            .map(|duration| duration.round() as i64)
+            .unwrap_or(-1);
+
+        res += &format!("#EXTINF:{},{}\n", duration, extinf_title(item));
+        res += &reading_dirs::get_file_url(
+            &library::urlencode_key(&item.rel_path),
+            ctx.host_header,
+            ctx.user_id,
+            &ctx.url_signer.sign(&item.rel_path, ctx.user_id.as_str()),
+        );
+        res += "\n";
+    }
+
+    Ok(res)
+}
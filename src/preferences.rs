@@ -0,0 +1,210 @@
+use crate::db;
+use crate::reading_dirs::ProgressFilter;
+use anyhow::Result;
+use rocket::serde::Serialize;
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::ActiveValue::Set;
+use sea_orm::*;
+
+use db::prelude::*;
+
+/*
+How each user wants directory listings: the order and which files to show. Listings take them as
+`?sort=&order=&watched=` and remember them in `listing_preferences`, so the next listing without them looks the same.
+*/
+
+#[derive(FromFormField, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SortBy {
+    /// Episode order, the rest by name
+    #[default]
+    Name,
+    Mtime,
+    Size,
+    Duration,
+    #[field(value = "last_watched")]
+    LastWatched,
+}
+
+impl SortBy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortBy::Name => "name",
+            SortBy::Mtime => "mtime",
+            SortBy::Size => "size",
+            SortBy::Duration => "duration",
+            SortBy::LastWatched => "last_watched",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "name" => Some(SortBy::Name),
+            "mtime" => Some(SortBy::Mtime),
+            "size" => Some(SortBy::Size),
+            "duration" => Some(SortBy::Duration),
+            "last_watched" => Some(SortBy::LastWatched),
+            _ => None,
+        }
+    }
+}
+
+#[derive(FromFormField, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "asc" => Some(SortOrder::Asc),
+            "desc" => Some(SortOrder::Desc),
+            _ => None,
+        }
+    }
+}
+
+/// Query string of /browse and /api/browse, whatever is left out is as the last time
+#[derive(FromForm, Debug, Default)]
+pub struct ListingQuery {
+    pub sort: Option<SortBy>,
+    pub order: Option<SortOrder>,
+    pub watched: Option<ProgressFilter>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ListingOptions {
+    pub sort: SortBy,
+    pub order: SortOrder,
+    pub watched: ProgressFilter,
+}
+
+impl ListingOptions {
+    fn from_model(model: &db::listing_preferences::Model) -> Self {
+        ListingOptions {
+            sort: SortBy::from_name(&model.sort).unwrap_or_default(),
+            order: SortOrder::from_name(&model.sort_order).unwrap_or_default(),
+            watched: ProgressFilter::from_name(&model.watched).unwrap_or_default(),
+        }
+    }
+
+    fn to_model(self, user_id: &str) -> db::listing_preferences::Model {
+        db::listing_preferences::Model {
+            user_id: String::from(user_id),
+            sort: String::from(self.sort.as_str()),
+            sort_order: String::from(self.order.as_str()),
+            watched: String::from(self.watched.as_str()),
+        }
+    }
+
+    /// These, with what `query` has instead
+    fn with(self, query: &ListingQuery) -> Self {
+        ListingOptions {
+            sort: query.sort.unwrap_or(self.sort),
+            order: query.order.unwrap_or(self.order),
+            watched: query.watched.unwrap_or(self.watched),
+        }
+    }
+}
+
+/// Listing options of the user: remembered ones with what the request changes, which are remembered in turn
+pub async fn resolve(
+    user_id: &str,
+    query: &ListingQuery,
+    conn: &DatabaseConnection,
+) -> Result<ListingOptions> {
+    let remembered = ListingPreference::find_by_id(String::from(user_id))
+        .one(conn)
+        .await?
+        .map(|model| ListingOptions::from_model(&model))
+        .unwrap_or_default();
+
+    let options = remembered.with(query);
+    if options == remembered {
+        return Ok(options);
+    }
+
+    let model = options.to_model(user_id);
+    ListingPreference::insert(db::listing_preferences::ActiveModel {
+        user_id: Set(model.user_id),
+        sort: Set(model.sort),
+        sort_order: Set(model.sort_order),
+        watched: Set(model.watched),
+    })
+    .on_conflict(
+        OnConflict::column(db::listing_preferences::Column::UserId)
+            .update_columns([
+                db::listing_preferences::Column::Sort,
+                db::listing_preferences::Column::SortOrder,
+                db::listing_preferences::Column::Watched,
+            ])
+            .to_owned(),
+    )
+    .exec_without_returning(conn)
+    .await?;
+
+    Ok(options)
+}
+
+/// Moves preferences of an anonymous id to the user's key, unless the user has their own already
+pub async fn claim<C: ConnectionTrait>(anonymous_id: &str, user_key: &str, conn: &C) -> Result<()> {
+    let has_own = ListingPreference::find_by_id(String::from(user_key))
+        .one(conn)
+        .await?
+        .is_some();
+
+    if has_own {
+        ListingPreference::delete_by_id(String::from(anonymous_id))
+            .exec(conn)
+            .await?;
+    } else {
+        ListingPreference::update_many()
+            .col_expr(
+                db::listing_preferences::Column::UserId,
+                Expr::value(user_key),
+            )
+            .filter(db::listing_preferences::Column::UserId.eq(anonymous_id))
+            .exec(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_remembered_options() {
+        let remembered = ListingOptions {
+            sort: SortBy::Size,
+            order: SortOrder::Desc,
+            watched: ProgressFilter::Unwatched,
+        };
+        let query = ListingQuery {
+            sort: Some(SortBy::LastWatched),
+            ..Default::default()
+        };
+
+        let options = remembered.with(&query);
+        assert_eq!(options.sort, SortBy::LastWatched);
+        assert_eq!(options.order, SortOrder::Desc);
+        assert_eq!(options.watched, ProgressFilter::Unwatched);
+
+        let model = options.to_model("user");
+        assert_eq!(model.sort, "last_watched");
+        assert_eq!(ListingOptions::from_model(&model), options);
+    }
+}
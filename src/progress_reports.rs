@@ -0,0 +1,96 @@
+use crate::db;
+use crate::library::{self, ItemKind};
+use crate::servings::{self, ServingKey};
+use crate::signing::{LinkSignature, UrlSigner};
+use crate::utils::now_secs;
+use anyhow::{anyhow, Context, Result};
+use rocket::serde::Deserialize;
+use sea_orm::ActiveValue::Set;
+use sea_orm::*;
+
+use db::prelude::*;
+
+/*
+Progress reported by the wrapper, which asks mpv for the playback position over its IPC socket.
+Unlike byte tracking, it's exact, so it's preferred whenever it's not older than the last served stream.
+*/
+
+// Stream is closed a bit after mpv quits, so its timestamp is always a bit later than the last report
+const REPORT_GRACE_SECS: i64 = 60;
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ProgressReport {
+    /// Path of the file, urlencoded, as it's in /files/ links
+    pub file: String,
+    pub user_id: String,
+    pub position_secs: f64,
+    pub duration_secs: Option<f64>,
+    #[serde(default)]
+    pub paused: bool,
+    /// Signature of the link being played, so only files listed to the user can be reported
+    #[serde(flatten)]
+    pub link: LinkSignature,
+}
+
+pub async fn store(
+    report: &ProgressReport,
+    url_signer: &UrlSigner,
+    conn: &DatabaseConnection,
+) -> Result<()> {
+    if !report.position_secs.is_finite() || report.position_secs < 0.0 {
+        return Err(anyhow!("invalid position {}", report.position_secs));
+    }
+    let duration_secs = report
+        .duration_secs
+        .filter(|duration| duration.is_finite() && *duration > 0.0);
+
+    let key = library::urldecode_key(&report.file)?;
+    url_signer
+        .verify(&key, &report.user_id, &report.link)
+        .with_context(|| format!("report for {:?} isn't accepted", key))?;
+
+    let item = LibraryItem::find_by_id(key.clone())
+        .one(conn)
+        .await?
+        .filter(|item| item.kind == ItemKind::File.as_str())
+        .ok_or_else(|| anyhow!("{:?} is not found in the library", key))?;
+
+    let now = now_secs();
+
+    // Byte tracking data is only there for new rows
+    let mut serving = ServingKey::new(&key, &report.user_id).active_model();
+    serving.last_timestamp = Set(now);
+    serving.last_file_position = Set(0);
+    serving.file_length = Set(item.size);
+    serving.orphaned = Set(false);
+    serving.reported_position_secs = Set(Some(report.position_secs));
+    serving.reported_duration_secs = Set(duration_secs);
+    serving.reported_paused = Set(report.paused);
+    serving.reported_timestamp = Set(Some(now));
+    // Playing it again is more telling than marking
+    serving.watched = Set(None);
+
+    let columns = [
+        db::movie_servings::Column::Orphaned,
+        db::movie_servings::Column::ReportedPositionSecs,
+        db::movie_servings::Column::ReportedDurationSecs,
+        db::movie_servings::Column::ReportedPaused,
+        db::movie_servings::Column::ReportedTimestamp,
+        db::movie_servings::Column::Watched,
+    ];
+    servings::upsert(serving, &columns, conn).await
+}
+
+/// Reported (position, duration) of the serving, if there's a report newer than byte tracking data
+pub fn current_report(serving: &db::movie_servings::Model) -> Option<(f64, Option<f64>)> {
+    let reported_timestamp = serving.reported_timestamp?;
+    if reported_timestamp + REPORT_GRACE_SECS < serving.last_timestamp {
+        return None;
+    }
+
+    Some((
+        serving.reported_position_secs?,
+        serving.reported_duration_secs,
+    ))
+}
@@ -0,0 +1,244 @@
+use std::ops::Range;
+
+/*
+mpv doesn't just read a file from the start to the end: when opening it, it reads the headers,
+jumps to the end for indexes (Matroska Cues, MP4 moov written last, AVI idx1), then goes back.
+A stream closed right after that would look like a movie watched till the very end.
+
+So instead of trusting the last position, every request keeps the history of byte ranges it served,
+and only ranges that look like actual playback count.
+*/
+
+// Reads shorter than this are headers, indexes and such
+const MIN_PLAYED_BYTES: u64 = 1024 * 1024;
+// Ranges this close to each other are considered contiguous
+const MAX_GAP_BYTES: u64 = 64 * 1024;
+// Range starting this close to the end of the file and reaching it is an index read, not playback.
+// The bigger of the two is used, but never more than a tenth of the file
+const TAIL_WINDOW_BYTES: u64 = 16 * 1024 * 1024;
+const TAIL_WINDOW_PERMILLE: u64 = 10;
+
+#[derive(Debug, Clone)]
+pub struct ProgressTracker {
+    file_len: u64,
+    position: u64,
+    // Served ranges, in the order they were served
+    ranges: Vec<Range<u64>>,
+}
+
+impl ProgressTracker {
+    pub fn new(file_len: u64) -> Self {
+        ProgressTracker {
+            file_len,
+            position: 0,
+            ranges: Vec::new(),
+        }
+    }
+
+    pub fn on_seek(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    pub fn on_read(&mut self, len: u64) {
+        if len == 0 {
+            return;
+        }
+
+        let end = self.position + len;
+        match self.ranges.last_mut() {
+            Some(last) if last.end == self.position => last.end = end,
+            _ => self.ranges.push(self.position..end),
+        }
+        self.position = end;
+    }
+
+    fn tail_window(&self) -> u64 {
+        TAIL_WINDOW_BYTES
+            .max(self.file_len / 1000 * TAIL_WINDOW_PERMILLE)
+            .min(self.file_len / 10)
+    }
+
+    fn is_playback(&self, range: &Range<u64>) -> bool {
+        let is_short = range.end - range.start < MIN_PLAYED_BYTES;
+        let tail_start = self.file_len.saturating_sub(self.tail_window());
+        let is_tail_read = range.start >= tail_start && range.end + MAX_GAP_BYTES >= self.file_len;
+
+        !is_short && !is_tail_read
+    }
+
+    /// Bytes served, re-reads included
+    pub fn bytes_read(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
+    /// Start of the first served range that looks like playback
+    pub fn start_position(&self) -> Option<u64> {
+        self.ranges
+            .iter()
+            .find(|range| self.is_playback(range))
+            .map(|range| range.start)
+    }
+
+    /// End of the furthest contiguous range that looks like playback.
+    /// None means the request only probed the file and shouldn't affect progress
+    pub fn committed_position(&self) -> Option<u64> {
+        let mut sorted = self.ranges.clone();
+        sorted.sort_by_key(|range| range.start);
+
+        let mut merged: Vec<Range<u64>> = Vec::new();
+        for range in sorted {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end + MAX_GAP_BYTES => {
+                    last.end = last.end.max(range.end)
+                }
+                _ => merged.push(range),
+            }
+        }
+
+        merged
+            .into_iter()
+            .filter(|range| self.is_playback(range))
+            .map(|range| range.end.min(self.file_len))
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn tracker_with(file_len: u64, reads: &[(u64, u64)]) -> ProgressTracker {
+        let mut tracker = ProgressTracker::new(file_len);
+        for (position, len) in reads {
+            tracker.on_seek(*position);
+            tracker.on_read(*len);
+        }
+
+        tracker
+    }
+
+    #[test]
+    fn nothing_read() {
+        let tracker = ProgressTracker::new(1000 * MB);
+        assert_eq!(tracker.committed_position(), None);
+    }
+
+    #[test]
+    fn plain_playback() {
+        let tracker = tracker_with(1000 * MB, &[(0, 300 * MB)]);
+        assert_eq!(tracker.committed_position(), Some(300 * MB));
+    }
+
+    #[test]
+    fn consecutive_reads_are_merged() {
+        let mut tracker = ProgressTracker::new(1000 * MB);
+        for _ in 0..100 {
+            tracker.on_read(64 * 1024);
+        }
+
+        // 100 small reads, 6.25 MB in total
+        assert_eq!(tracker.committed_position(), Some(100 * 64 * 1024));
+    }
+
+    #[test]
+    fn probe_only_is_ignored() {
+        // Headers, then Cues at the end of the file
+        let tracker = tracker_with(
+            1000 * MB,
+            &[(0, 256 * 1024), (1000 * MB - 300 * 1024, 300 * 1024)],
+        );
+        assert_eq!(tracker.committed_position(), None);
+    }
+
+    #[test]
+    fn tail_read_after_playback_is_ignored() {
+        let tracker = tracker_with(
+            1000 * MB,
+            &[
+                (0, 256 * 1024),
+                (1000 * MB - 300 * 1024, 300 * 1024),
+                (0, 200 * MB),
+            ],
+        );
+        assert_eq!(tracker.committed_position(), Some(200 * MB));
+    }
+
+    #[test]
+    fn big_readahead_at_the_end_is_still_a_tail_read() {
+        // mpv's cache can read the whole tail window after seeking to the index
+        let tracker = tracker_with(1000 * MB, &[(990 * MB, 10 * MB), (100 * MB, 50 * MB)]);
+        assert_eq!(tracker.committed_position(), Some(150 * MB));
+    }
+
+    #[test]
+    fn resumed_playback() {
+        // Headers, index, then resuming from the middle
+        let tracker = tracker_with(
+            1000 * MB,
+            &[
+                (0, 512 * 1024),
+                (999 * MB, MB),
+                (500 * MB, 2 * MB),
+                (502 * MB, 100 * MB),
+            ],
+        );
+        assert_eq!(tracker.committed_position(), Some(602 * MB));
+    }
+
+    #[test]
+    fn watching_till_the_end_counts() {
+        let tracker = tracker_with(1000 * MB, &[(0, MB), (900 * MB, 100 * MB)]);
+        assert_eq!(tracker.committed_position(), Some(1000 * MB));
+    }
+
+    #[test]
+    fn small_seeks_forward_stay_contiguous() {
+        // Skipping a few kilobytes, like mpv does when dropping a damaged packet
+        let tracker = tracker_with(
+            1000 * MB,
+            &[(0, 600 * 1024), (600 * 1024 + 4096, 600 * 1024)],
+        );
+        assert_eq!(tracker.committed_position(), Some(1200 * 1024 + 4096));
+    }
+
+    #[test]
+    fn scattered_small_reads_are_ignored() {
+        // Scrubbing through the file without actually watching
+        let reads: Vec<(u64, u64)> = (1..10).map(|idx| (idx * 100 * MB, 512 * 1024)).collect();
+        let tracker = tracker_with(1000 * MB, &reads);
+        assert_eq!(tracker.committed_position(), None);
+    }
+
+    #[test]
+    fn furthest_playback_wins() {
+        let tracker = tracker_with(1000 * MB, &[(400 * MB, 10 * MB), (100 * MB, 10 * MB)]);
+        assert_eq!(tracker.committed_position(), Some(410 * MB));
+    }
+
+    #[test]
+    fn start_position_skips_probing() {
+        let tracker = tracker_with(
+            1000 * MB,
+            &[
+                (0, 512 * 1024),
+                (999 * MB, MB),
+                (500 * MB, 2 * MB),
+                (502 * MB, 100 * MB),
+            ],
+        );
+        assert_eq!(tracker.start_position(), Some(500 * MB));
+        assert_eq!(tracker.bytes_read(), 512 * 1024 + 103 * MB);
+    }
+
+    #[test]
+    fn small_file_tail_window() {
+        // Whole file is within the tail window, reading it from the start is still playback
+        let tracker = tracker_with(10 * MB, &[(0, 10 * MB)]);
+        assert_eq!(tracker.committed_position(), Some(10 * MB));
+    }
+}
@@ -1,35 +1,79 @@
-use crate::{db, http};
-use anyhow::{Context, Result};
+use crate::art;
+use crate::episodes::{self, ParsedName};
+use crate::library::ItemKind;
+use crate::media::{MediaCategory, MediaTypes};
+use crate::metadata::{MediaInfo, StoredMetadata};
+use crate::nfo::NfoInfo;
+use crate::paths::Libraries;
+use crate::preferences::{ListingOptions, SortBy, SortOrder};
+use crate::servings;
+use crate::signing::{LinkSignature, UrlSigner};
+use crate::subtitles::{self, Sidecar};
+use crate::{db, http, library, metadata, nfo, paths, progress_reports};
+use anyhow::{anyhow, Context, Result};
 use log::trace;
+use rocket::futures::stream::{self, StreamExt};
 use rocket::serde::Serialize;
-use rocket_db_pools::Connection;
 use sea_orm::*;
-use std::fs;
-use std::fs::DirEntry;
-use std::path::{Path, PathBuf};
+use std::cmp::Ordering;
+use std::path::Path;
 
-use crate::db::Db;
-use db::prelude::*;
-
-static MOVIE_EXTENSIONS: &[&str] = &["mkv", "avi"];
+// Played this far counts as watched, unless marked otherwise
+const WATCHED_PERCENTAGE: i64 = 90;
+// Entries of a directory being processed at the same time
+const CONCURRENT_ENTRIES: usize = 16;
 
 #[derive(Serialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct ResultItem {
-    name: String,
+    pub name: String,
+    /// Shown instead of the name when it's recognized, like "S02E05 – Episode title"
+    pub title: Option<String>,
+    /// Series or season the item is listed under
+    group: Option<String>,
+    /// First item of its group, where the UI puts the group's header
+    starts_group: bool,
+    pub parsed: Option<ParsedName>,
     full_path: String,
-    rel_path: String,
+    pub rel_path: String,
     id: String, // Just md5 of full_path
     link: String,
+    // mpv:// link to the playlist of a directory
+    playlist_link: Option<String>,
+    category: Option<MediaCategory>,
+
+    pub progress: Option<ResultItemProgress>,
+    pub metadata: Option<MediaInfo>,
+    summary: Option<String>,
+    /// External subtitles of a movie, mpv gets them with --sub-file
+    pub subtitles: Vec<ResultSubtitle>,
+    /// /art/<id> link to the poster or cover, when there's one
+    art_link: Option<String>,
+    /// From the NFO file of the movie or the directory
+    pub nfo: Option<NfoInfo>,
+    size: i64,
+    mtime: i64,
+}
 
-    progress: Option<ResultItemProgress>,
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ResultSubtitle {
+    pub name: String,
+    pub language: Option<String>,
+    /// Signed http /files/ link
+    pub url: String,
 }
 
 #[derive(Serialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct ResultItemProgress {
     pub percentage: i64,
+    /// Marked as watched, or played far enough
+    pub watched: bool,
     pub timestamp: i64,
+    // Playback time, when the file's duration is known
+    pub position_secs: Option<f64>,
+    pub duration_secs: Option<f64>,
 }
 
 #[derive(Serialize, Debug)]
@@ -37,9 +81,13 @@ pub struct ResultItemProgress {
 pub struct ReadDirResult {
     pub dirs: Vec<ResultItem>,
     pub movies: Vec<ResultItem>,
+    /// mpv:// link to the playlist of the directory itself
+    pub playlist_link: Option<String>,
+    /// From "tvshow.nfo" or "movie.nfo" of the directory itself
+    pub nfo: Option<NfoInfo>,
 }
 
-fn get_dir_link(urlencoded_path: &str) -> String {
+pub fn get_dir_link(urlencoded_path: &str) -> String {
     let mut res = String::from("/browse");
 
     res += "/";
@@ -48,23 +96,181 @@ fn get_dir_link(urlencoded_path: &str) -> String {
     res
 }
 
-fn get_mpv_link(
+/// Link to the parent of the directory, roots of the libraries lead to the list of libraries
+pub fn get_parent_link(key: &str) -> String {
+    match key.rsplit_once('/') {
+        Some((parent, _)) => get_dir_link(&library::urlencode_key(parent)),
+        None => String::from("/"),
+    }
+}
+
+/// Signed link to /files/ or /playlist/, depending on `route`
+fn get_signed_link(
+    scheme: &str,
+    route: &str,
     urlencoded_path: &str,
     host_header: &http::HostHeader,
     user_id: &http::UserId,
+    signature: &LinkSignature,
 ) -> String {
-    let mut res = String::from("mpv://");
+    let mut res = String::from(scheme);
+    res += "://";
     res += host_header.to_string();
-    res += "/files/";
+    res += "/";
+    res += route;
+    res += "/";
 
     res += urlencoded_path;
 
     res += "?user_id=";
     res += user_id.as_str();
 
+    res += "&expires=";
+    res += &signature.expires.to_string();
+    res += "&signature=";
+    res += &signature.signature;
+
+    res
+}
+
+/// Plain http link to the file, for mpv to get from playlists
+pub fn get_file_url(
+    urlencoded_path: &str,
+    host_header: &http::HostHeader,
+    user_id: &http::UserId,
+    signature: &LinkSignature,
+) -> String {
+    get_signed_link(
+        "http",
+        "files",
+        urlencoded_path,
+        host_header,
+        user_id,
+        signature,
+    )
+}
+
+fn get_mpv_link(
+    urlencoded_path: &str,
+    host_header: &http::HostHeader,
+    user_id: &http::UserId,
+    signature: &LinkSignature,
+    start_secs: Option<u64>,
+    subtitles: &[ResultSubtitle],
+) -> String {
+    let mut res = get_signed_link(
+        "mpv",
+        "files",
+        urlencoded_path,
+        host_header,
+        user_id,
+        signature,
+    );
+
+    // Wrapper passes it to mpv as --start
+    if let Some(start_secs) = start_secs {
+        res += "&start=";
+        res += &start_secs.to_string();
+    }
+    // And these as --sub-file
+    for subtitle in subtitles {
+        res += "&sub=";
+        res += &urlencoding::encode(&subtitle.url);
+    }
+
     res
 }
 
+/// Link to the playlist of the directory, starting from the first unwatched file of the whole tree
+fn get_playlist_link(
+    urlencoded_path: &str,
+    host_header: &http::HostHeader,
+    user_id: &http::UserId,
+    signature: &LinkSignature,
+) -> String {
+    let mut res = get_signed_link(
+        "mpv",
+        "playlist",
+        urlencoded_path,
+        host_header,
+        user_id,
+        signature,
+    );
+    res += "&from_unwatched=true&recursive=true";
+
+    res
+}
+
+pub fn is_watched(progress: &Option<ResultItemProgress>) -> bool {
+    matches!(progress, Some(progress) if progress.watched)
+}
+
+/// Started, but not finished yet
+pub fn is_in_progress(progress: &Option<ResultItemProgress>) -> bool {
+    match progress {
+        Some(progress) if !progress.watched => {
+            progress.percentage > 0 || progress.position_secs.unwrap_or(0.0) >= 1.0
+        }
+        _ => false,
+    }
+}
+
+/// Which items to keep by their progress, `?watched=` of the pages
+#[derive(FromFormField, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ProgressFilter {
+    #[default]
+    All,
+    /// Not started at all
+    Unwatched,
+    #[field(value = "in_progress")]
+    InProgress,
+    Watched,
+}
+
+impl ProgressFilter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProgressFilter::All => "all",
+            ProgressFilter::Unwatched => "unwatched",
+            ProgressFilter::InProgress => "in_progress",
+            ProgressFilter::Watched => "watched",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "all" => Some(ProgressFilter::All),
+            "unwatched" => Some(ProgressFilter::Unwatched),
+            "in_progress" => Some(ProgressFilter::InProgress),
+            "watched" => Some(ProgressFilter::Watched),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, progress: &Option<ResultItemProgress>) -> bool {
+        match self {
+            ProgressFilter::All => true,
+            ProgressFilter::Unwatched => !is_watched(progress) && !is_in_progress(progress),
+            ProgressFilter::InProgress => is_in_progress(progress),
+            ProgressFilter::Watched => is_watched(progress),
+        }
+    }
+}
+
+/// Where to resume playback, unless it's not started or already finished
+fn get_resume_position(progress: &Option<ResultItemProgress>) -> Option<u64> {
+    if is_watched(progress) {
+        return None;
+    }
+    let progress = progress.as_ref()?;
+
+    match progress.position_secs {
+        Some(position) if position >= 1.0 => Some(position as u64),
+        _ => None,
+    }
+}
+
 #[derive(Debug)]
 enum FileTypes {
     File,
@@ -88,36 +294,29 @@ fn get_extension(entry_path: &Path) -> Option<String> {
     Some(String::from(ext))
 }
 
-fn get_path_properties(entry: &DirEntry, root_dir: &Path) -> Option<PathProperties> {
-    let mut root_depth = 0;
-    root_dir.iter().for_each(|_| {
-        root_depth += 1;
-    });
-    let entry_pathbuf = entry.path();
-
-    let stripped_path_chunks: Vec<&str> = entry_pathbuf
-        .iter()
-        .skip(root_depth)
-        .filter_map(|el| el.to_str())
-        .collect();
-    let rel_path = stripped_path_chunks.join("/");
+fn get_path_properties(
+    item: &db::library_items::Model,
+    libraries: &Libraries,
+) -> Option<PathProperties> {
+    let stripped_path_chunks: Vec<&str> = item.path.split('/').collect();
+    let rel_path = item.path.clone();
     let urlencoded_path_chunks: Vec<String> = stripped_path_chunks
         .iter()
         .map(|el| urlencoding::encode(el).to_string())
         .collect();
     let urlencoded_path = urlencoded_path_chunks.join("/");
 
+    let entry_pathbuf = libraries.by_key(&rel_path)?.abs_path(&rel_path)?;
     let full_path = entry_pathbuf.to_str()?.to_string();
-    let filename = entry_pathbuf.file_name()?.to_str()?.to_string();
-    let file_type = entry.file_type().ok()?;
+    let filename = stripped_path_chunks.last()?.to_string();
 
     // Unlike everything else, not getting an extension is expected
     let extension = get_extension(&entry_pathbuf);
 
     let file_type: FileTypes = {
-        if file_type.is_file() {
+        if item.kind == ItemKind::File.as_str() {
             FileTypes::File
-        } else if file_type.is_dir() {
+        } else if item.kind == ItemKind::Dir.as_str() {
             FileTypes::Dir
         } else {
             FileTypes::Other
@@ -134,118 +333,603 @@ fn get_path_properties(entry: &DirEntry, root_dir: &Path) -> Option<PathProperti
     })
 }
 
-async fn put_entry(
-    entry: &DirEntry,
-    root_dir: &Path,
-    host_header: &http::HostHeader,
-    result: &mut ReadDirResult,
-    user_id: &http::UserId,
-    conn: &DatabaseConnection,
-) -> Result<()> {
-    trace!("put_entry {:?}", entry);
+/// Per-request things listing needs, besides the directory itself
+pub struct ListingContext<'a> {
+    pub libraries: &'a Libraries,
+    pub host_header: &'a http::HostHeader,
+    pub user_id: &'a http::UserId,
+    pub media_types: &'a MediaTypes,
+    pub url_signer: &'a UrlSigner,
+    pub conn: &'a DatabaseConnection,
+}
+
+impl ListingContext<'_> {
+    fn subtitle(&self, sidecar: &Sidecar) -> ResultSubtitle {
+        let url = get_file_url(
+            &library::urlencode_key(&sidecar.key),
+            self.host_header,
+            self.user_id,
+            &self.url_signer.sign(&sidecar.key, self.user_id.as_str()),
+        );
+
+        ResultSubtitle {
+            name: String::from(sidecar.key.rsplit('/').next().unwrap_or(&sidecar.key)),
+            language: sidecar.language.clone(),
+            url,
+        }
+    }
 
-    let path_properties = get_path_properties(entry, root_dir)
+    fn playlist_link(&self, key: &str) -> String {
+        get_playlist_link(
+            &library::urlencode_key(key),
+            self.host_header,
+            self.user_id,
+            &self.url_signer.sign(key, self.user_id.as_str()),
+        )
+    }
+}
+
+/// Listing item of the entry, `None` for files that can't be played
+async fn make_entry(
+    entry: &db::library_items::Model,
+    ctx: &ListingContext<'_>,
+    stored: Option<StoredMetadata>,
+    serving: Option<db::movie_servings::Model>,
+    sidecars: Vec<Sidecar>,
+    has_art: bool,
+    nfo: Option<NfoInfo>,
+) -> Result<Option<ResultItem>> {
+    trace!("make_entry {:?}", entry);
+
+    let path_properties = get_path_properties(entry, ctx.libraries)
         .with_context(|| format!("gettint path properties of {:?} failed", entry))?;
 
-    trace!("put_entry, path_properties {:?}", path_properties);
+    trace!("make_entry, path_properties {:?}", path_properties);
 
     let entry_hash = md5::compute(path_properties.full_path.as_bytes());
     let entry_hash = format!("{:x}", entry_hash);
+    let art_link = has_art.then(|| format!("/art/{}", entry_hash));
 
     match path_properties.file_type {
         FileTypes::Dir => {
             let link = get_dir_link(&path_properties.urlencoded_path);
-            result.dirs.push(ResultItem {
+            let mut parsed = episodes::parse(&path_properties.filename);
+            let title = match &nfo {
+                Some(nfo) => nfo.apply(&mut parsed).or_else(|| parsed.title()),
+                None => parsed.title(),
+            };
+            Ok(Some(ResultItem {
                 name: path_properties.filename.clone(),
+                title,
+                group: parsed.group(),
+                starts_group: false,
+                parsed: Some(parsed),
                 full_path: path_properties.full_path.clone(),
                 rel_path: path_properties.rel_path.clone(),
                 id: entry_hash,
                 link,
+                playlist_link: Some(ctx.playlist_link(&entry.path)),
+                category: None,
                 progress: None,
-            })
+                metadata: None,
+                summary: None,
+                subtitles: Vec::new(),
+                art_link,
+                nfo,
+                size: entry.size,
+                mtime: entry.mtime,
+            }))
         }
         FileTypes::File => {
-            if let Some(ext) = path_properties.extension {
-                if !MOVIE_EXTENSIONS.contains(&ext.as_str()) {
-                    return Ok(());
-                }
-                let link = get_mpv_link(&path_properties.urlencoded_path, host_header, user_id);
-
-                let progress =
-                    get_item_progress(&path_properties.urlencoded_path, user_id, conn).await;
-
-                result.movies.push(ResultItem {
+            if let Some(ext) = &path_properties.extension {
+                let category = match ctx.media_types.category_by_extension(ext) {
+                    Some(category) if category.is_playable() => category,
+                    _ => return Ok(None),
+                };
+                let progress = match serving {
+                    Some(serving) => {
+                        Some(get_item_progress(&path_properties, &serving, stored.as_ref()).await)
+                    }
+                    None => None,
+                };
+                let metadata = stored.map(|stored| stored.info);
+
+                let subtitles: Vec<ResultSubtitle> =
+                    sidecars.iter().map(|el| ctx.subtitle(el)).collect();
+                let link = get_mpv_link(
+                    &path_properties.urlencoded_path,
+                    ctx.host_header,
+                    ctx.user_id,
+                    &ctx.url_signer.sign(&entry.path, ctx.user_id.as_str()),
+                    get_resume_position(&progress),
+                    &subtitles,
+                );
+
+                let stem = Path::new(&path_properties.filename)
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .unwrap_or(&path_properties.filename);
+                let mut parsed = episodes::parse(stem);
+                let title = match &nfo {
+                    Some(nfo) => nfo.apply(&mut parsed).or_else(|| parsed.title()),
+                    None => parsed.title(),
+                };
+
+                Ok(Some(ResultItem {
                     name: path_properties.filename.clone(),
+                    title,
+                    group: parsed.group(),
+                    starts_group: false,
+                    parsed: Some(parsed),
                     full_path: path_properties.full_path.clone(),
                     rel_path: path_properties.rel_path.clone(),
                     id: entry_hash,
                     link,
+                    playlist_link: None,
+                    category: Some(category),
                     progress,
-                });
+                    summary: metadata.as_ref().map(|info| info.summary()),
+                    metadata,
+                    subtitles,
+                    art_link,
+                    nfo,
+                    size: entry.size,
+                    mtime: entry.mtime,
+                }))
             } else {
-                return Ok(());
+                Ok(None)
             }
         }
-        _ => {}
+        _ => Ok(None),
     }
-
-    Ok(())
 }
 
 async fn get_item_progress(
-    urlencoded_path: &str,
-    user_id: &http::UserId,
-    conn: &DatabaseConnection,
-) -> Option<ResultItemProgress> {
-    let path = String::from(urlencoded_path) + "?" + user_id.to_string();
-    match MovieServing::find_by_id(path.clone()).one(conn).await {
-        Ok(Some(serve_model)) => Some(ResultItemProgress {
-            percentage: serve_model.last_file_position * 100 / serve_model.file_length,
-            timestamp: serve_model.last_timestamp,
-        }),
-        Ok(None) => {
-            log::debug!("No progress found for {}", &path);
-            None
+    path_properties: &PathProperties,
+    serve_model: &db::movie_servings::Model,
+    stored: Option<&StoredMetadata>,
+) -> ResultItemProgress {
+    let duration_secs = stored.and_then(|stored| stored.info.duration_secs);
+    let (position_secs, duration_secs) = match progress_reports::current_report(serve_model) {
+        Some((position, reported_duration)) => {
+            (Some(position), reported_duration.or(duration_secs))
+        }
+        None => (
+            get_tracked_position(path_properties, serve_model, duration_secs, stored).await,
+            duration_secs,
+        ),
+    };
+
+    let percentage = match (position_secs, duration_secs) {
+        (Some(position), Some(duration)) if duration > 0.0 => {
+            ((position * 100.0 / duration) as i64).min(100)
+        }
+        _ if serve_model.file_length > 0 => {
+            serve_model.last_file_position * 100 / serve_model.file_length
         }
+        _ => 0,
+    };
+
+    // Marks win over whatever was played before them
+    let (percentage, position_secs, watched) = match serve_model.watched {
+        Some(true) => (100, position_secs, true),
+        Some(false) => (0, None, false),
+        None => (percentage, position_secs, percentage >= WATCHED_PERCENTAGE),
+    };
+
+    let timestamp = serve_model
+        .last_timestamp
+        .max(serve_model.reported_timestamp.unwrap_or(0));
+
+    ResultItemProgress {
+        percentage,
+        watched,
+        timestamp,
+        position_secs,
+        duration_secs,
+    }
+}
+
+/// Playback position guessed from the bytes served
+async fn get_tracked_position(
+    path_properties: &PathProperties,
+    serve_model: &db::movie_servings::Model,
+    duration_secs: Option<f64>,
+    stored: Option<&StoredMetadata>,
+) -> Option<f64> {
+    let seek_index = match stored {
+        Some(stored) => stored.seek_index().await,
+        None => Ok(None),
+    };
+    let seek_index = match seek_index {
+        Ok(seek_index) => seek_index,
         Err(e) => {
-            log::debug!("No progress found for {}, {:?}", &path, e);
+            log::warn!(
+                "Failed to load seek index of {}: {:?}",
+                &path_properties.rel_path,
+                e
+            );
             None
         }
-    }
+    };
+
+    let offset = u64::try_from(serve_model.last_file_position).unwrap_or(0);
+    let file_len = u64::try_from(serve_model.file_length).unwrap_or(0);
+
+    metadata::playback_position(offset, file_len, duration_secs, seek_index.as_ref())
 }
 
-pub async fn read_dir(
-    dir: &PathBuf,
-    root_dir: &Path,
-    host_header: &http::HostHeader,
-    user_id: &http::UserId,
-    conn: &Connection<Db>,
-) -> Result<ReadDirResult> {
+pub async fn read_dir(dir: &Path, ctx: &ListingContext<'_>) -> Result<ReadDirResult> {
+    let conn = ctx.conn;
+    let rel_dir = paths::rel_path_to_key(dir)
+        .map_err(|err| anyhow!("invalid directory {:?}: {}", dir, err))?;
+    if !library::dir_exists(&rel_dir, conn).await? {
+        return Err(anyhow!(
+            "directory {:?} is not found in the library",
+            &rel_dir
+        ));
+    }
+
+    let entries = library::list_dir(&rel_dir, conn)
+        .await
+        .with_context(|| format!("failed to read dir {:?}", &rel_dir))?;
+    // Posters and NFO files of the subdirectories are listed with them
+    let subdirs: Vec<String> = entries
+        .iter()
+        .filter(|el| el.kind == ItemKind::Dir.as_str())
+        .map(|el| el.path.clone())
+        .collect();
+    let subdir_items = library::list_dirs(&subdirs, conn)
+        .await
+        .with_context(|| format!("failed to read subdirectories of {:?}", &rel_dir))?;
+
+    let nfo = nfo::load_for_listing(&entries, &subdir_items, ctx.libraries, conn)
+        .await
+        .with_context(|| format!("failed to load NFO files of {:?}", &rel_dir))?;
+
     let mut res = ReadDirResult {
         dirs: Vec::new(),
         movies: Vec::new(),
+        playlist_link: Some(ctx.playlist_link(&rel_dir)),
+        nfo: nfo.of_dir(&rel_dir).cloned(),
     };
 
-    let read_dir_res =
-        fs::read_dir(dir).with_context(|| format!("failed to read dir {:?}", &dir))?;
+    let mut metadata = metadata::load(entries.iter().map(|el| el.path.clone()).collect(), conn)
+        .await
+        .with_context(|| format!("failed to load metadata for {:?}", &rel_dir))?;
 
-    for entry in read_dir_res {
-        match entry {
-            Ok(entry) => {
-                put_entry(&entry, root_dir, host_header, &mut res, user_id, conn)
-                    .await
-                    .with_context(|| format!("failed to process entry {:?}", &entry))?;
-            }
-            Err(err) => {
-                println!("Error while iterating over directory: {}", err);
-            }
+    let file_keys: Vec<String> = entries
+        .iter()
+        .filter(|el| el.kind == ItemKind::File.as_str())
+        .map(|el| el.path.clone())
+        .collect();
+    let mut servings = servings::find_for_items(ctx.user_id.as_str(), &file_keys, conn)
+        .await
+        .with_context(|| format!("failed to load progress for {:?}", &rel_dir))?;
+
+    let mut sidecars = subtitles::find_sidecars(&rel_dir, &entries, ctx.media_types, conn)
+        .await
+        .with_context(|| format!("failed to find subtitles in {:?}", &rel_dir))?;
+
+    let art_sources = art::find_sources(&entries, &subdir_items, &metadata);
+
+    let pending: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            let entry_nfo = if entry.kind == ItemKind::Dir.as_str() {
+                nfo.of_dir(&entry.path)
+            } else {
+                nfo.of_file(&entry.path)
+            };
+            make_entry(
+                entry,
+                ctx,
+                metadata.remove(&entry.path),
+                servings.remove(&entry.path),
+                sidecars.remove(&entry.path).unwrap_or_default(),
+                art_sources.contains_key(&entry.path),
+                entry_nfo.cloned(),
+            )
+        })
+        .collect();
+    // Seek indexes are parsed on blocking threads, large directories are better off not waiting for each
+    let items: Vec<Result<Option<ResultItem>>> = stream::iter(pending)
+        .buffered(CONCURRENT_ENTRIES)
+        .collect()
+        .await;
+
+    for (entry, item) in entries.iter().zip(items) {
+        let item = item.with_context(|| format!("failed to process entry {:?}", entry))?;
+
+        match item {
+            // Only playable files have a category
+            Some(item) if item.category.is_some() => res.movies.push(item),
+            Some(item) => res.dirs.push(item),
+            None => {}
         }
     }
 
-    let comparator = |a: &ResultItem, b: &ResultItem| a.name.to_string().cmp(&b.name.to_string());
+    // Only what's listed, /art/<id> has nothing to serve for the rest
+    let listed_art: Vec<db::artwork::Model> = res
+        .dirs
+        .iter()
+        .chain(res.movies.iter())
+        .filter_map(|item| {
+            Some(db::artwork::Model {
+                id: item.id.clone(),
+                item: item.rel_path.clone(),
+                source: art_sources.get(&item.rel_path)?.clone(),
+            })
+        })
+        .collect();
+    // Listing is still good without art
+    if let Err(e) = art::register(&listed_art, conn).await {
+        log::warn!("Failed to register art of {:?}: {:?}", &rel_dir, e);
+    }
 
-    res.dirs.sort_by(comparator);
-    res.movies.sort_by(comparator);
+    sort_and_group(&mut res.dirs);
+    sort_and_group(&mut res.movies);
 
     Ok(res)
 }
+
+/// Marks where each series or season starts, in the order the items are in
+fn mark_groups(items: &mut [ResultItem]) {
+    let mut previous_group = None;
+    for item in items.iter_mut() {
+        item.starts_group = item.group.is_some() && item.group != previous_group;
+        previous_group = item.group.clone();
+    }
+}
+
+/// Puts episodes in order, marking where each series or season starts
+fn sort_and_group(items: &mut [ResultItem]) {
+    let no_parsed = ParsedName::default();
+    items.sort_by(|a, b| {
+        episodes::compare(
+            a.parsed.as_ref().unwrap_or(&no_parsed),
+            &a.name,
+            b.parsed.as_ref().unwrap_or(&no_parsed),
+            &b.name,
+        )
+    });
+
+    mark_groups(items);
+}
+
+/// What items are sorted by, other than the name. `None` goes last in either order
+fn sort_value(item: &ResultItem, sort: SortBy) -> Option<f64> {
+    match sort {
+        SortBy::Name => None,
+        SortBy::Mtime => Some(item.mtime as f64),
+        SortBy::Size => Some(item.size as f64),
+        SortBy::Duration => item.metadata.as_ref()?.duration_secs,
+        SortBy::LastWatched => Some(item.progress.as_ref()?.timestamp as f64),
+    }
+}
+
+fn sort_items(items: &mut [ResultItem], sort: SortBy, order: SortOrder) {
+    if sort == SortBy::Name {
+        sort_and_group(items);
+        if order == SortOrder::Desc {
+            items.reverse();
+            mark_groups(items);
+        }
+        return;
+    }
+
+    items.sort_by(|a, b| {
+        let ordering = match (sort_value(a, sort), sort_value(b, sort)) {
+            (Some(a_value), Some(b_value)) => {
+                let ordering = a_value.partial_cmp(&b_value).unwrap_or(Ordering::Equal);
+                match order {
+                    SortOrder::Asc => ordering,
+                    SortOrder::Desc => ordering.reverse(),
+                }
+            }
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        ordering.then_with(|| episodes::natural_cmp(&a.name, &b.name))
+    });
+
+    // Series headers only make sense in episode order
+    for item in items.iter_mut() {
+        item.starts_group = false;
+    }
+}
+
+/// Filters and sorts the listing the way the user wants it. Directories have no size, duration
+/// or progress of their own, so those keep them sorted by name
+pub fn arrange(listing: &mut ReadDirResult, options: &ListingOptions) {
+    listing
+        .movies
+        .retain(|item| options.watched.matches(&item.progress));
+    sort_items(&mut listing.movies, options.sort, options.order);
+
+    match options.sort {
+        SortBy::Name | SortBy::Mtime => sort_items(&mut listing.dirs, options.sort, options.order),
+        _ => sort_and_group(&mut listing.dirs),
+    }
+}
+
+/// Roots of the libraries, for the top-level page
+pub fn list_libraries(libraries: &Libraries) -> ReadDirResult {
+    let dirs = libraries
+        .iter()
+        .map(|library| {
+            let full_path = library.root().to_string_lossy().into_owned();
+            let urlencoded_path = library::urlencode_key(library.name());
+
+            ResultItem {
+                name: String::from(library.name()),
+                title: None,
+                group: None,
+                starts_group: false,
+                parsed: None,
+                id: format!("{:x}", md5::compute(full_path.as_bytes())),
+                full_path,
+                rel_path: String::from(library.name()),
+                link: get_dir_link(&urlencoded_path),
+                playlist_link: None,
+                category: None,
+                progress: None,
+                metadata: None,
+                summary: None,
+                subtitles: Vec::new(),
+                art_link: None,
+                nfo: None,
+                size: 0,
+                mtime: 0,
+            }
+        })
+        .collect();
+
+    ReadDirResult {
+        dirs,
+        movies: Vec::new(),
+        playlist_link: None,
+        nfo: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::MediaConfig;
+    use crate::metadata::SeekIndex;
+    use crate::servings::ServingKey;
+    use crate::signing::LinksConfig;
+    use crate::test_fixtures::{self, TempLibrary};
+    use rocket::serde::json;
+    use sea_orm::ActiveValue::Set;
+    use std::time::Instant;
+
+    const FILES: usize = 500;
+
+    /// "lib/Show" with partially watched episodes, each with a seek index, keys of them in order
+    async fn large_dir(conn: &DatabaseConnection) -> Vec<String> {
+        let item = |path: &str, parent: &str, kind: ItemKind| db::library_items::ActiveModel {
+            path: Set(String::from(path)),
+            parent: Set(String::from(parent)),
+            size: Set(1_000_000_000),
+            mtime: Set(0),
+            kind: Set(String::from(kind.as_str())),
+        };
+        item("lib/Show", "lib", ItemKind::Dir)
+            .insert(conn)
+            .await
+            .unwrap();
+
+        let info = json::to_string(&MediaInfo {
+            container: String::from("matroska"),
+            duration_secs: Some(1800.0),
+            ..Default::default()
+        })
+        .unwrap();
+        let seek_index = SeekIndex::from_points(
+            (0..100)
+                .map(|idx| (idx * 10_000_000, idx as f64 * 18.0))
+                .collect(),
+        );
+        let seek_index = json::to_string(&seek_index).unwrap();
+
+        let mut keys = Vec::new();
+        for idx in 0..FILES {
+            let key = format!("lib/Show/Episode {:03}.mkv", idx);
+            item(&key, "lib/Show", ItemKind::File)
+                .insert(conn)
+                .await
+                .unwrap();
+            db::media_metadata::ActiveModel {
+                path: Set(key.clone()),
+                size: Set(1_000_000_000),
+                mtime: Set(0),
+                info: Set(info.clone()),
+                seek_index: Set(Some(seek_index.clone())),
+            }
+            .insert(conn)
+            .await
+            .unwrap();
+            test_fixtures::serving(&key, "user", idx as i64 * 1_000_000, 1_000_000_000)
+                .insert(conn)
+                .await
+                .unwrap();
+            keys.push(key);
+        }
+
+        keys
+    }
+
+    #[rocket::async_test]
+    async fn lists_progress_of_large_directories() {
+        let temp = TempLibrary::new();
+        let links_config = LinksConfig {
+            signing_key: Some(String::from("secret")),
+            ..Default::default()
+        };
+        let url_signer = UrlSigner::from_config(&links_config, temp.root()).unwrap();
+        let media_types = MediaTypes::from_config(&MediaConfig::default());
+        let conn = test_fixtures::connect().await;
+        large_dir(&conn).await;
+
+        let host_header = http::HostHeader::new("localhost:8000");
+        let user_id = http::UserId::new(String::from("user"));
+        let ctx = ListingContext {
+            libraries: &temp.libraries,
+            host_header: &host_header,
+            user_id: &user_id,
+            media_types: &media_types,
+            url_signer: &url_signer,
+            conn: &conn,
+        };
+
+        let listed = read_dir(Path::new("lib/Show"), &ctx).await.unwrap();
+        assert_eq!(listed.movies.len(), FILES);
+        // Entries are processed concurrently, but listed in order
+        let episode = &listed.movies[250];
+        assert_eq!(episode.name, "Episode 250.mkv");
+        assert_eq!(
+            episode
+                .progress
+                .as_ref()
+                .and_then(|progress| progress.position_secs),
+            Some(450.0)
+        );
+    }
+
+    /// Progress of a large directory, looked up one file at a time as listings used to,
+    /// and with one `IN (...)` query as they do now
+    #[rocket::async_test]
+    async fn batched_progress_lookup_beats_per_item_queries() {
+        let conn = test_fixtures::connect().await;
+        let keys = large_dir(&conn).await;
+
+        let started = Instant::now();
+        let mut per_item = 0;
+        for key in keys.iter() {
+            let serving = servings::find(&ServingKey::new(key, "user"), &conn)
+                .await
+                .unwrap();
+            per_item += usize::from(serving.is_some());
+        }
+        let per_item_elapsed = started.elapsed();
+
+        let started = Instant::now();
+        let batched = servings::find_for_items("user", &keys, &conn)
+            .await
+            .unwrap();
+        let batched_elapsed = started.elapsed();
+
+        assert_eq!(per_item, FILES);
+        assert_eq!(batched.len(), FILES);
+        assert!(
+            batched_elapsed < per_item_elapsed,
+            "{} files: batched lookup took {:?}, per-item lookups {:?}",
+            FILES,
+            batched_elapsed,
+            per_item_elapsed
+        );
+    }
+}
@@ -0,0 +1,432 @@
+use crate::db;
+use crate::episodes;
+use crate::library::{self, ItemKind};
+use crate::media::{MediaCategory, MediaTypes};
+use crate::nfo::{self, ListingNfo};
+use crate::reading_dirs::{self, ListingContext, ProgressFilter, ResultItem};
+use crate::servings;
+use anyhow::{Context, Result};
+use log::debug;
+use rocket::futures::stream::{self, StreamExt};
+use rocket::serde::Serialize;
+use sea_orm::sea_query::{Condition, Expr, Func, Iden, LikeExpr, Query, SimpleExpr};
+use sea_orm::*;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use db::prelude::*;
+
+/*
+Search across the libraries. Names, titles and paths of the indexed items are kept in `search_index`:
+an FTS5 table on SQLite, a plain one searched with LIKE on other databases. The scan and the watcher rebuild
+the rows of what they went through, after importing NFO files, so titles from those are searchable as well.
+Matches are listed the same way as browsing their directories, with the same progress and links.
+*/
+
+const INSERT_CHUNK_SIZE: usize = db::insert_chunk_size(6);
+// Directories of the matches are read one by one, so there's only so many of them
+const MAX_MATCHES: usize = 100;
+// Matching rows are fetched this many at a time, until enough of them pass the filters
+const PAGE_SIZE: u64 = 200;
+const CONCURRENT_DIRS: usize = 4;
+// Weights of key, kind, ext, name, title and path: titles are what's shown, paths match a lot
+const FTS_RANK: &str = "bm25(search_index, 0.0, 0.0, 0.0, 5.0, 10.0, 1.0)";
+
+#[derive(Iden)]
+enum SearchIndex {
+    Table,
+    Key,
+    Kind,
+    Ext,
+    Name,
+    Title,
+    Path,
+}
+
+#[derive(FromFormField, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum SearchType {
+    Video,
+    Audio,
+    Dir,
+}
+
+/// Query string of /search and /api/search
+#[derive(FromForm, Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SearchQuery {
+    pub q: Option<String>,
+    #[field(name = "type")]
+    #[serde(rename = "type")]
+    pub item_type: Option<SearchType>,
+    /// Only files with this progress
+    pub watched: Option<ProgressFilter>,
+    /// Key of the directory to search in
+    pub dir: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SearchItem {
+    #[serde(flatten)]
+    pub item: ResultItem,
+    pub is_dir: bool,
+    /// Where the item is, since results come from all over the libraries
+    pub dir: String,
+    pub dir_link: String,
+}
+
+/// Row of the item: its key, kind, lowercase extension, name, titles and path.
+/// Titles are the one it's listed under, plus the original and the show's title from its NFO
+fn index_row(item: &db::library_items::Model, nfo: &ListingNfo) -> [SimpleExpr; 6] {
+    let name = library::name_of(&item.path);
+    let is_dir = item.kind == ItemKind::Dir.as_str();
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !is_dir => (stem, ext.to_lowercase()),
+        _ => (name, String::new()),
+    };
+
+    let mut parsed = episodes::parse(stem);
+    let info = if is_dir {
+        nfo.of_dir(&item.path)
+    } else {
+        nfo.of_file(&item.path)
+    };
+    let mut titles = Vec::new();
+    match info {
+        Some(info) => {
+            titles.extend(info.apply(&mut parsed).or_else(|| parsed.title()));
+            titles.extend(info.original_title.clone());
+            titles.extend(info.show_title.clone());
+        }
+        None => titles.extend(parsed.title()),
+    }
+
+    [
+        item.path.clone().into(),
+        item.kind.clone().into(),
+        ext.into(),
+        String::from(name).into(),
+        titles.join(" ").into(),
+        item.path.clone().into(),
+    ]
+}
+
+/// Removes the rows of `scope`, everything when it's empty
+async fn remove<C: ConnectionTrait>(scope: &str, conn: &C) -> Result<()> {
+    let backend = conn.get_database_backend();
+    if scope.is_empty() {
+        let delete = Query::delete().from_table(SearchIndex::Table).to_owned();
+        conn.execute(backend.build(&delete)).await?;
+        return Ok(());
+    }
+
+    // LIKE ignores case on SQLite, so the keys are checked before deleting
+    let pattern = db::escape_like(scope) + "%";
+    let select = Query::select()
+        .column(SearchIndex::Key)
+        .from(SearchIndex::Table)
+        .and_where(Expr::col(SearchIndex::Key).like(LikeExpr::str(&pattern).escape('\\')))
+        .to_owned();
+    let mut keys = Vec::new();
+    for row in conn.query_all(backend.build(&select)).await? {
+        let key: String = row.try_get("", &SearchIndex::Key.to_string())?;
+        if library::is_same_or_descendant(&key, scope) {
+            keys.push(key);
+        }
+    }
+
+    for chunk in keys.chunks(db::LOOKUP_CHUNK_SIZE) {
+        let delete = Query::delete()
+            .from_table(SearchIndex::Table)
+            .and_where(Expr::col(SearchIndex::Key).is_in(chunk.iter().cloned()))
+            .to_owned();
+        conn.execute(backend.build(&delete)).await?;
+    }
+
+    Ok(())
+}
+
+/// Rebuilds the rows of `scope` (index key of a subtree, empty string for everything) from the library index
+/// and NFO info stored for it. Returns the number of rows written
+pub async fn reindex(scope: &str, conn: &DatabaseConnection) -> Result<usize> {
+    let mut query = LibraryItem::find();
+    if !scope.is_empty() {
+        query = query.filter(db::library_items::Column::Path.starts_with(scope));
+    }
+    let items: Vec<db::library_items::Model> = query
+        .all(conn)
+        .await?
+        .into_iter()
+        .filter(|item| scope.is_empty() || library::is_same_or_descendant(&item.path, scope))
+        // Roots of the libraries are on the home page anyway
+        .filter(|item| !item.parent.is_empty())
+        .collect();
+    let nfo = nfo::load_for_items(&items, conn).await?;
+
+    let backend = conn.get_database_backend();
+    let txn = conn.begin().await?;
+    remove(scope, &txn).await?;
+    for chunk in items.chunks(INSERT_CHUNK_SIZE) {
+        let mut insert = Query::insert();
+        insert.into_table(SearchIndex::Table).columns([
+            SearchIndex::Key,
+            SearchIndex::Kind,
+            SearchIndex::Ext,
+            SearchIndex::Name,
+            SearchIndex::Title,
+            SearchIndex::Path,
+        ]);
+        for item in chunk {
+            insert.values(index_row(item, &nfo))?;
+        }
+        txn.execute(backend.build(&insert)).await?;
+    }
+    txn.commit().await?;
+
+    Ok(items.len())
+}
+
+/// Words of the query, leaving out the ones with nothing to search for, like "-"
+fn terms(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .filter(|term| term.chars().any(|ch| ch.is_alphanumeric()))
+        .map(String::from)
+        .collect()
+}
+
+/// FTS5 query matching rows with all the terms, each as a word prefix. Terms are quoted,
+/// so whatever they have is taken literally rather than as query syntax
+fn fts_query(terms: &[String]) -> String {
+    let phrases: Vec<String> = terms
+        .iter()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+
+    phrases.join(" ")
+}
+
+fn matching(backend: DbBackend, terms: &[String]) -> Condition {
+    if backend == DbBackend::Sqlite {
+        return Condition::all().add(Expr::cust_with_values(
+            "search_index MATCH ?",
+            [fts_query(terms)],
+        ));
+    }
+
+    // Every term has to be somewhere
+    let mut condition = Condition::all();
+    for term in terms {
+        let pattern = format!("%{}%", db::escape_like(&term.to_lowercase()));
+        let mut any_column = Condition::any();
+        for column in [SearchIndex::Name, SearchIndex::Title, SearchIndex::Path] {
+            any_column = any_column.add(
+                Expr::expr(Func::lower(Expr::col(column)))
+                    .like(LikeExpr::str(&pattern).escape('\\')),
+            );
+        }
+        condition = condition.add(any_column);
+    }
+
+    condition
+}
+
+/// Directories and playable files, or only ones of `item_type`
+fn of_type(item_type: Option<SearchType>, media_types: &MediaTypes) -> Condition {
+    let dirs = Expr::col(SearchIndex::Kind).eq(ItemKind::Dir.as_str());
+    let files_of = |categories: &[MediaCategory]| {
+        let extensions: Vec<String> = categories
+            .iter()
+            .flat_map(|category| media_types.extensions(*category))
+            .collect();
+        Condition::all()
+            .add(Expr::col(SearchIndex::Kind).eq(ItemKind::File.as_str()))
+            .add(Expr::col(SearchIndex::Ext).is_in(extensions))
+    };
+
+    match item_type {
+        None => Condition::any()
+            .add(dirs)
+            .add(files_of(&[MediaCategory::Video, MediaCategory::Audio])),
+        Some(SearchType::Dir) => Condition::all().add(dirs),
+        Some(SearchType::Video) => files_of(&[MediaCategory::Video]),
+        Some(SearchType::Audio) => files_of(&[MediaCategory::Audio]),
+    }
+}
+
+/// Keys of a page of the matching items, best matches first, along with the number of rows in the page
+async fn find_keys(
+    terms: &[String],
+    query: &SearchQuery,
+    media_types: &MediaTypes,
+    offset: u64,
+    conn: &DatabaseConnection,
+) -> Result<(Vec<String>, usize)> {
+    let backend = conn.get_database_backend();
+    let dir = query.dir.as_deref().filter(|dir| !dir.is_empty());
+
+    let mut condition = Condition::all()
+        .add(matching(backend, terms))
+        .add(of_type(query.item_type, media_types));
+    if let Some(dir) = dir {
+        condition = condition.add(Expr::col(SearchIndex::Key).like(format!("{}/%", dir)));
+    }
+
+    let mut select = Query::select();
+    select
+        .column(SearchIndex::Key)
+        .from(SearchIndex::Table)
+        .cond_where(condition)
+        .limit(PAGE_SIZE)
+        .offset(offset);
+    if backend == DbBackend::Sqlite {
+        select.order_by_expr(Expr::cust(FTS_RANK), Order::Asc);
+    } else {
+        select.order_by(SearchIndex::Title, Order::Asc);
+    }
+
+    let rows = conn.query_all(backend.build(&select)).await?;
+    let row_count = rows.len();
+    let mut keys = Vec::new();
+    for row in rows {
+        let key: String = row.try_get("", &SearchIndex::Key.to_string())?;
+        let in_dir = match dir {
+            Some(dir) => key != dir && library::is_same_or_descendant(&key, dir),
+            None => true,
+        };
+        if in_dir {
+            keys.push(key);
+        }
+    }
+
+    Ok((keys, row_count))
+}
+
+/// Lists the directories, adding their items to `found` by key
+async fn list_dirs(
+    dirs: &[String],
+    ctx: &ListingContext<'_>,
+    found: &mut HashMap<String, (ResultItem, bool)>,
+) {
+    let pending: Vec<_> = dirs
+        .iter()
+        .map(|dir| async move { (dir, reading_dirs::read_dir(Path::new(dir), ctx).await) })
+        .collect();
+    let listings: Vec<_> = stream::iter(pending)
+        .buffered(CONCURRENT_DIRS)
+        .collect()
+        .await;
+
+    for (dir, listing) in listings {
+        let listing = match listing {
+            Ok(listing) => listing,
+            Err(err) => {
+                // Index may be a bit behind the disk
+                debug!("Skipping matches in {:?}: {:#}", dir, err);
+                continue;
+            }
+        };
+        let dirs = listing.dirs.into_iter().map(|item| (item, true));
+        let files = listing.movies.into_iter().map(|item| (item, false));
+        for (item, is_dir) in dirs.chain(files) {
+            found.insert(item.rel_path.clone(), (item, is_dir));
+        }
+    }
+}
+
+/// Items matching all the words of the query, best matches first
+pub async fn search(query: &SearchQuery, ctx: &ListingContext<'_>) -> Result<Vec<SearchItem>> {
+    let text = query.q.as_deref().unwrap_or_default();
+    let terms = terms(text);
+    if terms.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Only files someone has played can be in progress or watched
+    let needs_serving = matches!(
+        query.watched,
+        Some(ProgressFilter::InProgress | ProgressFilter::Watched)
+    );
+
+    let mut matches = Vec::new();
+    let mut listed_dirs = HashSet::new();
+    let mut found: HashMap<String, (ResultItem, bool)> = HashMap::new();
+    let mut offset = 0;
+    while matches.len() < MAX_MATCHES {
+        let (mut keys, row_count) = find_keys(&terms, query, ctx.media_types, offset, ctx.conn)
+            .await
+            .with_context(|| format!("failed to search for {:?}", text))?;
+        if needs_serving {
+            let servings = servings::find_for_items(ctx.user_id.as_str(), &keys, ctx.conn)
+                .await
+                .with_context(|| format!("failed to load progress of matches for {:?}", text))?;
+            keys.retain(|key| servings.contains_key(key));
+        }
+
+        let dirs: Vec<String> = keys
+            .iter()
+            .map(|key| library::parent_key(key))
+            .filter(|dir| listed_dirs.insert(dir.clone()))
+            .collect();
+        list_dirs(&dirs, ctx, &mut found).await;
+
+        matches.extend(
+            keys.iter()
+                .filter_map(|key| found.remove(key))
+                .filter(|(item, is_dir)| match query.watched {
+                    None | Some(ProgressFilter::All) => true,
+                    // Directories have no progress of their own
+                    Some(filter) => !is_dir && filter.matches(&item.progress),
+                }),
+        );
+
+        if (row_count as u64) < PAGE_SIZE {
+            break;
+        }
+        offset += PAGE_SIZE;
+    }
+    matches.truncate(MAX_MATCHES);
+
+    Ok(matches
+        .into_iter()
+        .map(|(item, is_dir)| {
+            let dir = library::parent_key(&item.rel_path);
+            let dir_link = reading_dirs::get_dir_link(&library::urlencode_key(&dir));
+            SearchItem {
+                item,
+                is_dir,
+                dir,
+                dir_link,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::sea_query::PostgresQueryBuilder;
+
+    #[test]
+    fn makes_fts_queries() {
+        let terms = terms("  the \"office\" - s02e01 ");
+        assert_eq!(terms, vec!["the", "\"office\"", "s02e01"]);
+        assert_eq!(fts_query(&terms), r#""the"* """office"""* "s02e01"*"#);
+    }
+
+    #[test]
+    fn escapes_like_patterns() {
+        let terms = terms("100% my_show");
+        let (query, values) = Query::select()
+            .column(SearchIndex::Key)
+            .from(SearchIndex::Table)
+            .cond_where(matching(DbBackend::Postgres, &terms))
+            .build(PostgresQueryBuilder);
+        assert!(query.contains("LIKE $1 ESCAPE"), "{}", query);
+        let patterns: Vec<Value> = ["%100\\%%", "%my\\_show%"]
+            .iter()
+            .flat_map(|pattern| vec![Value::from(*pattern); 3])
+            .collect();
+        assert_eq!(values.0, patterns);
+    }
+}
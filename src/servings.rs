@@ -0,0 +1,232 @@
+use crate::db;
+use crate::library;
+use anyhow::Result;
+use log::debug;
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::*;
+use std::collections::HashMap;
+
+use db::prelude::*;
+
+/*
+Progress of a file for a user lives in `movie_servings`, one row per user and file.
+Rows are identified by the user, the library and the path in it, always through ServingKey.
+*/
+
+/// Which file's progress, for which user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServingKey {
+    pub user_id: String,
+    pub library_id: String,
+    pub item_path: String,
+}
+
+impl ServingKey {
+    /// Key of the library item `key` for the user
+    pub fn new(key: &str, user_id: &str) -> Self {
+        let (library_id, item_path) = split_key(key);
+
+        ServingKey {
+            user_id: String::from(user_id),
+            library_id: String::from(library_id),
+            item_path: String::from(item_path),
+        }
+    }
+
+    pub fn of(serving: &db::movie_servings::Model) -> Self {
+        ServingKey {
+            user_id: serving.user_id.clone(),
+            library_id: serving.library_id.clone(),
+            item_path: serving.item_path.clone(),
+        }
+    }
+
+    fn condition(&self) -> Condition {
+        Condition::all()
+            .add(db::movie_servings::Column::UserId.eq(self.user_id.as_str()))
+            .add(db::movie_servings::Column::LibraryId.eq(self.library_id.as_str()))
+            .add(db::movie_servings::Column::ItemPath.eq(self.item_path.as_str()))
+    }
+
+    /// New row for the key, the rest is up to the caller
+    pub fn active_model(&self) -> db::movie_servings::ActiveModel {
+        db::movie_servings::ActiveModel {
+            user_id: Set(self.user_id.clone()),
+            library_id: Set(self.library_id.clone()),
+            item_path: Set(self.item_path.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Library name and the path in it, the root of the library has an empty path
+fn split_key(key: &str) -> (&str, &str) {
+    key.split_once('/').unwrap_or((key, ""))
+}
+
+fn join_key(library_id: &str, item_path: &str) -> String {
+    if item_path.is_empty() {
+        String::from(library_id)
+    } else {
+        String::from(library_id) + "/" + item_path
+    }
+}
+
+/// Library key of the serving's file
+pub fn item_key(serving: &db::movie_servings::Model) -> String {
+    join_key(&serving.library_id, &serving.item_path)
+}
+
+pub async fn find<C: ConnectionTrait>(
+    key: &ServingKey,
+    conn: &C,
+) -> Result<Option<db::movie_servings::Model>> {
+    Ok(MovieServing::find()
+        .filter(key.condition())
+        .one(conn)
+        .await?)
+}
+
+pub async fn delete<C: ConnectionTrait>(key: &ServingKey, conn: &C) -> Result<()> {
+    MovieServing::delete_many()
+        .filter(key.condition())
+        .exec(conn)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn find_by_user<C: ConnectionTrait>(
+    user_id: &str,
+    conn: &C,
+) -> Result<Vec<db::movie_servings::Model>> {
+    Ok(MovieServing::find()
+        .filter(db::movie_servings::Column::UserId.eq(user_id))
+        .all(conn)
+        .await?)
+}
+
+/// Servings of the user for the files, by their library keys. Files without one are left out
+pub async fn find_for_items<C: ConnectionTrait>(
+    user_id: &str,
+    keys: &[String],
+    conn: &C,
+) -> Result<HashMap<String, db::movie_servings::Model>> {
+    // Listings are of one directory, so it's the same library for all of them
+    let mut by_library: HashMap<&str, Vec<&str>> = HashMap::new();
+    for key in keys {
+        let (library_id, item_path) = split_key(key);
+        by_library.entry(library_id).or_default().push(item_path);
+    }
+
+    let mut result = HashMap::new();
+    for (library_id, item_paths) in by_library {
+        for chunk in item_paths.chunks(db::LOOKUP_CHUNK_SIZE) {
+            let servings = MovieServing::find()
+                .filter(db::movie_servings::Column::UserId.eq(user_id))
+                .filter(db::movie_servings::Column::LibraryId.eq(library_id))
+                .filter(db::movie_servings::Column::ItemPath.is_in(chunk.iter().copied()))
+                .all(conn)
+                .await?;
+
+            result.extend(
+                servings
+                    .into_iter()
+                    .map(|serving| (item_key(&serving), serving)),
+            );
+        }
+    }
+
+    Ok(result)
+}
+
+/// Servings of every user for the file, or for everything under the directory
+pub async fn find_under<C: ConnectionTrait>(
+    key: &str,
+    conn: &C,
+) -> Result<Vec<db::movie_servings::Model>> {
+    let (library_id, item_path) = split_key(key);
+
+    Ok(MovieServing::find()
+        .filter(db::movie_servings::Column::LibraryId.eq(library_id))
+        .filter(library::subtree_condition(
+            db::movie_servings::Column::ItemPath,
+            item_path,
+        ))
+        .all(conn)
+        .await?
+        .into_iter()
+        .filter(|serving| library::is_same_or_descendant(&item_key(serving), key))
+        .collect())
+}
+
+/// Inserts the serving, or updates `columns` of the one already there for the same key
+pub async fn upsert<C: ConnectionTrait>(
+    serving: db::movie_servings::ActiveModel,
+    columns: &[db::movie_servings::Column],
+    conn: &C,
+) -> Result<()> {
+    MovieServing::insert(serving)
+        .on_conflict(
+            OnConflict::columns([
+                db::movie_servings::Column::UserId,
+                db::movie_servings::Column::LibraryId,
+                db::movie_servings::Column::ItemPath,
+            ])
+            .update_columns(columns.iter().copied())
+            .to_owned(),
+        )
+        .exec_without_returning(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Gives the serving a new key, replacing whatever was recorded for it
+pub async fn move_to<C: ConnectionTrait>(
+    serving: &db::movie_servings::Model,
+    to: &ServingKey,
+    conn: &C,
+) -> Result<()> {
+    debug!("Moving progress {:?} to {:?}", ServingKey::of(serving), to);
+
+    MovieServing::delete_many()
+        .filter(to.condition())
+        .filter(db::movie_servings::Column::Id.ne(serving.id))
+        .exec(conn)
+        .await?;
+    MovieServing::update_many()
+        .col_expr(
+            db::movie_servings::Column::UserId,
+            Expr::value(to.user_id.as_str()),
+        )
+        .col_expr(
+            db::movie_servings::Column::LibraryId,
+            Expr::value(to.library_id.as_str()),
+        )
+        .col_expr(
+            db::movie_servings::Column::ItemPath,
+            Expr::value(to.item_path.as_str()),
+        )
+        .filter(db::movie_servings::Column::Id.eq(serving.id))
+        .exec(conn)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_library_keys() {
+        let key = ServingKey::new("default/Show/S01/a b.mkv", "user");
+        assert_eq!(key.library_id, "default");
+        assert_eq!(key.item_path, "Show/S01/a b.mkv");
+
+        let root = ServingKey::new("default", "user");
+        assert_eq!(root.item_path, "");
+        assert_eq!(join_key(&root.library_id, &root.item_path), "default");
+    }
+}
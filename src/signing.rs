@@ -0,0 +1,214 @@
+use crate::utils::now_secs;
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use rocket::serde::Deserialize;
+use sha2::Sha256;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use uuid::Uuid;
+
+/*
+Links to /files are signed with HMAC-SHA256 over the file's library key, user id and expiry time,
+so only files listed to someone can be played, and only as the user they were listed to.
+mpv gets them pasted as-is, so the signature has to be in the URL itself.
+*/
+
+type HmacSha256 = Hmac<Sha256>;
+
+const KEY_FILE_NAME: &str = "url_signing.key";
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", default)]
+pub struct LinksConfig {
+    /// How long a link stays valid. Seeking re-requests the file, so it should outlive a movie
+    pub ttl_secs: i64,
+    /// Secret for signing, generated and kept in the settings directory when not set
+    pub signing_key: Option<String>,
+}
+
+impl Default for LinksConfig {
+    fn default() -> Self {
+        LinksConfig {
+            ttl_secs: 24 * 60 * 60,
+            signing_key: None,
+        }
+    }
+}
+
+/// Signature part of a link's query
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct LinkSignature {
+    pub expires: i64,
+    pub signature: String,
+}
+
+pub struct UrlSigner {
+    key: Vec<u8>,
+    ttl_secs: i64,
+}
+
+/// Reads the key from the settings directory, creating it on the first run
+fn load_or_create_key(settings_dir: &Path) -> Result<Vec<u8>> {
+    let key_path = settings_dir.join(KEY_FILE_NAME);
+
+    match fs::read_to_string(&key_path) {
+        Ok(key) if !key.trim().is_empty() => return Ok(key.trim().as_bytes().to_vec()),
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_context(|| format!("failed to read {:?}", key_path)),
+    }
+
+    fs::create_dir_all(settings_dir)
+        .with_context(|| format!("failed to create settings directory {:?}", settings_dir))?;
+
+    let key = Uuid::new_v4().simple().to_string() + &Uuid::new_v4().simple().to_string();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&key_path)
+        .and_then(|mut file| file.write_all(key.as_bytes()))
+        .with_context(|| format!("failed to write {:?}", key_path))?;
+
+    Ok(key.into_bytes())
+}
+
+impl UrlSigner {
+    pub fn from_config(config: &LinksConfig, settings_dir: &Path) -> Result<Self> {
+        if config.ttl_secs <= 0 {
+            return Err(anyhow!("links.ttl_secs should be positive"));
+        }
+
+        let key = match &config.signing_key {
+            Some(key) if !key.is_empty() => key.as_bytes().to_vec(),
+            _ => load_or_create_key(settings_dir)?,
+        };
+
+        Ok(UrlSigner {
+            key,
+            ttl_secs: config.ttl_secs,
+        })
+    }
+
+    fn mac(&self, key: &str, user_id: &str, expires: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        // File names may have anything in them, so the key goes last
+        mac.update(user_id.as_bytes());
+        mac.update(b"\n");
+        mac.update(expires.to_string().as_bytes());
+        mac.update(b"\n");
+        mac.update(key.as_bytes());
+
+        mac
+    }
+
+    /// Signs a link to the file with the library key for the user
+    pub fn sign(&self, key: &str, user_id: &str) -> LinkSignature {
+        let expires = now_secs() + self.ttl_secs;
+        let signature = hex::encode(self.mac(key, user_id, expires).finalize().into_bytes());
+
+        LinkSignature { expires, signature }
+    }
+
+    /// Checks that the link was signed by us, for the same file and user, and hasn't expired
+    pub fn verify(&self, key: &str, user_id: &str, link: &LinkSignature) -> Result<()> {
+        if link.expires < now_secs() {
+            return Err(anyhow!("link has expired"));
+        }
+
+        let signature = hex::decode(&link.signature).map_err(|_| anyhow!("malformed signature"))?;
+        self.mac(key, user_id, link.expires)
+            .verify_slice(&signature)
+            .map_err(|_| anyhow!("signature doesn't match"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "movies/Some Movie (2020)/movie.mkv";
+
+    fn signer() -> UrlSigner {
+        UrlSigner {
+            key: b"secret".to_vec(),
+            ttl_secs: 3600,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let signer = signer();
+        let link = signer.sign(KEY, "user");
+
+        assert!(link.expires > now_secs());
+        assert_eq!(link.signature.len(), 64);
+        assert!(signer.verify(KEY, "user", &link).is_ok());
+    }
+
+    #[test]
+    fn tampered_link() {
+        let signer = signer();
+        let link = signer.sign(KEY, "user");
+
+        assert!(signer.verify("movies/other.mkv", "user", &link).is_err());
+        assert!(signer.verify(KEY, "other user", &link).is_err());
+
+        let later = LinkSignature {
+            expires: link.expires + 1,
+            ..link.clone()
+        };
+        assert!(signer.verify(KEY, "user", &later).is_err());
+
+        let other_signer = UrlSigner {
+            key: b"other secret".to_vec(),
+            ttl_secs: 3600,
+        };
+        assert!(other_signer.verify(KEY, "user", &link).is_err());
+    }
+
+    #[test]
+    fn expired_link() {
+        let signer = signer();
+        let expires = now_secs() - 10;
+        let link = LinkSignature {
+            expires,
+            signature: hex::encode(signer.mac(KEY, "user", expires).finalize().into_bytes()),
+        };
+
+        assert!(signer.verify(KEY, "user", &link).is_err());
+    }
+
+    #[test]
+    fn malformed_signature() {
+        let signer = signer();
+        let link = signer.sign(KEY, "user");
+
+        for signature in ["", "not hex", &link.signature[1..], &link.signature[2..]] {
+            let broken = LinkSignature {
+                signature: String::from(signature),
+                ..link.clone()
+            };
+            assert!(
+                signer.verify(KEY, "user", &broken).is_err(),
+                "{:?}",
+                signature
+            );
+        }
+    }
+
+    #[test]
+    fn ttl_has_to_be_positive() {
+        let config = LinksConfig {
+            ttl_secs: 0,
+            signing_key: Some(String::from("secret")),
+        };
+        assert!(UrlSigner::from_config(&config, Path::new("/nonexistent")).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use crate::db;
+use crate::library::{self, ItemKind};
+use crate::media::{MediaCategory, MediaTypes};
+use anyhow::Result;
+use sea_orm::DatabaseConnection;
+use std::collections::HashMap;
+use std::path::Path;
+
+/*
+External subtitles aren't listed on their own, they go with the movie they belong to:
+"Movie.srt" or "Movie.en.srt" next to "Movie.mkv" or in a "Subs" directory next to it,
+and anything in "Subs/Movie/", which is how some releases ship them.
+When there's a single movie in the directory, every subtitle of its "Subs" is for that movie.
+*/
+
+// Compared case-insensitively
+const SUBS_DIRS: &[&str] = &["subs", "subtitles", "sub"];
+
+/// Subtitle file of a movie
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sidecar {
+    pub key: String,
+    /// What's in the name besides the movie's name, like "en" or "English"
+    pub language: Option<String>,
+}
+
+fn stem_of(name: &str) -> &str {
+    Path::new(name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(name)
+}
+
+fn category(item: &db::library_items::Model, media_types: &MediaTypes) -> Option<MediaCategory> {
+    let ext = Path::new(&item.path).extension()?.to_str()?;
+    media_types.category_by_extension(ext)
+}
+
+fn is_subs_dir(key: &str) -> bool {
+    let name = library::name_of(key).to_lowercase();
+    SUBS_DIRS.iter().any(|el| *el == name)
+}
+
+/// Language of a subtitle named after the movie, `None` when it's named after something else
+fn language_after_stem(sub_stem: &str, movie_stem: &str) -> Option<Option<String>> {
+    if sub_stem == movie_stem {
+        return Some(None);
+    }
+
+    let rest = sub_stem.strip_prefix(movie_stem)?.strip_prefix('.')?;
+    Some(Some(String::from(rest)).filter(|rest| !rest.is_empty()))
+}
+
+/// Language of a subtitle in a movie's own directory, like "2_English"
+fn language_of_name(sub_stem: &str) -> Option<String> {
+    let language = sub_stem
+        .trim_start_matches(|ch: char| ch.is_ascii_digit())
+        .trim_start_matches(['_', ' ', '.', '-']);
+
+    Some(String::from(language)).filter(|language| !language.is_empty())
+}
+
+/// The movie with the longest name that the subtitle is named after, with the subtitle's language
+fn match_by_name<'a>(sub_stem: &str, movies: &'a [String]) -> Option<(&'a String, Option<String>)> {
+    movies
+        .iter()
+        .filter_map(|movie| {
+            let language = language_after_stem(sub_stem, stem_of(library::name_of(movie)))?;
+            Some((movie, language))
+        })
+        .max_by_key(|(movie, _)| movie.len())
+}
+
+/// Pairs subtitles with movies of the directory `dir`.
+/// Subtitles are expected to be in `dir`, in its "Subs" or in directories inside "Subs"
+pub fn associate(
+    dir: &str,
+    movies: &[String],
+    subtitles: &[String],
+) -> HashMap<String, Vec<Sidecar>> {
+    let single_movie = match movies {
+        [movie] => Some(movie),
+        _ => None,
+    };
+
+    let mut result: HashMap<String, Vec<Sidecar>> = HashMap::new();
+    for subtitle in subtitles {
+        let sub_stem = stem_of(library::name_of(subtitle));
+        let parent = library::parent_key(subtitle);
+        let in_subs_dir = is_subs_dir(&parent) && library::parent_key(&parent) == dir;
+
+        let matched = if parent == dir || in_subs_dir {
+            match match_by_name(sub_stem, movies) {
+                Some(matched) => Some(matched),
+                // Name of a single movie is often not repeated in its "Subs"
+                None if in_subs_dir => {
+                    single_movie.map(|movie| (movie, language_of_name(sub_stem)))
+                }
+                None => None,
+            }
+        } else {
+            // Subs/<movie name>/<language>.srt
+            let movie_dir = library::name_of(&parent);
+            movies
+                .iter()
+                .find(|movie| stem_of(library::name_of(movie)) == movie_dir)
+                .or(single_movie)
+                .map(|movie| (movie, language_of_name(sub_stem)))
+        };
+
+        if let Some((movie, language)) = matched {
+            result.entry(movie.clone()).or_default().push(Sidecar {
+                key: subtitle.clone(),
+                language,
+            });
+        }
+    }
+
+    for sidecars in result.values_mut() {
+        sidecars.sort_by(|a, b| a.key.cmp(&b.key));
+    }
+
+    result
+}
+
+/// Subtitles of the movies among `entries`, the indexed children of `dir`
+pub async fn find_sidecars(
+    dir: &str,
+    entries: &[db::library_items::Model],
+    media_types: &MediaTypes,
+    conn: &DatabaseConnection,
+) -> Result<HashMap<String, Vec<Sidecar>>> {
+    let mut files: Vec<db::library_items::Model> = entries
+        .iter()
+        .filter(|el| el.kind == ItemKind::File.as_str())
+        .cloned()
+        .collect();
+
+    // Only a couple of levels, it's subtitles of this directory's movies
+    let mut pending: Vec<String> = entries
+        .iter()
+        .filter(|el| el.kind == ItemKind::Dir.as_str() && is_subs_dir(&el.path))
+        .map(|el| el.path.clone())
+        .collect();
+    while let Some(subs_dir) = pending.pop() {
+        let is_top = library::parent_key(&subs_dir) == dir;
+        for item in library::list_dir(&subs_dir, conn).await? {
+            if item.kind == ItemKind::File.as_str() {
+                files.push(item);
+            } else if is_top {
+                pending.push(item.path);
+            }
+        }
+    }
+
+    let mut movies = Vec::new();
+    let mut subtitles = Vec::new();
+    for file in files {
+        match category(&file, media_types) {
+            // Movies of the "Subs" are not this directory's movies
+            Some(category) if category.is_playable() && library::parent_key(&file.path) == dir => {
+                movies.push(file.path)
+            }
+            Some(MediaCategory::Subtitle) => subtitles.push(file.path),
+            _ => {}
+        }
+    }
+
+    Ok(associate(dir, &movies, &subtitles))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::strings;
+
+    fn languages(result: &HashMap<String, Vec<Sidecar>>, movie: &str) -> Vec<Option<String>> {
+        result
+            .get(movie)
+            .map(|sidecars| sidecars.iter().map(|el| el.language.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn pairs_by_name_and_language() {
+        let movies = strings(&["tv/Show/Show.S01E01.mkv", "tv/Show/Show.S01E02.mkv"]);
+        let subtitles = strings(&[
+            "tv/Show/Show.S01E01.srt",
+            "tv/Show/Show.S01E01.en.srt",
+            "tv/Show/Show.S01E02.pt-BR.forced.ass",
+            "tv/Show/Other.srt",
+        ]);
+
+        let result = associate("tv/Show", &movies, &subtitles);
+        assert_eq!(
+            languages(&result, "tv/Show/Show.S01E01.mkv"),
+            vec![Some(String::from("en")), None]
+        );
+        assert_eq!(
+            languages(&result, "tv/Show/Show.S01E02.mkv"),
+            vec![Some(String::from("pt-BR.forced"))]
+        );
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn longer_names_win() {
+        let movies = strings(&["m/Movie.mkv", "m/Movie.Extended.mkv"]);
+        let subtitles = strings(&["m/Movie.Extended.en.srt", "m/Movie.en.srt"]);
+
+        let result = associate("m", &movies, &subtitles);
+        assert_eq!(result["m/Movie.mkv"][0].key, "m/Movie.en.srt");
+        assert_eq!(
+            result["m/Movie.Extended.mkv"][0].key,
+            "m/Movie.Extended.en.srt"
+        );
+    }
+
+    #[test]
+    fn subs_directories() {
+        let movies = strings(&["tv/S01/E01.mkv", "tv/S01/E02.mkv"]);
+        let subtitles = strings(&[
+            "tv/S01/Subs/E01/2_English.srt",
+            "tv/S01/Subs/E02.en.srt",
+            "tv/S01/Subs/Unknown.srt",
+        ]);
+
+        let result = associate("tv/S01", &movies, &subtitles);
+        assert_eq!(
+            languages(&result, "tv/S01/E01.mkv"),
+            vec![Some(String::from("English"))]
+        );
+        assert_eq!(
+            languages(&result, "tv/S01/E02.mkv"),
+            vec![Some(String::from("en"))]
+        );
+
+        // Nothing to tell it apart by, unless there's one movie
+        let movies = strings(&["m/Movie/Movie.2019.1080p.mkv"]);
+        let subtitles = strings(&["m/Movie/Subs/English.srt", "m/Movie/SUBS/3_French.srt"]);
+        let result = associate("m/Movie", &movies, &subtitles);
+        assert_eq!(
+            languages(&result, "m/Movie/Movie.2019.1080p.mkv"),
+            vec![Some(String::from("French")), Some(String::from("English"))]
+        );
+    }
+}
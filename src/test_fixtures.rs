@@ -0,0 +1,75 @@
+use crate::db;
+use crate::db::migration::{Migrator, MigratorTrait};
+use crate::paths::{Libraries, PathsConfig};
+use crate::servings::ServingKey;
+use sea_orm::ActiveValue::Set;
+use sea_orm::{Database, DatabaseConnection};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/*
+Setup shared by tests that go through the database or the file system.
+*/
+
+/// Empty in-memory database with all migrations applied
+pub async fn connect() -> DatabaseConnection {
+    let conn = Database::connect("sqlite::memory:").await.unwrap();
+    Migrator::up(&conn, None).await.unwrap();
+    conn
+}
+
+/// Progress of the user at `position` bytes of `length`, last played at the start of the epoch
+pub fn serving(
+    key: &str,
+    user_id: &str,
+    position: i64,
+    length: i64,
+) -> db::movie_servings::ActiveModel {
+    let mut serving = ServingKey::new(key, user_id).active_model();
+    serving.last_timestamp = Set(1);
+    serving.last_file_position = Set(position);
+    serving.file_length = Set(length);
+    serving
+}
+
+/// Temporary directory configured as library "lib", removed when dropped
+pub struct TempLibrary {
+    root: PathBuf,
+    pub libraries: Libraries,
+}
+
+impl TempLibrary {
+    pub fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("mpvserve-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut paths_config = PathsConfig::default();
+        paths_config
+            .libraries
+            .insert(String::from("lib"), dir.to_str().unwrap().to_string());
+        let libraries = Libraries::from_config(&paths_config).unwrap();
+        // Canonical, as the paths the watcher gets
+        let root = libraries.get("lib").unwrap().root().to_path_buf();
+
+        TempLibrary { root, libraries }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Creates a file, with the directories it's in
+    pub fn add_file(&self, rel_path: &str) -> PathBuf {
+        let path = self.root.join(rel_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"movie").unwrap();
+        path
+    }
+}
+
+impl Drop for TempLibrary {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::db;
+use crate::progress_tracker::ProgressTracker;
+use crate::servings::{self, ServingKey};
+use crate::utils::now_secs;
+use crate::{db, history};
 use rocket::futures::executor::block_on;
 use rocket::tokio::fs::File;
 use rocket::tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
@@ -7,20 +10,17 @@ use rocket::tokio::sync::oneshot;
 use rocket::tokio::task;
 use rocket_db_pools::Connection;
 use sea_orm::ActiveValue::Set;
-use sea_orm::*;
 use std::{
     io::SeekFrom,
-    path::Path,
     path::PathBuf,
     pin::Pin,
     task::{Context, Poll},
-    time::{SystemTime, UNIX_EPOCH},
 };
 
-use db::prelude::*;
 /*
 How does mpvserve display current progress, even though progress is done by mpv on client machine?
-It tracks last byte requested by mpv. Not 100% accurate, but should do.
+It tracks bytes requested by mpv. Not 100% accurate, but should do.
+Which of the requested bytes count as played is decided by ProgressTracker.
 
 However:
 * there's no way in Rocket to say, how much data was transferred during the request
@@ -37,25 +37,24 @@ pub struct TrackedFileStream {
 
 #[derive(Debug, Clone)]
 pub struct TrackedFileStreamData {
-    pub path: String,
+    pub path: PathBuf,
+    pub key: String,
+    pub user_id: String,
+    pub client_address: Option<String>,
+    pub started_at: i64,
 
     pub len: i64,
-    pub last_pos: i64,
+    pub tracker: ProgressTracker,
 }
 impl TrackedFileStream {
     pub fn from_path(
         abs_path: &PathBuf,
-        rel_path: &Path,
+        key: &str,
         user_id: &str,
+        client_address: Option<String>,
+        recorder: history::Recorder,
         database: Connection<db::Db>,
     ) -> std::io::Result<Self> {
-        // let urlencoded_path = /utils::get_urlencoded_path(rel_path).unwrap();
-        let urlencoded_path: Vec<String> = rel_path
-            .iter()
-            .filter_map(|el| el.to_str())
-            .map(|el| urlencoding::encode(el).to_string())
-            .collect();
-        let result_path = urlencoded_path.join("/") + "?" + user_id;
         let handle = Handle::current();
         let _ = handle.enter();
         let file = match block_on(File::open(abs_path)) {
@@ -71,51 +70,61 @@ impl TrackedFileStream {
                 Ok(data) => data,
                 _ => return,
             };
-
-            let now_secs = i64::try_from(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_secs(),
-            )
-            .unwrap();
-
-            let serving = db::movie_servings::ActiveModel {
-                path: Set(data.path.clone()),
-                last_timestamp: Set(now_secs),
-                last_file_position: Set(data.last_pos),
-                file_length: Set(data.len),
-            };
-
+            let now_secs = now_secs();
             let conn = database.into_inner();
 
-            let insert_error = match serving.insert(&conn).await {
-                Ok(_) => return,
-                Err(e) => e,
+            let record = history::StreamRecord {
+                user_id: data.user_id.clone(),
+                key: data.key.clone(),
+                started_at: data.started_at,
+                ended_at: now_secs,
+                played: data
+                    .tracker
+                    .start_position()
+                    .zip(data.tracker.committed_position()),
+                bytes_sent: data.tracker.bytes_read(),
+                client_address: data.client_address.clone(),
             };
-            debug!("insert failed, trying update: {}", insert_error);
-
-            match MovieServing::find_by_id(data.path.clone()).one(&conn).await {
-                Ok(serv) => {
-                    let serve_movdel: db::movie_servings::Model = serv.unwrap();
-                    let mut active_serving: db::movie_servings::ActiveModel = serve_movdel.into();
-                    active_serving.last_timestamp = Set(now_secs);
-                    active_serving.last_file_position = Set(data.last_pos);
-
-                    if let Err(e) = active_serving.update(&conn).await {
-                        log::error!("update failed on insert conflict: {:?}", e);
-                    }
-                }
-                Err(e) => {
-                    log::error!("find_by_id failed on insert conflict: {:?}", e);
+            if let Err(e) = recorder.record(&record, &conn).await {
+                log::error!("Failed to record playback of {:?}: {:?}", data.path, e);
+            }
+
+            let last_pos = match data.tracker.committed_position() {
+                Some(pos) => i64::try_from(pos).unwrap(),
+                None => {
+                    debug!("{:?} was only probed, keeping the progress", data.path);
+                    return;
                 }
             };
+
+            let mut serving = ServingKey::new(&data.key, &data.user_id).active_model();
+            serving.last_timestamp = Set(now_secs);
+            serving.last_file_position = Set(last_pos);
+            serving.file_length = Set(data.len);
+            serving.orphaned = Set(false);
+            // Playing it again is more telling than marking
+            serving.watched = Set(None);
+
+            let columns = [
+                db::movie_servings::Column::LastTimestamp,
+                db::movie_servings::Column::LastFilePosition,
+                db::movie_servings::Column::FileLength,
+                db::movie_servings::Column::Orphaned,
+                db::movie_servings::Column::Watched,
+            ];
+            if let Err(e) = servings::upsert(serving, &columns, &conn).await {
+                log::error!("Failed to store progress of {:?}: {:?}", data.path, e);
+            }
         });
 
         let data = TrackedFileStreamData {
-            path: result_path,
+            path: abs_path.clone(),
+            key: String::from(key),
+            user_id: String::from(user_id),
+            client_address,
+            started_at: now_secs(),
             len,
-            last_pos: 0,
+            tracker: ProgressTracker::new(u64::try_from(len).unwrap()),
         };
 
         Ok(Self {
@@ -140,10 +149,12 @@ impl AsyncRead for TrackedFileStream {
         cx: &mut Context,
         buf: &mut ReadBuf,
     ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
         let poll = Pin::new(&mut self.tokio_file).poll_read(cx, buf);
 
-        if poll.is_ready() {
-            self.data.last_pos += buf.filled().len() as i64;
+        if let Poll::Ready(Ok(())) = poll {
+            let read = buf.filled().len() - filled_before;
+            self.data.tracker.on_read(read as u64);
         }
 
         poll
@@ -159,7 +170,7 @@ impl AsyncSeek for TrackedFileStream {
         let poll = Pin::new(&mut self.tokio_file).poll_complete(cx);
 
         if let Poll::Ready(Ok(new_pos)) = poll {
-            self.data.last_pos = i64::try_from(new_pos).unwrap();
+            self.data.tracker.on_seek(new_pos);
         }
 
         poll
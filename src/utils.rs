@@ -0,0 +1,48 @@
+use anyhow::{anyhow, Result};
+use rocket::figment::Figment;
+use rocket::serde::de::DeserializeOwned;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current UNIX timestamp, in seconds
+pub fn now_secs() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    i64::try_from(now.as_secs()).unwrap()
+}
+
+/// Section of Rocket.toml, its defaults when the section is missing
+pub fn config_section<T: DeserializeOwned + Default>(figment: &Figment, name: &str) -> Result<T> {
+    match figment.extract_inner::<T>(name) {
+        Ok(config) => Ok(config),
+        Err(err) if err.missing() => Ok(T::default()),
+        Err(err) => Err(anyhow!("invalid {} config: {}", name, err)),
+    }
+}
+
+/// Owned copies of `values`
+pub fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|el| String::from(*el)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::MediaConfig;
+    use rocket::figment::providers::{Format, Toml};
+
+    #[test]
+    fn reads_config_sections() {
+        let figment = Figment::from(Toml::string("[media]\nvideo = [\"mkv\"]"));
+        let config: MediaConfig = config_section(&figment, "media").unwrap();
+        assert_eq!(config.video, ["mkv"]);
+        assert_eq!(config.audio, MediaConfig::default().audio);
+
+        let config: MediaConfig = config_section(&Figment::new(), "media").unwrap();
+        assert_eq!(config.video, MediaConfig::default().video);
+
+        let figment = Figment::from(Toml::string("[media]\nvideo = 1"));
+        assert!(config_section::<MediaConfig>(&figment, "media").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::db;
+use crate::library::{self, ItemKind};
+use crate::media::MediaTypes;
+use crate::servings::{self, ServingKey};
+use crate::utils::now_secs;
+use anyhow::{anyhow, Result};
+use log::debug;
+use sea_orm::ActiveValue::Set;
+use sea_orm::*;
+use std::path::Path;
+
+use db::prelude::*;
+
+/*
+Progress set by hand: a file, or every file of a directory, marked as watched or unwatched, or cleared.
+Marks are kept in `movie_servings.watched` and win over the played percentage until the file is played again.
+*/
+
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mark {
+    Watched,
+    Unwatched,
+    /// Forget the progress altogether
+    Reset,
+}
+
+fn is_playable(item: &db::library_items::Model, media_types: &MediaTypes) -> bool {
+    let ext = match Path::new(&item.path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some(ext) => ext,
+        None => return false,
+    };
+
+    matches!(media_types.category_by_extension(ext), Some(category) if category.is_playable())
+}
+
+/// Playable files at the key: the file itself, or everything under the directory
+async fn files_at(
+    key: &str,
+    media_types: &MediaTypes,
+    conn: &DatabaseConnection,
+) -> Result<Vec<db::library_items::Model>> {
+    let item = LibraryItem::find_by_id(String::from(key))
+        .one(conn)
+        .await?
+        .ok_or_else(|| anyhow!("{:?} is not found in the library", key))?;
+
+    let items = if item.kind == ItemKind::Dir.as_str() {
+        LibraryItem::find()
+            .filter(library::subtree_condition(
+                db::library_items::Column::Path,
+                key,
+            ))
+            .filter(db::library_items::Column::Kind.eq(ItemKind::File.as_str()))
+            .all(conn)
+            .await?
+            .into_iter()
+            .filter(|el| library::is_same_or_descendant(&el.path, key))
+            .collect()
+    } else {
+        vec![item]
+    };
+
+    Ok(items
+        .into_iter()
+        .filter(|el| is_playable(el, media_types))
+        .collect())
+}
+
+/// Marks the file or all files of the directory for the user, returns the number of marked files
+pub async fn mark(
+    key: &str,
+    mark: Mark,
+    user_id: &str,
+    media_types: &MediaTypes,
+    conn: &DatabaseConnection,
+) -> Result<usize> {
+    let files = files_at(key, media_types, conn).await?;
+    let now = now_secs();
+
+    let txn = conn.begin().await?;
+    for file in files.iter() {
+        let serving_key = ServingKey::new(&file.path, user_id);
+        debug!("Marking {:?} as {:?}", serving_key, mark);
+
+        let watched = match mark {
+            Mark::Watched => true,
+            Mark::Unwatched => false,
+            Mark::Reset => {
+                servings::delete(&serving_key, &txn).await?;
+                continue;
+            }
+        };
+
+        let mut serving = serving_key.active_model();
+        serving.last_timestamp = Set(now);
+        serving.last_file_position = Set(0);
+        serving.file_length = Set(file.size);
+        serving.orphaned = Set(false);
+        serving.watched = Set(Some(watched));
+
+        let columns = [
+            db::movie_servings::Column::LastTimestamp,
+            db::movie_servings::Column::Orphaned,
+            db::movie_servings::Column::Watched,
+        ];
+        servings::upsert(serving, &columns, &txn).await?;
+    }
+    txn.commit().await?;
+
+    Ok(files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::MediaConfig;
+    use crate::test_fixtures::{self, connect};
+
+    async fn add_item(path: &str, kind: ItemKind, conn: &DatabaseConnection) {
+        db::library_items::ActiveModel {
+            path: Set(String::from(path)),
+            parent: Set(library::parent_key(path)),
+            size: Set(1000),
+            mtime: Set(0),
+            kind: Set(String::from(kind.as_str())),
+        }
+        .insert(conn)
+        .await
+        .unwrap();
+    }
+
+    async fn watched(key: &str, conn: &DatabaseConnection) -> Option<Option<bool>> {
+        servings::find(&ServingKey::new(key, "user"), conn)
+            .await
+            .unwrap()
+            .map(|serving| serving.watched)
+    }
+
+    async fn mark_as(key: &str, mark: Mark, conn: &DatabaseConnection) -> usize {
+        let media_types = MediaTypes::from_config(&MediaConfig::default());
+        super::mark(key, mark, "user", &media_types, conn)
+            .await
+            .unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn marks_single_files() {
+        let conn = connect().await;
+        add_item("lib/a.mkv", ItemKind::File, &conn).await;
+        add_item("lib/b.mkv", ItemKind::File, &conn).await;
+
+        assert_eq!(mark_as("lib/a.mkv", Mark::Watched, &conn).await, 1);
+        assert_eq!(watched("lib/a.mkv", &conn).await, Some(Some(true)));
+        assert_eq!(watched("lib/b.mkv", &conn).await, None);
+
+        assert_eq!(mark_as("lib/a.mkv", Mark::Unwatched, &conn).await, 1);
+        assert_eq!(watched("lib/a.mkv", &conn).await, Some(Some(false)));
+    }
+
+    #[rocket::async_test]
+    async fn marks_directories_recursively() {
+        let conn = connect().await;
+        for (path, kind) in [
+            ("lib/Show_1", ItemKind::Dir),
+            ("lib/Show_1/e1.mkv", ItemKind::File),
+            ("lib/Show_1/notes.txt", ItemKind::File),
+            ("lib/Show_1/S2", ItemKind::Dir),
+            ("lib/Show_1/S2/e2.mkv", ItemKind::File),
+            ("lib/ShowA1", ItemKind::Dir),
+            ("lib/ShowA1/e1.mkv", ItemKind::File),
+            ("lib/Show_10", ItemKind::Dir),
+            ("lib/Show_10/e1.mkv", ItemKind::File),
+        ] {
+            add_item(path, kind, &conn).await;
+        }
+
+        assert_eq!(mark_as("lib/Show_1", Mark::Watched, &conn).await, 2);
+        assert_eq!(watched("lib/Show_1/e1.mkv", &conn).await, Some(Some(true)));
+        assert_eq!(
+            watched("lib/Show_1/S2/e2.mkv", &conn).await,
+            Some(Some(true))
+        );
+        assert_eq!(watched("lib/Show_1/notes.txt", &conn).await, None);
+        assert_eq!(watched("lib/ShowA1/e1.mkv", &conn).await, None);
+        assert_eq!(watched("lib/Show_10/e1.mkv", &conn).await, None);
+    }
+
+    #[rocket::async_test]
+    async fn reset_clears_progress() {
+        let conn = connect().await;
+        add_item("lib/a.mkv", ItemKind::File, &conn).await;
+        test_fixtures::serving("lib/a.mkv", "user", 500, 1000)
+            .insert(&conn)
+            .await
+            .unwrap();
+
+        assert_eq!(mark_as("lib/a.mkv", Mark::Reset, &conn).await, 1);
+        assert_eq!(watched("lib/a.mkv", &conn).await, None);
+    }
+}
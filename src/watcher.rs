@@ -0,0 +1,285 @@
+use crate::library;
+use crate::paths::Libraries;
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::{task, time};
+use sea_orm::DatabaseConnection;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/*
+inotify reports a rename as three events: From, To and Both (with both paths), all sharing a tracker.
+If a file is moved out of the watched tree, only From arrives. So From is kept pending for a while,
+and if nothing pairs with it, it's treated as a removal.
+*/
+const PENDING_RENAME_TIMEOUT: Duration = Duration::from_secs(2);
+const PENDING_RENAME_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+struct LibraryWatcher {
+    libraries: Libraries,
+    scanner: library::Scanner,
+    conn: DatabaseConnection,
+    pending_renames: HashMap<usize, (String, Instant)>,
+}
+
+impl LibraryWatcher {
+    fn path_to_key(&self, path: &Path) -> Option<String> {
+        self.libraries.key_of(path)
+    }
+
+    async fn index(&self, key: &str) {
+        debug!("Watcher: re-indexing {}", key);
+        if let Err(err) = self
+            .scanner
+            .index_path(&self.libraries, key, &self.conn)
+            .await
+        {
+            log::error!("Failed to update index for {}: {:?}", key, err);
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) {
+        debug!("Watcher: {} renamed to {}", from, to);
+        if let Err(err) = self
+            .scanner
+            .rename_path(&self.libraries, from, to, &self.conn)
+            .await
+        {
+            log::error!("Failed to move {} to {}: {:?}", from, to, err);
+        }
+    }
+
+    async fn handle_event(&mut self, event: Event) {
+        if event.need_rescan() {
+            info!("Watcher lost some events, rescanning the library");
+            if let Err(err) = self.scanner.scan(&self.libraries, &self.conn).await {
+                log::error!("Library scan failed: {:?}", err);
+            }
+            return;
+        }
+
+        let keys: Vec<String> = event
+            .paths
+            .iter()
+            .filter_map(|path| self.path_to_key(path))
+            .collect();
+
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                if let (Some(tracker), Some(key)) = (event.tracker(), keys.first()) {
+                    self.pending_renames
+                        .insert(tracker, (key.clone(), Instant::now()));
+                } else {
+                    for key in keys.iter() {
+                        self.index(key).await;
+                    }
+                }
+            }
+            // Paired renames are handled by the following Both event
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) if event.tracker().is_none() => {
+                for key in keys.iter() {
+                    self.index(key).await;
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let Some(tracker) = event.tracker() {
+                    self.pending_renames.remove(&tracker);
+                }
+
+                // One of the paths may be outside of the roots
+                match (event.paths.first(), event.paths.get(1)) {
+                    (Some(from), Some(to)) => {
+                        match (self.path_to_key(from), self.path_to_key(to)) {
+                            (Some(from), Some(to)) => self.rename(&from, &to).await,
+                            (Some(key), None) | (None, Some(key)) => self.index(&key).await,
+                            (None, None) => {}
+                        }
+                    }
+                    _ => {
+                        for key in keys.iter() {
+                            self.index(key).await;
+                        }
+                    }
+                }
+            }
+            EventKind::Create(_)
+            | EventKind::Remove(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                for key in keys.iter() {
+                    self.index(key).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn flush_pending_renames(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .pending_renames
+            .iter()
+            .filter(|(_, (_, since))| now.duration_since(*since) > PENDING_RENAME_TIMEOUT)
+            .map(|(tracker, _)| *tracker)
+            .collect();
+
+        for tracker in expired {
+            if let Some((key, _)) = self.pending_renames.remove(&tracker) {
+                debug!("Watcher: {} moved out of the library", key);
+                self.index(&key).await;
+            }
+        }
+    }
+}
+
+/// Starts watching library roots in background, updating the index through `scanner`.
+/// Symlinked directories are not watched, changes in them are picked up by rescans
+pub fn spawn(
+    libraries: Libraries,
+    scanner: library::Scanner,
+    conn: DatabaseConnection,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let mut watcher = RecommendedWatcher::new(
+        move |res: notify::Result<Event>| {
+            // Receiver is gone only if the server is shutting down
+            let _ = tx.send(res);
+        },
+        notify::Config::default(),
+    )
+    .map_err(|err| anyhow!("failed to create watcher: {}", err))?;
+
+    // Roots are canonical, so are the paths in events
+    for library in libraries.iter() {
+        watcher
+            .watch(library.root(), RecursiveMode::Recursive)
+            .map_err(|err| anyhow!("failed to watch {:?}: {}", library.root(), err))?;
+
+        info!("Watching {:?} for changes", library.root());
+    }
+
+    let mut library_watcher = LibraryWatcher {
+        libraries,
+        scanner,
+        conn,
+        pending_renames: HashMap::new(),
+    };
+
+    task::spawn(async move {
+        // Watcher stops when dropped, so the task owns it
+        let _watcher = watcher;
+        let mut interval = time::interval(PENDING_RENAME_CHECK_INTERVAL);
+
+        loop {
+            rocket::tokio::select! {
+                res = rx.recv() => match res {
+                    Some(Ok(event)) => library_watcher.handle_event(event).await,
+                    Some(Err(err)) => warn!("Watcher error: {}", err),
+                    None => break,
+                },
+                _ = interval.tick() => library_watcher.flush_pending_renames().await,
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servings::{self, ServingKey};
+    use crate::test_fixtures::{self, TempLibrary};
+    use sea_orm::ActiveModelTrait;
+    use std::fs;
+
+    /// Watcher of a library with "Old/a.mkv" in it, indexed and watched halfway
+    async fn watcher() -> (LibraryWatcher, TempLibrary) {
+        let temp = TempLibrary::new();
+        temp.add_file("Old/a.mkv");
+
+        let conn = test_fixtures::connect().await;
+        let scanner = library::Scanner::default();
+        scanner.scan(&temp.libraries, &conn).await.unwrap();
+        test_fixtures::serving("lib/Old/a.mkv", "user", 500, 1000)
+            .insert(&conn)
+            .await
+            .unwrap();
+
+        let watcher = LibraryWatcher {
+            libraries: temp.libraries.clone(),
+            scanner,
+            conn,
+            pending_renames: HashMap::new(),
+        };
+        (watcher, temp)
+    }
+
+    fn rename_event(mode: RenameMode, paths: &[&Path], tracker: usize) -> Event {
+        let mut event = Event::new(EventKind::Modify(ModifyKind::Name(mode))).set_tracker(tracker);
+        for path in paths {
+            event = event.add_path(path.to_path_buf());
+        }
+        event
+    }
+
+    async fn serving(watcher: &LibraryWatcher, key: &str) -> Option<bool> {
+        servings::find(&ServingKey::new(key, "user"), &watcher.conn)
+            .await
+            .unwrap()
+            .map(|serving| serving.orphaned)
+    }
+
+    #[rocket::async_test]
+    async fn paired_rename_moves_progress() {
+        let (mut watcher, temp) = watcher().await;
+        let root = temp.root();
+        let (old, new) = (root.join("Old"), root.join("New"));
+        fs::rename(&old, &new).unwrap();
+
+        watcher
+            .handle_event(rename_event(RenameMode::From, &[&old], 1))
+            .await;
+        assert_eq!(watcher.pending_renames.len(), 1);
+        watcher
+            .handle_event(rename_event(RenameMode::To, &[&new], 1))
+            .await;
+        watcher
+            .handle_event(rename_event(RenameMode::Both, &[&old, &new], 1))
+            .await;
+
+        assert!(watcher.pending_renames.is_empty());
+        assert_eq!(serving(&watcher, "lib/Old/a.mkv").await, None);
+        assert_eq!(serving(&watcher, "lib/New/a.mkv").await, Some(false));
+        assert!(library::dir_exists("lib/New", &watcher.conn).await.unwrap());
+        assert!(!library::dir_exists("lib/Old", &watcher.conn).await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn unpaired_rename_is_a_removal() {
+        let (mut watcher, temp) = watcher().await;
+        let root = temp.root();
+        let old = root.join("Old");
+        fs::remove_dir_all(&old).unwrap();
+
+        watcher
+            .handle_event(rename_event(RenameMode::From, &[&old], 1))
+            .await;
+        watcher.flush_pending_renames().await;
+        assert_eq!(watcher.pending_renames.len(), 1);
+
+        // As if the timeout has passed
+        for (_, since) in watcher.pending_renames.values_mut() {
+            *since -= PENDING_RENAME_TIMEOUT * 2;
+        }
+        watcher.flush_pending_renames().await;
+
+        assert!(watcher.pending_renames.is_empty());
+        assert!(!library::dir_exists("lib/Old", &watcher.conn).await.unwrap());
+        assert_eq!(serving(&watcher, "lib/Old/a.mkv").await, Some(true));
+    }
+}
@@ -1,4 +0,0 @@
-#!/usr/bin/env bash
-
-PATH=/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin
-mpv "${1/mpv\:\/\//http://}"
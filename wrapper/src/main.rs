@@ -0,0 +1,552 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+Handler of mpv:// links. Turns the link into a plain http:// one and launches mpv on it,
+with JSON IPC enabled. Then it watches playback position over the IPC socket
+and reports it to mpvserve, which is way more accurate than guessing it from served bytes.
+Links may be to a single file or to a playlist of files, reports are for whatever mpv plays at the moment.
+*/
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// mpv needs a moment to create the socket
+const CONNECT_ATTEMPTS: u32 = 50;
+
+// Where mpv usually is, when PATH of a desktop app doesn't have it
+const EXTRA_PATH: &str = "/usr/local/bin:/opt/homebrew/bin:/usr/bin:/bin";
+
+// Ids of observed properties
+const OBSERVE_TIME_POS: u64 = 1;
+const OBSERVE_DURATION: u64 = 2;
+const OBSERVE_PAUSE: u64 = 3;
+const OBSERVE_PATH: u64 = 4;
+
+/// What's parsed out of mpv://host/<files|playlist>/<path>?user_id=<id>&expires=<ts>&signature=<hex>&start=<secs>&sub=<url>
+#[derive(Debug)]
+struct Link {
+    http_url: String,
+    report_url: String,
+    // Saved position on the server, in seconds
+    start_secs: Option<f64>,
+    // External subtitles, each one is a signed http:// URL
+    subtitle_urls: Vec<String>,
+}
+
+/// File being played, as it's in its http://host/files/<path>?... URL
+#[derive(Debug, PartialEq)]
+struct PlayedFile {
+    file: String,
+    user_id: String,
+    // Reports are only accepted for signed links
+    expires: i64,
+    signature: String,
+}
+
+fn parse_file_url(url: &str) -> Result<PlayedFile> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("not an http:// URL: {}", url))?;
+    let (_host, path_and_query) = rest
+        .split_once('/')
+        .ok_or_else(|| anyhow!("no path in {}", url))?;
+    let (path, query) = path_and_query
+        .split_once('?')
+        .unwrap_or((path_and_query, ""));
+
+    let file = path
+        .strip_prefix("files/")
+        .ok_or_else(|| anyhow!("not a file URL: {}", url))?;
+
+    let mut user_id = None;
+    let mut expires = None;
+    let mut signature = None;
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        match pair.split_once('=') {
+            Some(("user_id", value)) => user_id = Some(urlencoding::decode(value)?.into_owned()),
+            Some(("expires", value)) => {
+                expires = Some(
+                    value
+                        .parse::<i64>()
+                        .with_context(|| format!("invalid expires {:?}", value))?,
+                )
+            }
+            Some(("signature", value)) => signature = Some(String::from(value)),
+            _ => {}
+        }
+    }
+
+    Ok(PlayedFile {
+        file: String::from(file),
+        user_id: user_id.ok_or_else(|| anyhow!("no user_id in {}", url))?,
+        expires: expires.ok_or_else(|| anyhow!("no expires in {}", url))?,
+        signature: signature.ok_or_else(|| anyhow!("no signature in {}", url))?,
+    })
+}
+
+fn parse_link(link: &str) -> Result<Link> {
+    let rest = link
+        .strip_prefix("mpv://")
+        .ok_or_else(|| anyhow!("not an mpv:// link: {}", link))?;
+    let (host, path_and_query) = rest
+        .split_once('/')
+        .ok_or_else(|| anyhow!("no path in {}", link))?;
+    let (path, query) = path_and_query
+        .split_once('?')
+        .unwrap_or((path_and_query, ""));
+
+    if !path.starts_with("files/") && !path.starts_with("playlist/") {
+        return Err(anyhow!("not a file or playlist link: {}", link));
+    }
+
+    let mut start_secs = None;
+    let mut subtitle_urls = Vec::new();
+    // Everything but start and sub goes to mpvserve as is
+    let mut http_query = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        match pair.split_once('=') {
+            Some(("start", value)) => {
+                start_secs = Some(
+                    value
+                        .parse::<f64>()
+                        .with_context(|| format!("invalid start {:?}", value))?,
+                )
+            }
+            Some(("sub", value)) => subtitle_urls.push(urlencoding::decode(value)?.into_owned()),
+            _ => http_query.push(pair),
+        }
+    }
+
+    Ok(Link {
+        http_url: format!("http://{}/{}?{}", host, path, http_query.join("&")),
+        report_url: format!("http://{}/api/progress", host),
+        start_secs,
+        subtitle_urls,
+    })
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+struct ProgressReport {
+    file: String,
+    user_id: String,
+    position_secs: f64,
+    duration_secs: Option<f64>,
+    paused: bool,
+    expires: i64,
+    signature: String,
+}
+
+fn send_report(url: &str, report: &ProgressReport) -> Result<()> {
+    let response: Value = ureq::post(url)
+        .timeout(Duration::from_secs(5))
+        .send_json(report)?
+        .into_json()?;
+
+    match response.get("Error") {
+        Some(err) => Err(anyhow!("mpvserve refused the report: {}", err)),
+        None => Ok(()),
+    }
+}
+
+fn socket_path() -> PathBuf {
+    std::env::temp_dir().join(format!("mpvserve-wrapper-{}.sock", std::process::id()))
+}
+
+fn spawn_mpv(link: &Link, socket: &Path, extra_args: &[String]) -> Result<Child> {
+    let path = match std::env::var("PATH") {
+        Ok(path) if !path.is_empty() => format!("{}:{}", path, EXTRA_PATH),
+        _ => String::from(EXTRA_PATH),
+    };
+
+    let mut command = Command::new("mpv");
+    command
+        .env("PATH", path)
+        .arg(format!("--input-ipc-server={}", socket.display()));
+
+    // Explicit --start given to the wrapper wins
+    let has_start = extra_args.iter().any(|arg| arg.starts_with("--start"));
+    if let (Some(start_secs), false) = (link.start_secs, has_start) {
+        command.arg(format!("--start={}", start_secs));
+    }
+    for url in link.subtitle_urls.iter() {
+        command.arg(format!("--sub-file={}", url));
+    }
+
+    command
+        .args(extra_args)
+        .arg(&link.http_url)
+        .spawn()
+        .context("failed to launch mpv")
+}
+
+fn connect(socket: &Path, mpv: &mut Child) -> Result<UnixStream> {
+    for _ in 0..CONNECT_ATTEMPTS {
+        if let Ok(stream) = UnixStream::connect(socket) {
+            return Ok(stream);
+        }
+        if mpv.try_wait()?.is_some() {
+            return Err(anyhow!("mpv exited before IPC socket appeared"));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Err(anyhow!("IPC socket {:?} didn't appear", socket))
+}
+
+/// Sends mpv lines from the socket to the channel, until the socket is closed
+fn spawn_reader(stream: UnixStream) -> mpsc::Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            match serde_json::from_str(&line) {
+                Ok(value) => {
+                    if sender.send(value).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => eprintln!("Unexpected mpv IPC message {:?}: {}", line, err),
+            }
+        }
+    });
+
+    receiver
+}
+
+fn observe(stream: &mut UnixStream) -> Result<()> {
+    for (id, property) in [
+        (OBSERVE_TIME_POS, "time-pos"),
+        (OBSERVE_DURATION, "duration"),
+        (OBSERVE_PAUSE, "pause"),
+        (OBSERVE_PATH, "path"),
+    ] {
+        let command = json!({ "command": ["observe_property", id, property] });
+        stream.write_all(format!("{}\n", command).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// New path mpv plays, from playlists it goes through the files one by one
+fn path_change(event: &Value) -> Option<&str> {
+    if event.get("event").and_then(Value::as_str) != Some("property-change") {
+        return None;
+    }
+    if event.get("id").and_then(Value::as_u64) != Some(OBSERVE_PATH) {
+        return None;
+    }
+
+    event.get("data").and_then(Value::as_str)
+}
+
+/// Report for the file, without any progress yet
+fn new_report(path: &str) -> Option<ProgressReport> {
+    // Playlist itself is a path too, until mpv opens its first entry
+    if !path.contains("/files/") {
+        return None;
+    }
+
+    match parse_file_url(path) {
+        Ok(played) => Some(ProgressReport {
+            file: played.file,
+            user_id: played.user_id,
+            expires: played.expires,
+            signature: played.signature,
+            ..Default::default()
+        }),
+        Err(err) => {
+            eprintln!("Progress of {} won't be reported: {:#}", path, err);
+            None
+        }
+    }
+}
+
+/// Applies a property-change event to the report, returns true if it's worth reporting right away
+fn apply_event(report: &mut ProgressReport, event: &Value) -> bool {
+    if event.get("event").and_then(Value::as_str) != Some("property-change") {
+        return false;
+    }
+
+    let data = event.get("data");
+    match event.get("id").and_then(Value::as_u64) {
+        Some(OBSERVE_TIME_POS) => {
+            if let Some(position) = data.and_then(Value::as_f64) {
+                report.position_secs = position.max(0.0);
+            }
+            false
+        }
+        Some(OBSERVE_DURATION) => {
+            report.duration_secs = data.and_then(Value::as_f64);
+            false
+        }
+        Some(OBSERVE_PAUSE) => {
+            let paused = data.and_then(Value::as_bool).unwrap_or(false);
+            let changed = paused != report.paused;
+            report.paused = paused;
+            changed
+        }
+        _ => false,
+    }
+}
+
+fn track_progress(link: &Link, mpv: &mut Child, socket: &Path) -> Result<()> {
+    let mut stream = connect(socket, mpv)?;
+    observe(&mut stream)?;
+    let events = spawn_reader(stream);
+
+    // Set once mpv says what it plays
+    let mut report: Option<ProgressReport> = None;
+    let mut last_sent: Option<ProgressReport> = None;
+    let mut last_sent_at = Instant::now();
+    let mut has_position = false;
+
+    loop {
+        let mut report_now = false;
+        let mut mpv_gone = false;
+        let mut next_path = None;
+
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => match path_change(&event) {
+                Some(path) => {
+                    // Last position of the previous file goes out before switching
+                    next_path = Some(String::from(path));
+                    report_now = true;
+                }
+                _ => {
+                    if let Some(report) = report.as_mut() {
+                        report_now = apply_event(report, &event);
+                        has_position |= report.position_secs > 0.0;
+                    }
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => mpv_gone = true,
+        }
+
+        let due = report_now || mpv_gone || last_sent_at.elapsed() >= REPORT_INTERVAL;
+        if let Some(report) = report.as_ref() {
+            if due && has_position && last_sent.as_ref() != Some(report) {
+                if let Err(err) = send_report(&link.report_url, report) {
+                    eprintln!("Failed to report progress: {:#}", err);
+                }
+                last_sent = Some(report.clone());
+                last_sent_at = Instant::now();
+            }
+        }
+
+        if let Some(path) = next_path {
+            let next_report = new_report(&path);
+            if next_report.as_ref().map(|el| &el.file) != report.as_ref().map(|el| &el.file) {
+                report = next_report;
+                has_position = false;
+            }
+        }
+
+        if mpv_gone {
+            return Ok(());
+        }
+    }
+}
+
+fn run() -> Result<i32> {
+    let mut args = std::env::args().skip(1);
+    let link = args.next().ok_or_else(|| {
+        anyhow!("usage: mpvserve-wrapper mpv://host/<files|playlist>/... [mpv args]")
+    })?;
+    let extra_args: Vec<String> = args.collect();
+
+    let link = parse_link(&link)?;
+    let socket = socket_path();
+    let mut mpv = spawn_mpv(&link, &socket, &extra_args)?;
+
+    // mpv keeps playing even if progress can't be tracked
+    if let Err(err) = track_progress(&link, &mut mpv, &socket) {
+        eprintln!("Progress won't be reported: {:#}", err);
+    }
+
+    let status = mpv.wait()?;
+    let _ = std::fs::remove_file(&socket);
+
+    Ok(status.code().unwrap_or(1))
+}
+
+fn main() {
+    match run() {
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            eprintln!("Error: {:#}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNED: &str = "user_id=u%2B1&expires=1700000000&signature=abcdef";
+
+    #[test]
+    fn file_links() {
+        let link = parse_link(&format!(
+            "mpv://host:8000/files/lib/Show/S01%20E01.mkv?{}",
+            SIGNED
+        ))
+        .unwrap();
+
+        assert_eq!(
+            link.http_url,
+            format!("http://host:8000/files/lib/Show/S01%20E01.mkv?{}", SIGNED)
+        );
+        assert_eq!(link.report_url, "http://host:8000/api/progress");
+        assert_eq!(link.start_secs, None);
+        assert!(link.subtitle_urls.is_empty());
+    }
+
+    #[test]
+    fn start_and_subtitles_are_taken_out() {
+        let sub = "http://host/files/lib/a%20b.srt?user_id=u&expires=1&signature=ff";
+        let link = parse_link(&format!(
+            "mpv://host/files/lib/a%20b.mkv?start=754.5&{}&sub={}&sub=http%3A%2F%2Fhost%2Fx.ass",
+            SIGNED,
+            urlencoding::encode(sub)
+        ))
+        .unwrap();
+
+        assert_eq!(
+            link.http_url,
+            format!("http://host/files/lib/a%20b.mkv?{}", SIGNED)
+        );
+        assert_eq!(link.start_secs, Some(754.5));
+        assert_eq!(link.subtitle_urls, vec![sub, "http://host/x.ass"]);
+    }
+
+    #[test]
+    fn playlist_links() {
+        let link =
+            parse_link(&format!("mpv://host/playlist/lib/Show?{}&start=10", SIGNED)).unwrap();
+
+        assert_eq!(
+            link.http_url,
+            format!("http://host/playlist/lib/Show?{}", SIGNED)
+        );
+        assert_eq!(link.start_secs, Some(10.0));
+    }
+
+    #[test]
+    fn invalid_links() {
+        assert!(parse_link("http://host/files/a.mkv").is_err());
+        assert!(parse_link("mpv://host").is_err());
+        assert!(parse_link("mpv://host/browse/lib").is_err());
+        assert!(parse_link("mpv://host/files/a.mkv?start=soon").is_err());
+        assert!(parse_link("mpv://host/files/a.mkv?sub=%FF").is_err());
+    }
+
+    #[test]
+    fn file_urls() {
+        let played = parse_file_url(&format!(
+            "http://host/files/lib/Show/S01%20E01.mkv?{}&other=1",
+            SIGNED
+        ))
+        .unwrap();
+
+        assert_eq!(
+            played,
+            PlayedFile {
+                // Stays urlencoded, mpvserve decodes it
+                file: String::from("lib/Show/S01%20E01.mkv"),
+                user_id: String::from("u+1"),
+                expires: 1700000000,
+                signature: String::from("abcdef"),
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_file_urls() {
+        assert!(
+            parse_file_url("https://host/files/a.mkv?user_id=u&expires=1&signature=ff").is_err()
+        );
+        assert!(parse_file_url("http://host/playlist/a?user_id=u&expires=1&signature=ff").is_err());
+        assert!(parse_file_url("http://host/files/a.mkv?user_id=u&expires=1").is_err());
+        assert!(
+            parse_file_url("http://host/files/a.mkv?user_id=u&expires=x&signature=ff").is_err()
+        );
+        assert_eq!(new_report("http://host/playlist/lib/Show?user_id=u"), None);
+    }
+
+    fn property_change(id: u64, data: Value) -> Value {
+        json!({ "event": "property-change", "id": id, "name": "whatever", "data": data })
+    }
+
+    #[test]
+    fn applies_events() {
+        let mut report = ProgressReport::default();
+
+        assert!(!apply_event(
+            &mut report,
+            &property_change(OBSERVE_TIME_POS, json!(12.5))
+        ));
+        assert!(!apply_event(
+            &mut report,
+            &property_change(OBSERVE_DURATION, json!(1800.0))
+        ));
+        assert_eq!(report.position_secs, 12.5);
+        assert_eq!(report.duration_secs, Some(1800.0));
+
+        // Only pausing and unpausing are reported right away
+        assert!(apply_event(
+            &mut report,
+            &property_change(OBSERVE_PAUSE, json!(true))
+        ));
+        assert!(!apply_event(
+            &mut report,
+            &property_change(OBSERVE_PAUSE, json!(true))
+        ));
+        assert!(report.paused);
+        assert!(apply_event(
+            &mut report,
+            &property_change(OBSERVE_PAUSE, json!(false))
+        ));
+
+        // Position goes away while seeking, the last one stays
+        assert!(!apply_event(
+            &mut report,
+            &property_change(OBSERVE_TIME_POS, Value::Null)
+        ));
+        assert_eq!(report.position_secs, 12.5);
+        assert!(!apply_event(&mut report, &json!({ "event": "seek" })));
+        assert!(!apply_event(
+            &mut report,
+            &property_change(OBSERVE_TIME_POS, json!(-0.1))
+        ));
+        assert_eq!(report.position_secs, 0.0);
+    }
+
+    #[test]
+    fn path_changes() {
+        let url = format!("http://host/files/lib/a.mkv?{}", SIGNED);
+        let event = property_change(OBSERVE_PATH, json!(url));
+        assert_eq!(path_change(&event), Some(url.as_str()));
+        assert_eq!(
+            path_change(&property_change(OBSERVE_PAUSE, json!(true))),
+            None
+        );
+
+        let report = new_report(&url).unwrap();
+        assert_eq!(report.file, "lib/a.mkv");
+        assert_eq!(report.user_id, "u+1");
+    }
+}